@@ -15,6 +15,10 @@ export type StatArray =
   | {
       kind: "String";
       values: (string | null)[];
+    }
+  | {
+      kind: "Bool";
+      values: (boolean | null)[];
     };
 
 export type CardTable = {
@@ -283,6 +283,7 @@ function deckColumns(deck) {
     currency: (x) =>  x != null ? `$${x.toLocaleString()}` : "",
     latLng: (x) => x != null ? `${x[0]}, ${x[1]}` : "",
     boolean: (x) => x === true ? "true" : "",
+    yesNo: (x) => x === true ? "yes" : x === false ? "no" : "",
     stringArray: (x) => x != null ? x.join(", ") : "",
   }
   const columns = [
@@ -305,7 +306,7 @@ function deckColumns(deck) {
       return {
         label: e.label,
         section: "stat",
-        type: unpascal(e.data.kind),
+        type: e.data.kind === 'Bool' ? 'boolean' : unpascal(e.data.kind),
         show: true,
         get: (i) => e.data.values[i],
         formatter:
@@ -313,6 +314,8 @@ function deckColumns(deck) {
             ? formatters.date
             : e.data.kind === 'LatLng'
             ? formatters.latLng
+            : e.data.kind === 'Bool'
+            ? formatters.yesNo
             : e.data.kind === 'Number' && e.data.unit === 'Dollar'
             ? formatters.currency
             : e.data.kind === 'Number'
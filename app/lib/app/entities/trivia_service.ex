@@ -3,6 +3,8 @@ defmodule App.Entities.TriviaService do
   def get_any_trivia_impl(kb, deck_details, id_log) do
     id_to_deck = deck_details
     |> Enum.flat_map(fn %{id: id, trivia_defs: lst} -> Enum.map(lst, &{&1, id}) end)
+    # the other definitions can only be tried from the explorer
+    |> Enum.filter(fn {{_, common}, _} -> common.availability == :rotation end)
     |> Enum.map(fn {{id, _}, deck_id} -> {id, deck_id} end)
     |> Map.new()
     id_from_deck = Enum.map(deck_details, &(&1.id))
@@ -41,9 +43,52 @@ defmodule App.Entities.TriviaService do
     end
   end
 
+  # A question about the subject of an earlier trivia, from a follow-up
+  # definition of the same deck
+  def get_any_follow_up(%{continuation: %{deck_id: deck_id} = continuation}) do
+    with {:ok, kb, deck_details} <- App.Native.cached_trivia_base() do
+      deck_details
+      |> Enum.filter(&(&1.id == deck_id))
+      |> Enum.flat_map(&(&1.trivia_defs))
+      |> Enum.filter(fn {_, common} -> common.availability == :follow_up end)
+      |> Enum.shuffle()
+      |> Enum.find_value({:error, "No follow-up for this trivia"}, fn {id, _} ->
+        case App.Native.get_follow_up(kb, id, continuation) do
+          {:ok, _, _} = ok -> ok
+          _ -> nil
+        end
+      end)
+    end
+  end
+
+  def get_any_follow_up(_trivia), do: {:error, "No subject to follow up on"}
+
+  # A grade is the share of the credit earned, 1 for a correct answer and
+  # in between for a partly correct one
   def grade_answers_single(expected_ans, answer_lst) do
     answer_set = MapSet.new(answer_lst)
-    Enum.all?(expected_ans, fn
+    expected_ans
+    |> Enum.map(fn
+      {:permutation, %{ids: group}} ->
+        correct = Enum.zip(group, answer_lst) |> Enum.count(fn {aid, ans} -> aid == ans end)
+        correct / length(group)
+      {:order, %{groups: groups}} ->
+        App.Native.grade_order(groups, Enum.filter(answer_lst, &is_integer/1))
+      {:location, %{lat_lng: lat_lng, bands_km: bands_km}} ->
+        case Enum.filter(answer_lst, &is_number/1) do
+          [lat, lng] -> App.Native.grade_location(lat_lng, bands_km, {lat * 1.0, lng * 1.0})
+          _ -> 0
+        end
+      {:credit, credit} ->
+        credit
+      exp ->
+        if grade_single?(exp, answer_lst, answer_set), do: 1, else: 0
+    end)
+    |> Enum.min(fn -> 1 end)
+  end
+
+  defp grade_single?(expected, answer_lst, answer_set) do
+    case expected do
       {:all, %{ids: group}} ->
         Enum.all?(group, &(&1 in answer_set))
       {:none, %{ids: group}} ->
@@ -61,10 +106,36 @@ defmodule App.Entities.TriviaService do
             :error -> false
           end
         end)
-    end)
+      {:text, %{answers: answers, max_typos: n}} ->
+        Enum.any?(answer_lst, &(is_binary(&1) and App.Native.grade_text(answers, n, &1)))
+    end
+  end
+
+  def points(credit) when is_number(credit), do: credit
+  def points(_), do: 0
+
+  # Whether the grade counts as a correct answer, nil if there is none yet
+  def correct?(nil), do: nil
+  def correct?(credit), do: credit >= 1
+
+  # The closest estimate wins, so the answers of all the players are graded
+  # together
+  defp estimate_credits(trivia_exps, answer_map) do
+    case Enum.find(trivia_exps, &match?({:near, _}, &1)) do
+      {:near, %{value: value, bands: bands, is_relative: is_relative}} ->
+        {user_ids, guesses} =
+          answer_map
+          |> Enum.map(fn {uid, ans_lst} -> {uid, Enum.find(ans_lst, &is_number/1)} end)
+          |> Enum.unzip()
+        guesses = Enum.map(guesses, &(&1 && &1 * 1.0))
+        credits = App.Native.grade_estimates(value, bands, is_relative, guesses)
+        Enum.zip(user_ids, credits) |> Map.new()
+      nil -> %{}
+    end
   end
 
   def grade_answers(trivia_exps, answer_map) do
+    near_credits = estimate_credits(trivia_exps, answer_map)
     for {user_id, ans_lst} <- Map.to_list(answer_map), into: %{} do
       exp_lst = Enum.flat_map(trivia_exps, fn
         :matchrank ->
@@ -74,6 +145,7 @@ defmodule App.Entities.TriviaService do
               |> Enum.map(fn {aid, idx} -> {:all_pos, %{ids: [aid], min_pos: idx}} end)
             _ -> []
           end
+        {:near, _} -> [{:credit, Map.get(near_credits, user_id, 0)}]
         simple -> [simple]
       end)
 
@@ -43,7 +43,25 @@ defmodule App.Native do
 
   def get_trivia(_kb, _def_id), do: :erlang.nif_error(:nif_not_loaded)
 
+  def get_follow_up(_kb, _def_id, _continuation), do: :erlang.nif_error(:nif_not_loaded)
+
   def get_cards(_kb, _deck_id, _difficulty, _category_boosts, _limit) do
     :erlang.nif_error(:nif_not_loaded)
   end
+
+  def analyze_expression(_kb, _deck_id, _src), do: :erlang.nif_error(:nif_not_loaded)
+
+  def evaluate_expression(_kb, _deck_id, _src, _filter, _limit) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
+
+  def grade_text(_answers, _max_typos, _typed), do: :erlang.nif_error(:nif_not_loaded)
+
+  def grade_order(_groups, _selection), do: :erlang.nif_error(:nif_not_loaded)
+
+  def grade_location(_lat_lng, _bands_km, _answer), do: :erlang.nif_error(:nif_not_loaded)
+
+  def grade_estimates(_value, _bands, _is_relative, _answers) do
+    :erlang.nif_error(:nif_not_loaded)
+  end
 end
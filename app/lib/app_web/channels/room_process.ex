@@ -257,8 +257,8 @@ defmodule AppWeb.RoomProcess do
   defp turn_feedback_payload_partial(turn_id, current_scores, grade_map) do
     score_entries = Enum.map(current_scores, fn {k, v} ->
       turn_grade = Map.get(grade_map, k)
-      v = if turn_grade == true, do: v + 1, else: v
-      %{userId: k, score: v, turnGrade: turn_grade}
+      v = v + TriviaService.points(turn_grade)
+      %{userId: k, score: v, turnGrade: TriviaService.correct?(turn_grade), turnCredit: turn_grade}
     end)
 
     %{
@@ -290,11 +290,30 @@ defmodule AppWeb.RoomProcess do
     end
   end
 
+  # Follows up on the subject of the last trivia when a definition can, but
+  # not on a follow-up
+  defp next_trivia(state_agent, past_def_ids) do
+    follow_up =
+      case get_turn_history(state_agent) do
+        [%{trivia: last_trivia, is_follow_up: false} | _] -> TriviaService.get_any_follow_up(last_trivia)
+        _ -> {:error, "No trivia to follow up on"}
+      end
+    case follow_up do
+      {:ok, trivia, trivia_exps} ->
+        {:ok, trivia, trivia_exps, true}
+      _ ->
+        with {:ok, trivia, trivia_exps} <- TriviaService.get_any_trivia(past_def_ids, not: ["matchrank"]) do
+          {:ok, trivia, trivia_exps, false}
+        end
+    end
+  end
+
   defp play_turn(state_agent, turn_id, current_scores) do
     past_def_ids = []  # TODO
     game_participants = get_game_participants(state_agent)
 
-    with {:ok, trivia, trivia_exps} <- TriviaService.get_any_trivia(past_def_ids, not: ["matchrank"]) do
+    with {:ok, trivia, trivia_exps, is_follow_up} <- next_trivia(state_agent, past_def_ids) do
+      :ok = update_turn_history(state_agent, %{trivia: trivia, is_follow_up: is_follow_up})
       st_payload = turn_start_payload(turn_id, trivia)
       IO.inspect({:st_payload, st_payload})
       score_payload = %{
@@ -339,7 +358,7 @@ defmodule AppWeb.RoomProcess do
             with true <- check(ans_turn_id == turn_id, client, "incorrect turn ID in message"),
                  true <- check(client.user_id in game_participants, client, "invalid message type for spectator"),
                  true <- check(is_list(answered), client, "invalid message content for type 'turn:feedback'"),
-                 true <- check(Enum.all?(answered, &is_number/1), client, "invalid message content for type 'turn:feedback'")
+                 true <- check(Enum.all?(answered, &(is_number(&1) or is_binary(&1))), client, "invalid message content for type 'turn:feedback'")
             do
               {_, acc_answers_map} = acc
               reply(client, {:ok, %{}})
@@ -376,10 +395,10 @@ defmodule AppWeb.RoomProcess do
 
       score_map =
         grade_map
-        |> Enum.filter(fn {_, correct?} -> correct? end)
-        |> Map.new(fn {id, _} -> {id, 1} end)
+        |> Map.new(fn {id, grade} -> {id, TriviaService.points(grade)} end)
+        |> Map.filter(fn {_, points} -> points > 0 end)
         |> Map.merge(current_scores, fn _, v1, v2 -> v1 + v2 end)
-      grade_map = Map.new(game_participants, fn k -> {k, Map.get(grade_map, k, false)} end)
+      grade_map = Map.new(game_participants, fn k -> {k, Map.get(grade_map, k, 0)} end)
       fb_payload = turn_feedback_payload_full(
         turn_id, current_scores, grade_map, answers_map, trivia, trivia_exps, is_final: true
       )
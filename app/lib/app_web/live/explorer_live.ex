@@ -64,6 +64,7 @@ defmodule AppWeb.ExplorerLive do
         |> assign(:trivia_base, {kb, tdefs})
         |> push_event("load", %{"ok" => deck_json})
       else
+        {:error, %{message: msg}} -> push_event(socket, "load", %{"error" => msg})
         {:error, err} -> push_event(socket, "load", %{"error" => to_string(err)})
       end
     else
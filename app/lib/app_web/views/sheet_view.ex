@@ -26,6 +26,9 @@ defmodule AppWeb.SheetView do
   defp stat_array_json({:string, amap}) do
     Map.put(amap, :kind, "String")
   end
+  defp stat_array_json({:bool, amap}) do
+    Map.put(amap, :kind, "Bool")
+  end
 
   defp card_table_json(card_table) do
     card_table
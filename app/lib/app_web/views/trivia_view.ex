@@ -5,6 +5,10 @@ defmodule AppWeb.TriviaView do
 
   def answer_type_json(:selection), do: "selection"
   def answer_type_json(:hangman), do: "hangman"
+  def answer_type_json(:free_text), do: "free_text"
+  def answer_type_json(:estimate), do: "estimate"
+  def answer_type_json(:matching), do: "matching"
+  def answer_type_json(:location), do: "location"
   def answer_type_json({:ranking, :min}), do: "stat.min"
   def answer_type_json({:ranking, :asc}), do: "stat.asc"
   def answer_type_json({:ranking, :max}), do: "stat.max"
@@ -12,6 +16,7 @@ defmodule AppWeb.TriviaView do
 
   def question_value_type_json(:date), do: "date"
   def question_value_type_json(:number), do: "number"
+  def question_value_type_json(:lat_lng), do: "latlng"
   def question_value_type_json(:string), do: "string"
   def question_value_type_json(:int_array), do: "number[]"
   def question_value_type_json(:string_array), do: "string[]"
@@ -21,12 +26,26 @@ defmodule AppWeb.TriviaView do
     %{axisMod: axis_mod, axisMin: axis_min, axisMax: axis_max}
   end
 
+  def map_annotation_json(nil), do: nil
+  def map_annotation_json(%{min: min, max: max, marker: marker, radius_km: radius_km}) do
+    %{
+      min: lat_lng_json(min),
+      max: lat_lng_json(max),
+      marker: lat_lng_json(marker),
+      radiusKm: radius_km
+    }
+  end
+
+  # JSON has no tuples, so points are sent as [lat, lng]
+  def lat_lng_json({lat, lng}), do: [lat, lng]
+  def lat_lng_json(value), do: value
+
   def option_json(option) do
     %{id: option.id, answer: option.answer}
     |> maybe_put_lazy(
       Map.has_key?(option, :question_value),
       :questionValue,
-      fn -> option.question_value end
+      fn -> lat_lng_json(option.question_value) end
     )
   end
 
@@ -38,6 +57,7 @@ defmodule AppWeb.TriviaView do
       maxAnswers: trivia.max_answers,
       questionValueType: question_value_type_json(trivia.question_value_type),
       statAnnotation: stat_annotation_json(trivia.stat_annotation),
+      mapAnnotation: map_annotation_json(trivia.map_annotation),
       options: Enum.map(trivia.options, &option_json/1),
       prefilledAnswers: Enum.map(trivia.prefilled_answers, &option_json/1)
     }
@@ -53,11 +73,22 @@ defmodule AppWeb.TriviaView do
     }
   end
 
+  @expectation_keys %{
+    min_pos: :minPos,
+    max_typos: :maxTypos,
+    is_relative: :isRelative,
+    lat_lng: :latLng,
+    bands_km: :bandsKm
+  }
+
   def expected_answers_json(expectations) do
     Enum.map(expectations, fn {kind, e} ->
-      result = Map.put(e, :kind, kind)
-      {min_pos, result} = Map.pop(result, :min_pos)
-      if is_nil(min_pos), do: result, else: Map.put(result, :minPos, min_pos)
+      Enum.reduce(@expectation_keys, Map.put(e, :kind, kind), fn {key, json_key}, result ->
+        case Map.pop(result, key) do
+          {nil, result} -> result
+          {value, result} -> Map.put(result, json_key, lat_lng_json(value))
+        end
+      end)
     end)
   end
 
@@ -0,0 +1,70 @@
+use app_native::tinylang::{expr, test_support::card_table_of};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const CARDS: usize = 1000;
+
+const EXPRESSIONS: [(&str, &str); 4] = [
+    ("arithmetic", r#"(L"N" - R"N") / 1000 + 1"#),
+    (
+        "predicate",
+        r#"L"Card" != R"Card" and L"N" > R"N" and L"D" < R"D""#,
+    ),
+    ("distance", r#"L"P" <-> R"P""#),
+    (
+        "block",
+        r#"{
+          let d1: Date = L"D"
+          let d2: Date = R"D"
+          if d1 == d2 { return None }
+          return 1 / (1 + ((d2 - d1) / 3652.5) ** 2)
+        }"#,
+    ),
+];
+
+/// Evaluate every expression for one left Card against every right Card,
+/// like a selector looking for a right Card
+fn evaluation(c: &mut Criterion) {
+    let table = card_table_of(CARDS);
+    for (name, src) in EXPRESSIONS {
+        let e = expr(src).unwrap();
+        let mut group = c.benchmark_group(format!("evaluate/{}", name));
+        let tree = e.optimize(&table, &table).unwrap();
+        group.bench_function("tree", |b| {
+            b.iter(|| {
+                for j in 0..CARDS {
+                    black_box(tree.get_value(black_box(1), j));
+                }
+            })
+        });
+        let program = e.compile(&table, &table).unwrap();
+        group.bench_function("compiled", |b| {
+            b.iter(|| {
+                let bound = program.bind(&table, &table);
+                for j in 0..CARDS {
+                    black_box(bound.get_value(black_box(1), j));
+                }
+            })
+        });
+        group.finish();
+    }
+}
+
+/// The work done on each `get_trivia` call before the first evaluation
+fn preparation(c: &mut Criterion) {
+    let table = card_table_of(CARDS);
+    let (_, src) = EXPRESSIONS[3];
+    let e = expr(src).unwrap();
+    let program = e.compile(&table, &table).unwrap();
+    let mut group = c.benchmark_group("prepare");
+    group.bench_function("parse", |b| b.iter(|| expr(black_box(src))));
+    group.bench_function("optimize", |b| {
+        b.iter(|| black_box(&e).optimize(&table, &table).is_ok())
+    });
+    group.bench_function("bind", |b| {
+        b.iter(|| black_box(&program).bind(&table, &table).is_const())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, evaluation, preparation);
+criterion_main!(benches);
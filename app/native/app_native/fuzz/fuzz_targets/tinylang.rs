@@ -0,0 +1,38 @@
+//! Parse and evaluate arbitrary sources, with `cargo fuzz run tinylang`.
+//!
+//! The compiled program must agree with the interpreter on every pair of
+//! Cards, and neither may panic.
+#![no_main]
+
+use app_native::tinylang::{
+    expr,
+    test_support::{card_table, CARDS},
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(src) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(e) = expr(src) else {
+        return;
+    };
+    let card_table = card_table();
+    let Ok(ie) = e.optimize(&card_table, &card_table) else {
+        return;
+    };
+    let program = e.compile(&card_table, &card_table).unwrap();
+    let bound = program.bind(&card_table, &card_table);
+    assert_eq!(program.get_type(), ie.get_type());
+    for i in 0..CARDS {
+        for j in 0..CARDS {
+            // NaN != NaN, the debug representations can be compared
+            assert_eq!(
+                format!("{:?}", bound.get_value(i, j)),
+                format!("{:?}", ie.get_value(i, j)),
+                "{}",
+                src
+            );
+        }
+    }
+});
@@ -0,0 +1,88 @@
+/// A set of Card indices below a fixed bound, one bit per Card
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bitset {
+    words: Vec<u64>,
+    bound: usize,
+}
+
+impl Bitset {
+    /// The empty set
+    pub fn new(bound: usize) -> Self {
+        Self {
+            words: vec![0; bound.div_ceil(64)],
+            bound,
+        }
+    }
+
+    /// The set of every index below the bound
+    pub fn full(bound: usize) -> Self {
+        Self::from_fn(bound, |_| true)
+    }
+
+    pub fn from_fn(bound: usize, mut f: impl FnMut(usize) -> bool) -> Self {
+        let mut res = Self::new(bound);
+        for i in 0..bound {
+            if f(i) {
+                res.insert(i);
+            }
+        }
+        res
+    }
+
+    pub fn bound(&self) -> usize {
+        self.bound
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        index < self.bound && self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    pub fn insert(&mut self, index: usize) {
+        assert!(index < self.bound, "{} is out of bounds", index);
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    /// Keep the indices that are also in `other`
+    pub fn intersect_with(&mut self, other: &Bitset) {
+        for (i, word) in self.words.iter_mut().enumerate() {
+            *word &= other.words.get(i).copied().unwrap_or(0);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// The indices in the set, in increasing order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            let mut word = *word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitset;
+
+    #[test]
+    fn test_bitset() {
+        let mut set = Bitset::from_fn(130, |i| i % 3 == 0);
+        assert_eq!(set.count(), 44);
+        assert!(set.contains(129));
+        assert!(!set.contains(130));
+        set.intersect_with(&Bitset::from_fn(130, |i| i % 2 == 0));
+        assert_eq!(set.iter().take(4).collect::<Vec<_>>(), vec![0, 6, 12, 18]);
+        assert_eq!(set.iter().last(), Some(126));
+        assert_eq!(Bitset::full(65).count(), 65);
+        assert_eq!(Bitset::new(0).iter().next(), None);
+    }
+}
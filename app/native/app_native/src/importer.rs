@@ -299,6 +299,15 @@ fn group_columns<'a>(
     result
 }
 
+/// Read a cell of a yes/no column
+fn parse_yes_no(s: &str) -> Option<bool> {
+    match s {
+        "1" | "y" | "Y" | "yes" | "Yes" | "YES" | "true" | "True" | "TRUE" => Some(true),
+        "0" | "n" | "N" | "no" | "No" | "NO" | "false" | "False" | "FALSE" => Some(false),
+        _ => None,
+    }
+}
+
 fn convert_cards(card_columns: CardColumns<'_>, callouts: &mut Vec<Callout>) -> Vec<Card> {
     let mut cards = vec![];
     let Some(title_column) = card_columns.title else {
@@ -325,12 +334,7 @@ fn convert_cards(card_columns: CardColumns<'_>, callouts: &mut Vec<Callout>) ->
         let is_disabled = card_columns
             .is_disabled
             .and_then(|col| col.body.get(row_index))
-            .map(|s| {
-                !matches!(
-                    s.as_str(),
-                    "" | "0" | "n" | "N" | "no" | "No" | "NO" | "false" | "False" | "FALSE"
-                )
-            })
+            .map(|s| !s.is_empty() && parse_yes_no(s) != Some(false))
             .unwrap_or(false);
         let notes = card_columns
             .notes
@@ -464,6 +468,7 @@ mod formats {
     pub struct Iso8601 {}
     pub struct DollarAmount {}
     pub struct Coordinates {}
+    pub struct YesNo {}
 }
 
 impl StatArrayConverter for formats::Numeric {
@@ -542,6 +547,18 @@ impl StatArrayConverter for formats::Coordinates {
     }
 }
 
+impl StatArrayConverter for formats::YesNo {
+    type Item = bool;
+
+    fn convert_one(&self, src: &str) -> Option<bool> {
+        parse_yes_no(src)
+    }
+
+    fn finalize(&self, values: Vec<Option<bool>>) -> StatArray {
+        StatArray::Bool { values }
+    }
+}
+
 fn convert_stat_defs(
     stat_columns: Vec<Column<'_>>,
     len: usize,
@@ -549,11 +566,13 @@ fn convert_stat_defs(
 ) -> Vec<StatDef> {
     let mut stat_defs = vec![];
     let mut labels = HashSet::new();
-    let value_parsers: [Box<dyn StatArrayConvert>; 4] = [
+    // a column of 0s and 1s is read as numbers
+    let value_parsers: [Box<dyn StatArrayConvert>; 5] = [
         Box::new(formats::Numeric {}),
         Box::new(formats::DollarAmount {}),
         Box::new(formats::Iso8601 {}),
         Box::new(formats::Coordinates {}),
+        Box::new(formats::YesNo {}),
     ];
     for col in stat_columns {
         if len > 0 && col.body.len() > len {
@@ -803,8 +822,8 @@ mod tests {
     use crate::{
         importer::parse_value_range,
         match_it,
-        tinylang::{expr, ExprType, OwnedExprValue},
-        types::{Callout, Card, CardTable, StatArray, StatUnit},
+        tinylang::{analyze, expr, ExprType, OwnedExprValue, PartialContext, Reference},
+        types::{Callout, Card, CardTable, Edge, EdgeSide, Pairing, StatArray, StatUnit},
     };
 
     fn movies_row_major() -> Vec<Vec<String>> {
@@ -1005,6 +1024,52 @@ mod tests {
         assert_eq!(errors.len(), 1);
     }
 
+    #[test]
+    fn test_parse_yes_no_column() {
+        let sheet = [
+            ["Card", "Paris", "Lyon", "Nice", "Marseille"],
+            ["Is capital?", "yes", "No", "", "n"],
+            ["Flag", "0", "1", "1", "0"],
+            ["Disable?", "", "no", "Y", "x"],
+        ];
+        let sheet = sheet
+            .iter()
+            .map(|col| col.iter().map(|s| s.to_string()).collect())
+            .collect();
+        let (card_table, _) = parse_value_range(sheet);
+        let disabled: Vec<_> = card_table.cards.iter().map(|c| c.is_disabled).collect();
+        assert_eq!(disabled, [false, false, true, true]);
+        assert_eq!(card_table.stat_defs[0].label, "Is capital?");
+        assert_eq!(
+            card_table.stat_defs[0].data,
+            StatArray::Bool {
+                values: vec![Some(true), Some(false), None, Some(false)]
+            }
+        );
+        // a column of 0s and 1s is a number
+        assert!(matches!(
+            card_table.stat_defs[1].data,
+            StatArray::Number { .. }
+        ));
+
+        let e = expr(r#"L"Is capital?" and not R"Is capital?""#).unwrap();
+        let ie = e.optimize(&card_table, &card_table).unwrap();
+        assert_eq!(ie.get_type(), ExprType::Bool);
+        assert_eq!(ie.get_value(0, 1), Some(OwnedExprValue::Bool(true)));
+        assert_eq!(ie.get_value(1, 0), Some(OwnedExprValue::Bool(false)));
+        assert_eq!(ie.get_value(0, 2), None);
+        assert!(!ie.has_vars(&PartialContext::Right(2)));
+        let program = e.compile(&card_table, &card_table).unwrap();
+        let bound = program.bind(&card_table, &card_table);
+        for i in 0..4 {
+            for j in 0..4 {
+                assert_eq!(bound.get_value(i, j), ie.get_value(i, j));
+            }
+        }
+        let filtered = bound.filter(EdgeSide::Right);
+        assert_eq!(filtered.iter().collect::<Vec<_>>(), [1, 3]);
+    }
+
     #[test]
     fn test_expression_eval() {
         let (card_table, _) = parse_value_range(movies());
@@ -1033,4 +1098,508 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_block_eval() {
+        let (card_table, _) = parse_value_range(movies());
+        let block = expr(
+            r#"{
+              let theaters: String = R"Num Theaters"
+              let n: Number = theaters
+              if n < 3000 { return None }
+              let tagline: Number = R"Tagline"
+              return n + L"Box Office" / 1000
+            }"#,
+        )
+        .unwrap();
+        let block = block.optimize(&card_table, &card_table).unwrap();
+        assert_eq!(block.get_type(), ExprType::Number);
+        assert_eq!(
+            block
+                .get_value(0, 0)
+                .map(|r| match_it!(r, it, OwnedExprValue::Number(it)).unwrap()),
+            Some(3084.0 + 1602.0)
+        );
+        // guard
+        assert_eq!(block.get_value(0, 1), None);
+        // missing variable on the left
+        assert_eq!(block.get_value(14, 0), None);
+
+        let block = expr(r#"{ let t: Number = R"Tagline"; return t }"#).unwrap();
+        let block = block.optimize(&card_table, &card_table).unwrap();
+        assert_eq!(block.get_type(), ExprType::Number);
+        assert_eq!(block.get_value(0, 0), None);
+
+        let block = expr(r#"{ let d: String = R"Release Date"; return d }"#).unwrap();
+        let block = block.optimize(&card_table, &card_table).unwrap();
+        assert_eq!(
+            block.get_value(0, 0),
+            Some(OwnedExprValue::String("1999-06-23".into()))
+        );
+
+        let bad = [
+            r#"{ let x = 1; return y }"#,
+            r#"{ let x: LatLng = R"Tagline"; return x }"#,
+            r#"{ if 1 { return None } return 2 }"#,
+        ];
+        for src in bad {
+            let block = expr(src).unwrap();
+            if block.optimize(&card_table, &card_table).is_ok() {
+                panic!("{}", src)
+            }
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let (card_table, _) = parse_value_range(movies());
+        let value = |src: &str, left_idx: usize| {
+            let e = expr(src).unwrap();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            (ie.is_const(), ie.get_value(left_idx, 0))
+        };
+
+        let days = OwnedExprValue::Number(3652.0);
+        assert_eq!(
+            value(r#"D"2000-01-01" - D"1990-01-01""#, 0),
+            (true, Some(days))
+        );
+        let pow = OwnedExprValue::Number(1024.0);
+        assert_eq!(value("2 ** 10", 0), (true, Some(pow)));
+        let s = OwnedExprValue::String("3".into());
+        assert_eq!(value("{ let s: String = 1 + 2; return s }", 0).1, Some(s));
+
+        // a constant left operand short-circuits, even when the other operand
+        // is missing
+        let f = Some(OwnedExprValue::Bool(false));
+        let t = Some(OwnedExprValue::Bool(true));
+        assert_eq!(value(r#"1 > 2 and L"Box Office" > 0"#, 14), (true, f));
+        assert_eq!(value(r#"1 < 2 or R"Tagline"?"#, 14), (true, t.clone()));
+        // a missing left operand makes the result None, whatever the right one
+        let src = r#"L"Box Office" > 0 and 1 > 2"#;
+        assert_eq!(value(src, 14), (false, None));
+        let src = r#"not (L"Box Office" > 0 and 1 > 2)"#;
+        assert_eq!(value(src, 14), (false, None));
+        let src = r#"L"Box Office" > 0 or R"Box Office" > 0"#;
+        assert_eq!(value(src, 14), (false, None));
+        let src = r#"R"Box Office" > 0 or L"Box Office" > 0"#;
+        assert_eq!(value(src, 14), (false, t));
+        let src = r#"L"Box Office" > 0 and R"Box Office" > 0"#;
+        assert_eq!(value(src, 14), (false, None));
+
+        // identities keep the value of the operand
+        let src = r#"- -(L"Box Office" * 1 + 0) / 1 ** 1"#;
+        assert_eq!(value(src, 0), (false, value(r#"L"Box Office""#, 0).1));
+        assert_eq!(value(src, 14), (false, None));
+    }
+
+    #[test]
+    fn test_compile() {
+        let (card_table, _) = parse_value_range(movies());
+        let cases = [
+            r#"L"Release Date" - R"Release Date""#,
+            r#"L"Box Office" / 1000 + R"Num Theaters" ** 0.5 * -2"#,
+            r#"L"Box Office" > R"Box Office" or L"Tagline" == R"Card""#,
+            r#"L"Release Date" <= R"Release Date" and not (L"Num Theaters" != 3084)"#,
+            r#"L"Setting" <-> R"Setting""#,
+            r#"(L"Box Office" > 0)? and R"Tagline"?"#,
+            r#"{
+              let theaters: String = R"Num Theaters"
+              let n: Number = theaters
+              if n < 3000 { return None }
+              let d: String = L"Release Date"
+              return d
+            }"#,
+            r#"{ let t: Date = R"Tagline"; return (t == D"2000-01-01")? }"#,
+            r#"bearing(L"Setting", C"48.85,2.35") + (midpoint(L"Setting", R"Setting") <-> C"0,0")"#,
+            r#"{
+              let d: Duration = days(L"Release Date" - R"Release Date") - years(5)
+              return d / 2 < -days(3)
+            }"#,
+            r#"R"Release Date" + L"Num Theaters" * days(1) * 2"#,
+            r#"L"Card" =~ "^The " or capture(R"Tagline", "(\w+) World") == "Real""#,
+        ];
+        for src in cases {
+            let e = expr(src).unwrap();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            let program = e.compile(&card_table, &card_table).unwrap();
+            let bound = program.bind(&card_table, &card_table);
+            assert_eq!(program.get_type(), ie.get_type());
+            for i in 0..15 {
+                for j in 0..15 {
+                    // the distance from a point to itself is NaN
+                    let expected = format!("{:?}", ie.get_value(i, j));
+                    assert_eq!(format!("{:?}", bound.get_value(i, j)), expected, "{}", src);
+                }
+                for ctx in [PartialContext::Left(i), PartialContext::Right(i)] {
+                    assert_eq!(bound.has_vars(&ctx), ie.has_vars(&ctx), "{}", src);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_whole_column() {
+        let (card_table, _) = parse_value_range(movies());
+        let cases = [
+            (EdgeSide::Left, r#"L"Box Office" > 1000000 and L"Tagline"?"#),
+            (
+                EdgeSide::Right,
+                r#"R"Release Date" < D"2010-01-01" or R"Box Office" > 0"#,
+            ),
+            (
+                EdgeSide::Right,
+                r#"{ let n: Number = R"Tagline"; return n? }"#,
+            ),
+        ];
+        for (side, src) in cases {
+            let program = expr(src)
+                .unwrap()
+                .compile(&card_table, &card_table)
+                .unwrap();
+            let bound = program.bind(&card_table, &card_table);
+            let values = bound.get_values(side);
+            let present = bound.has_vars_all(side);
+            let filtered = bound.filter(side);
+            assert_eq!(values.len(), card_table.cards.len());
+            for (i, value) in values.iter().enumerate() {
+                let (ctx, expected) = match side {
+                    EdgeSide::Left => (PartialContext::Left(i), bound.get_value(i, 0)),
+                    EdgeSide::Right => (PartialContext::Right(i), bound.get_value(0, i)),
+                };
+                assert_eq!(value, &expected, "{}", src);
+                assert_eq!(present.contains(i), bound.has_vars(&ctx), "{}", src);
+                assert_eq!(
+                    filtered.contains(i),
+                    expected == Some(OwnedExprValue::Bool(true)),
+                    "{}",
+                    src
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_lat_lng_and_duration() {
+        let (card_table, _) = parse_value_range(movies());
+        let value = |src: &str, left_idx: usize, right_idx: usize| {
+            let e = expr(src).unwrap();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            ie.get_value(left_idx, right_idx)
+        };
+
+        let bearing = value(r#"bearing(R"Setting", C"48.85,2.35")"#, 0, 13)
+            .map(|r| match_it!(r, it, OwnedExprValue::Number(it)).unwrap());
+        assert_eq!(bearing.map(|x| (x * 100.0).round()), Some(3462.0));
+        assert_eq!(
+            value(r#"bearing(C"0,0", C"0,90")"#, 0, 0),
+            Some(OwnedExprValue::Number(90.0))
+        );
+        assert_eq!(
+            value(r#"midpoint(C"0,170", C"0,-170")"#, 0, 0),
+            Some(OwnedExprValue::LatLng((0.0, -180.0)))
+        );
+        assert_eq!(value(r#"L"Setting" <-> C"48.85,2.35""#, 0, 0), None);
+
+        let date = NaiveDate::from_ymd_opt(2000, 6, 23).unwrap();
+        assert_eq!(
+            value(r#"L"Release Date" + days(366)"#, 0, 0),
+            Some(OwnedExprValue::Date(
+                date.and_hms_opt(0, 0, 0).unwrap().into()
+            ))
+        );
+        assert_eq!(
+            value(r#"days(2) + L"Release Date""#, 0, 0),
+            value(r#"L"Release Date" - -days(2)"#, 0, 0)
+        );
+        assert_eq!(
+            value("(years(2) - days(1)) / days(1)", 0, 0),
+            Some(OwnedExprValue::Number(729.485))
+        );
+        assert_eq!(
+            value("{ let d: Duration = -days(3) * 2; return d }", 0, 0),
+            Some(OwnedExprValue::Duration(-6.0))
+        );
+        assert_eq!(
+            value("years(1) / 2 > days(182)", 0, 0),
+            Some(OwnedExprValue::Bool(true))
+        );
+
+        // released within 5 years of The Matrix
+        let src = r#"
+            L"Release Date" - years(5) < R"Release Date"
+                and R"Release Date" < L"Release Date" + years(5)
+        "#;
+        let within: Vec<usize> = (0..15)
+            .filter(|j| value(src, 0, *j) == Some(OwnedExprValue::Bool(true)))
+            .collect();
+        assert_eq!(within, [0, 1, 2, 3, 6, 8, 9, 10, 12]);
+    }
+
+    #[test]
+    fn test_regex() {
+        let (card_table, _) = parse_value_range(movies());
+        let value = |src: &str, left_idx: usize| {
+            let e = expr(src).unwrap();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            ie.get_value(left_idx, 0)
+        };
+
+        let program = expr(r#"not (R"Card" =~ "^The ")"#)
+            .unwrap()
+            .compile(&card_table, &card_table)
+            .unwrap();
+        let bound = program.bind(&card_table, &card_table);
+        assert_eq!(
+            bound.filter(EdgeSide::Right).iter().collect::<Vec<_>>(),
+            (4..15).collect::<Vec<_>>()
+        );
+
+        let year = r#"{
+          let date: String = L"Release Date"
+          let year: Number = capture(date, "^(\d{4})-")
+          return year
+        }"#;
+        assert_eq!(value(year, 0), Some(OwnedExprValue::Number(1999.0)));
+        assert_eq!(value(year, 14), None);
+        assert_eq!(
+            value(r#"capture(L"Tagline", "\w+$")"#, 0),
+            Some(OwnedExprValue::String("World".into()))
+        );
+        assert_eq!(value(r#"capture(L"Tagline", "^(Goodbye)?")"#, 0), None);
+        assert_eq!(
+            value(r#"L"Tagline" =~ "(?i)^welcome\b""#, 0),
+            Some(OwnedExprValue::Bool(true))
+        );
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let (card_table, _) = parse_value_range(movies());
+        let value = |src: &str, left_idx: usize| {
+            let e = expr(src).unwrap();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            ie.get_value(left_idx, 0)
+        };
+        let filter = |src: &str| {
+            let program = expr(src)
+                .unwrap()
+                .compile(&card_table, &card_table)
+                .unwrap();
+            let bound = program.bind(&card_table, &card_table);
+            bound.filter(EdgeSide::Left).iter().collect::<Vec<_>>()
+        };
+
+        // the disabled Card isn't counted
+        assert_eq!(
+            value(r#"mean(R"Num Theaters")"#, 0),
+            Some(OwnedExprValue::Number(2943.0))
+        );
+        assert_eq!(
+            filter(r#"L"Num Theaters" > median(L"Num Theaters")"#),
+            [0, 2, 4, 5, 6, 7, 10]
+        );
+        assert_eq!(filter(r#"percentile(L"Num Theaters") >= 80"#), [0, 2, 4, 6]);
+        assert_eq!(
+            [0, 2, 6].map(|i| value(r#"rank(L"Num Theaters")"#, i)),
+            [3.0, 2.0, 1.0].map(|x| Some(OwnedExprValue::Number(x)))
+        );
+        assert_eq!(value(r#"zscore(L"Num Theaters")"#, 14), None);
+        // the population standard deviation
+        let src = r#"(L"Num Theaters" - mean(L"Num Theaters")) / zscore(L"Num Theaters")"#;
+        let std_dev = value(src, 0).map(|r| match_it!(r, it, OwnedExprValue::Number(it)).unwrap());
+        assert_eq!(std_dev.map(f64::round), Some(146.0));
+    }
+
+    #[test]
+    fn test_pairings() {
+        let (mut card_table, _) = parse_value_range(movies());
+        let edges = [
+            (0, 1, Some("Keanu Reeves")),
+            (0, 3, Some("Keanu Reeves")),
+            // to the disabled Card
+            (1, 2, Some("Keanu Reeves")),
+            (4, 5, Some("Tom Cruise")),
+            (5, 6, Some("Tom Cruise")),
+            (6, 7, Some("Jerry O'Connell")),
+            (4, 8, Some("Jack Nicholson")),
+            (11, 12, None),
+        ];
+        card_table.pairings.push(Pairing {
+            label: "Shared Actor".into(),
+            is_symmetric: true,
+            data: edges
+                .into_iter()
+                .map(|(l, r, info)| Edge::new(l, r, info.map(Into::into)))
+                .collect(),
+        });
+        let value = |src: &str, left_idx: usize, right_idx: usize| {
+            let e = expr(src).unwrap();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            let program = e.compile(&card_table, &card_table).unwrap();
+            let value = ie.get_value(left_idx, right_idx);
+            assert_eq!(
+                program
+                    .bind(&card_table, &card_table)
+                    .get_value(left_idx, right_idx),
+                value
+            );
+            value
+        };
+
+        let src = r#"has_edge("Shared Actor", L, R)"#;
+        assert_eq!(value(src, 0, 1), Some(OwnedExprValue::Bool(true)));
+        assert_eq!(value(src, 1, 0), Some(OwnedExprValue::Bool(true)));
+        assert_eq!(value(src, 1, 2), Some(OwnedExprValue::Bool(false)));
+        assert_eq!(value(src, 0, 4), Some(OwnedExprValue::Bool(false)));
+
+        let src = r#"edge_info("Shared Actor", L, R)"#;
+        assert_eq!(
+            value(src, 5, 4),
+            Some(OwnedExprValue::String("Tom Cruise".into()))
+        );
+        assert_eq!(value(src, 11, 12), None);
+        assert_eq!(value(src, 0, 4), None);
+
+        let src = r#"count(neighbors("Shared Actor", L))"#;
+        assert_eq!(value(src, 0, 0), Some(OwnedExprValue::Number(2.0)));
+        assert_eq!(value(src, 13, 0), Some(OwnedExprValue::Number(0.0)));
+
+        // shares an actor with a movie that shares one with R, but not with R
+        let src = r#"has_edge("Shared Actor", neighbors("Shared Actor", L), R)
+            and not has_edge("Shared Actor", L, R) and L"Card" != R"Card""#;
+        let two_hops: Vec<usize> = (0..card_table.cards.len())
+            .filter(|i| value(src, *i, 4) == Some(OwnedExprValue::Bool(true)))
+            .collect();
+        assert_eq!(two_hops, [6]);
+        // a Card is a neighbor of its neighbors
+        assert_eq!(
+            value(
+                r#"count(neighbors("Shared Actor", neighbors("Shared Actor", L)))"#,
+                4,
+                0
+            ),
+            Some(OwnedExprValue::Number(2.0))
+        );
+        assert_eq!(
+            expr(r#"has_edge("Shared Actor", L, R)"#).unwrap().sides(),
+            (true, true)
+        );
+    }
+
+    #[test]
+    fn test_analyze() {
+        let (card_table, _) = parse_value_range(movies());
+        let reference = |side, key: &str, column_type| Reference {
+            side,
+            key: key.into(),
+            column_type,
+        };
+
+        let src = r#"{ let d = L"Release Date"; return R"Box Office" / (d - R"Release Date") }"#;
+        let analysis = analyze(src, &card_table, &card_table);
+        assert_eq!(analysis.return_type, Some(ExprType::Number));
+        assert_eq!(analysis.diagnostics, []);
+        assert_eq!(
+            analysis.references,
+            [
+                reference(EdgeSide::Left, "Release Date", Some(ExprType::Date)),
+                reference(EdgeSide::Right, "Box Office", Some(ExprType::Number)),
+                reference(EdgeSide::Right, "Release Date", Some(ExprType::Date)),
+            ]
+        );
+
+        // the references are known even if the column was removed
+        let analysis = analyze(r#"L"Card" == R"Director""#, &card_table, &card_table);
+        assert_eq!(analysis.return_type, None);
+        assert_eq!(analysis.diagnostics.len(), 1);
+        assert_eq!(
+            analysis.references,
+            [
+                reference(EdgeSide::Left, "Card", Some(ExprType::String)),
+                reference(EdgeSide::Right, "Director", None),
+            ]
+        );
+
+        let analysis = analyze(r#"L"Card" == (1"#, &card_table, &card_table);
+        assert_eq!(analysis.references, []);
+        assert_eq!(analysis.diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn test_type_error_span() {
+        let (card_table, _) = parse_value_range(movies());
+        let cases = [
+            (
+                r#"1 + (R"Tagline" - 2)"#,
+                "    ^^^^^^^^^^^^^^^^ `-` is not defined for (String, Number)",
+            ),
+            (
+                r#"L"Box Office" + L"Nope""#,
+                "                ^^^^^^^ Stat Nope not found",
+            ),
+            (r#"not 1"#, "^^^^^ `not` is not defined for (Number)"),
+            (
+                r#"{ let x: LatLng = R"Tagline"; return x }"#,
+                "                  ^^^^^^^^^^ let x: (String) can not be coerced to (LatLng)",
+            ),
+            (
+                r#"bearing(L"Setting")"#,
+                "^^^^^^^^^^^^^^^^^^^ `bearing` is not defined for (LatLng)",
+            ),
+            (r#"1 + age(1)"#, "    ^^^^^^ unknown function `age`"),
+            (
+                r#"L"Release Date" + 1"#,
+                "^^^^^^^^^^^^^^^^^^^ `+` is not defined for (Date, Number)",
+            ),
+            (
+                r#"L"Card" =~ "(The""#,
+                "^^^^^^^^^^^^^^^^^ invalid pattern: unclosed group",
+            ),
+            (
+                r#"L"Num Theaters" =~ "1""#,
+                "^^^^^^^^^^^^^^^^^^^^^^ `=~` is not defined for (Number, String)",
+            ),
+            (
+                r#"{ let p = "^The "; return L"Card" =~ p }"#,
+                "                          ^^^^^^^^^^^^ the pattern of `=~` must be a string literal",
+            ),
+            (
+                r#"rank(L"Num Theaters" + 1)"#,
+                "^^^^^^^^^^^^^^^^^^^^^^^^^ `rank` is computed over a column, like `rank(R\"Population\")`",
+            ),
+            (
+                r#"capture(L"Card", L"Card")"#,
+                "^^^^^^^^^^^^^^^^^^^^^^^^^ the pattern of `capture` must be a string literal",
+            ),
+            (
+                r#"has_edge("Couple", L, R)"#,
+                "         ^^^^^^^^ Pairing Couple not found",
+            ),
+            (
+                r#"count(L"Card")"#,
+                "      ^^^^^^^ expected Cards, like `L` or `neighbors(\"Couple\", R)`",
+            ),
+            (
+                r#"L + 1"#,
+                "^ the Card `L` can only be passed to `neighbors`, `has_edge`, `edge_info` or `count`",
+            ),
+            (
+                r#"{ let n = neighbors("Couple", R); return 1 }"#,
+                "          ^^^^^^^^^^^^^^^^^^^^^^ the Cards `neighbors` returns can only be passed \
+                 to `neighbors`, `has_edge`, `edge_info` or `count`",
+            ),
+            (
+                r#"has_edge("Couple", L)"#,
+                "^^^^^^^^^^^^^^^^^^^^^ `has_edge` is called like `has_edge(\"Couple\", L, R)`",
+            ),
+        ];
+        for (src, underline) in cases {
+            let err = match expr(src).unwrap().optimize(&card_table, &card_table) {
+                Ok(_) => panic!("{}", src),
+                Err(err) => err,
+            };
+            assert_eq!(err.render(src), format!("1 | {}\n  | {}", src, underline));
+        }
+    }
 }
@@ -1,18 +1,19 @@
+pub mod bitset;
 mod importer;
 mod macros;
 mod probability;
-mod tinylang;
+pub mod tinylang;
 mod trivia;
-mod types;
+pub mod types;
 
 use std::sync::Mutex;
 
-use rustler::{Encoder, Env, Error, NifResult, ResourceArc, Term};
+use rustler::{Encoder, Env, Error, NifMap, NifResult, ResourceArc, Term};
 
 use trivia::KnowledgeBase;
 
 use crate::{
-    trivia::{ActiveDeck, DeckFeatureSet},
+    trivia::{ActiveDeck, Continuation, DeckFeatureSet},
     types::{Deck, ExDeck},
 };
 
@@ -26,6 +27,42 @@ struct KnowledgeBaseResource {
     pub data: Mutex<KnowledgeBase>,
 }
 
+/// Tinylang errors keep their diagnostics, so that the UI can underline the
+/// offending part of the source.
+#[derive(NifMap)]
+struct TinylangError {
+    message: String,
+    source: String,
+    diagnostics: Vec<tinylang::Diagnostic>,
+}
+
+fn trivia_error(err: trivia::Error) -> Error {
+    Error::Term(Box::new(format!("{}", err)))
+}
+
+/// Like `trivia_error`, but syntax and type errors are returned as a
+/// `TinylangError`. Only for the NIFs that take source from the explorer.
+fn tinylang_error(err: trivia::Error) -> Error {
+    let message = format!("{}", err);
+    match err.kind() {
+        trivia::ErrorKind::TinylangSyntaxError(src, diagnostics) => {
+            Error::Term(Box::new(TinylangError {
+                message,
+                source: src.clone(),
+                diagnostics: diagnostics.clone(),
+            }))
+        }
+        trivia::ErrorKind::TinylangTypeError(src, diagnostic) => {
+            Error::Term(Box::new(TinylangError {
+                message,
+                source: src.clone(),
+                diagnostics: vec![diagnostic.clone()],
+            }))
+        }
+        _ => Error::Term(Box::new(message)),
+    }
+}
+
 fn load(env: Env, _: Term) -> bool {
     rustler::resource!(KnowledgeBaseResource, env);
     true
@@ -98,7 +135,7 @@ fn load_trivia_base(env: Env<'_>, stored: Vec<ExDeck>) -> NifResult<Term<'_>> {
         decks: active_decks,
         trivia_defs: vec![],
     };
-    trivia::seed(&mut base).map_err(|err| Error::Term(Box::new(format!("{}", err))))?;
+    trivia::seed(&mut base).map_err(trivia_error)?;
     let mut deck_details: Vec<_> = base.decks.iter().map(DeckFeatureSet::from).collect();
     let mut trivia_def_entries: Vec<_> = base
         .trivia_defs
@@ -132,11 +169,31 @@ fn get_trivia(
     env: Env<'_>,
     kb_sync: ResourceArc<KnowledgeBaseResource>,
     def_id: usize,
+) -> NifResult<Term<'_>> {
+    let kb: std::sync::MutexGuard<'_, KnowledgeBase> = kb_sync.data.try_lock().unwrap();
+    let (trivia, exps) = kb.get_trivia(def_id).map_err(trivia_error)?;
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            atoms::ok().encode(env),
+            trivia.encode(env),
+            exps.encode(env),
+        ],
+    ))
+}
+
+/// A Trivia about the subject of an earlier one, carried by its continuation
+#[rustler::nif]
+fn get_follow_up(
+    env: Env<'_>,
+    kb_sync: ResourceArc<KnowledgeBaseResource>,
+    def_id: usize,
+    continuation: Continuation,
 ) -> NifResult<Term<'_>> {
     let kb: std::sync::MutexGuard<'_, KnowledgeBase> = kb_sync.data.try_lock().unwrap();
     let (trivia, exps) = kb
-        .get_trivia(def_id)
-        .map_err(|err| Error::Term(Box::new(format!("{}", err))))?;
+        .get_follow_up(def_id, &continuation)
+        .map_err(trivia_error)?;
     Ok(rustler::types::tuple::make_tuple(
         env,
         &[
@@ -164,13 +221,88 @@ fn get_cards(
             category_boosts.into_iter().collect(),
             limit,
         )
-        .map_err(|err| Error::Term(Box::new(format!("{}", err))))?;
+        .map_err(trivia_error)?;
     Ok(rustler::types::tuple::make_tuple(
         env,
         &[atoms::ok().encode(env), cards.encode(env)],
     ))
 }
 
+#[rustler::nif]
+fn analyze_expression(
+    env: Env<'_>,
+    kb_sync: ResourceArc<KnowledgeBaseResource>,
+    deck_id: u64,
+    src: String,
+) -> NifResult<Term<'_>> {
+    let kb: std::sync::MutexGuard<'_, KnowledgeBase> = kb_sync.data.try_lock().unwrap();
+    let analysis = kb
+        .analyze_expression(deck_id, &src)
+        .map_err(tinylang_error)?;
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[atoms::ok().encode(env), analysis.encode(env)],
+    ))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn evaluate_expression(
+    env: Env<'_>,
+    kb_sync: ResourceArc<KnowledgeBaseResource>,
+    deck_id: u64,
+    src: String,
+    filter: Option<String>,
+    limit: usize,
+) -> NifResult<Term<'_>> {
+    // copy the deck out, so the other NIFs aren't locked out for the whole
+    // evaluation
+    let data = {
+        let kb: std::sync::MutexGuard<'_, KnowledgeBase> = kb_sync.data.try_lock().unwrap();
+        let deck = kb
+            .get_deck(deck_id)
+            .ok_or_else(|| trivia_error(trivia::ErrorKind::InvalidDeckId(deck_id).into()))?;
+        deck.data.clone()
+    };
+    let evaluation = trivia::evaluate_expression(&data, &src, filter.as_deref(), limit)
+        .map_err(tinylang_error)?;
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[atoms::ok().encode(env), evaluation.encode(env)],
+    ))
+}
+
+/// Whether a typed answer matches the answers of a free-text Trivia
+#[rustler::nif]
+fn grade_text(answers: Vec<String>, max_typos: u8, typed: String) -> bool {
+    trivia::grade_text(&answers, max_typos, &typed)
+}
+
+/// The share of credit earned by a selection for the groups of an ordering
+/// Trivia
+#[rustler::nif]
+fn grade_order(groups: Vec<Vec<u8>>, selection: Vec<i64>) -> f64 {
+    trivia::order_credit(&groups, &selection)
+}
+
+/// The share of credit earned by an answered point for the location of a
+/// geography Trivia
+#[rustler::nif]
+fn grade_location(lat_lng: (f64, f64), bands_km: Vec<f64>, answer: (f64, f64)) -> f64 {
+    trivia::location_credit(lat_lng, &bands_km, answer)
+}
+
+/// The share of credit earned by each player's answer to an estimation
+/// Trivia
+#[rustler::nif]
+fn grade_estimates(
+    value: f64,
+    bands: Vec<f64>,
+    is_relative: bool,
+    answers: Vec<Option<f64>>,
+) -> Vec<f64> {
+    trivia::estimate_credits(value, &bands, is_relative, &answers)
+}
+
 rustler::init!(
     "Elixir.App.Native",
     [
@@ -179,7 +311,14 @@ rustler::init!(
         deserialize_deck,
         load_trivia_base,
         get_trivia,
+        get_follow_up,
         get_cards,
+        analyze_expression,
+        evaluate_expression,
+        grade_text,
+        grade_order,
+        grade_location,
+        grade_estimates,
     ],
     load = load
 );
@@ -46,6 +46,10 @@ where
     type Item = E;
 
     fn sample(&mut self, count: usize) -> Vec<Self::Item> {
+        if count == 0 {
+            return vec![];
+        }
+
         // https://richardstartin.github.io/posts/reservoir-sampling#algorithm-l
         let mut res = vec![];
         while res.len() < count {
@@ -60,8 +64,8 @@ where
         let mut w = rand::random::<f64>().powf(invcount);
         loop {
             let jump_len = f64::ln(rand::random()) / f64::ln(1.0 - w);
-            // Safety: random and 1-w are in [0, 1), both args to the product are negative
-            let jump_len: usize = unsafe { jump_len.to_int_unchecked() };
+            // random can be 0, so the jump may be infinite, which saturates
+            let jump_len = jump_len as usize;
             for _ in 0..jump_len {
                 if matches!(self.next(), None) {
                     return res;
@@ -70,9 +74,8 @@ where
             let Some(v) = self.next() else {
                 return res;
             };
-            // Safety: random is never NaN or infinite, and both args to the product are non-negative
-            let replace_idx: usize =
-                unsafe { (rand::random::<f64>() * (count as f64)).to_int_unchecked() };
+            // the product can round up to count
+            let replace_idx = ((rand::random::<f64>() * (count as f64)) as usize).min(count - 1);
             res[replace_idx] = v;
 
             w *= rand::random::<f64>().powf(invcount);
@@ -377,6 +380,12 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sample_none() {
+        assert!((0..10).sample(0).is_empty());
+        assert!((0..10).sample_weighted(0, |_| 1.0).is_empty());
+    }
+
     #[test]
     fn test_sample_weighted() {
         let data = vec![1, 2, 3];
@@ -0,0 +1,91 @@
+/// The values of a Number column over the enabled Cards of a deck, that the
+/// aggregate functions compare a Card's value to
+pub(super) struct Population(Vec<f64>);
+
+impl Population {
+    pub(super) fn new(values: impl Iterator<Item = f64>) -> Self {
+        let mut values: Vec<f64> = values.filter(|x| !x.is_nan()).collect();
+        values.sort_by(f64::total_cmp);
+        Self(values)
+    }
+
+    /// NaN for an empty column, like the other statistics
+    pub(super) fn mean(&self) -> f64 {
+        self.0.iter().sum::<f64>() / self.0.len() as f64
+    }
+
+    pub(super) fn median(&self) -> f64 {
+        let n = self.0.len();
+        match n {
+            0 => f64::NAN,
+            _ if n % 2 == 1 => self.0[n / 2],
+            _ => (self.0[n / 2 - 1] + self.0[n / 2]) / 2.0,
+        }
+    }
+
+    fn std_dev(&self) -> f64 {
+        let mean = self.mean();
+        let variance = self.0.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / self.0.len() as f64;
+        variance.sqrt()
+    }
+
+    /// How many values are below `value`, and how many are equal to it
+    fn position(&self, value: f64) -> (usize, usize) {
+        let below = self.0.partition_point(|x| *x < value);
+        let not_above = self.0.partition_point(|x| *x <= value);
+        (below, not_above - below)
+    }
+
+    /// 1 for the largest value, tied values share the best rank. None for
+    /// NaN, which the population leaves out
+    pub(super) fn rank(&self, value: f64) -> Option<f64> {
+        let (below, equal) = self.position(value);
+        (!value.is_nan()).then(|| (self.0.len() - below - equal + 1) as f64)
+    }
+
+    /// The percentage of values below `value`, counting the equal ones as
+    /// half below. None for NaN, like `rank`
+    pub(super) fn percentile(&self, value: f64) -> Option<f64> {
+        let (below, equal) = self.position(value);
+        (!value.is_nan()).then(|| 100.0 * (below as f64 + 0.5 * equal as f64) / self.0.len() as f64)
+    }
+
+    /// How many standard deviations `value` is above the mean, None if all
+    /// the values are the same
+    pub(super) fn zscore(&self, value: f64) -> Option<f64> {
+        let std_dev = self.std_dev();
+        (std_dev > 0.0).then(|| (value - self.mean()) / std_dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Population;
+
+    #[test]
+    fn test_population() {
+        let p = Population::new([4.0, 1.0, f64::NAN, 2.0, 4.0, 9.0].into_iter());
+        assert_eq!(p.mean(), 4.0);
+        assert_eq!(p.median(), 4.0);
+        assert_eq!(p.rank(9.0), Some(1.0));
+        assert_eq!(p.rank(4.0), Some(2.0));
+        assert_eq!(p.rank(3.0), Some(4.0));
+        assert_eq!(p.percentile(1.0), Some(10.0));
+        assert_eq!(p.percentile(4.0), Some(60.0));
+        assert_eq!(p.percentile(10.0), Some(100.0));
+        assert_eq!(p.rank(f64::NAN), None);
+        assert_eq!(p.percentile(f64::NAN), None);
+        assert_eq!(p.zscore(4.0), Some(0.0));
+        assert_eq!(p.zscore(9.0).map(|z| (z * 100.0).round()), Some(181.0));
+
+        let p = Population::new([2.0, 1.0].into_iter());
+        assert_eq!(p.median(), 1.5);
+
+        let p = Population::new([3.0].into_iter());
+        assert_eq!(p.zscore(3.0), None);
+
+        let p = Population::new([].into_iter());
+        assert!(p.mean().is_nan());
+        assert!(p.median().is_nan());
+    }
+}
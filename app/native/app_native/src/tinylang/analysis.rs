@@ -0,0 +1,236 @@
+use rustler::NifMap;
+
+use crate::types::{CardTable, EdgeSide, StatArray};
+
+use super::{
+    diagnostic::Diagnostic,
+    interpreter::ExprType,
+    parser::{expr, BinOp, Expression, Statement},
+};
+
+/// The parts of a conjunction, grouped by the sides of the edge they read.
+///
+/// `left and right and mixed` is equivalent to the split expression, up to
+/// the order of the operands.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Conjuncts {
+    /// Only reads variables of the left Card
+    pub left: Option<Expression>,
+    /// Only reads variables of the right Card
+    pub right: Option<Expression>,
+    /// Reads both Cards, or none
+    pub mixed: Option<Expression>,
+}
+
+impl Expression {
+    /// Split a chain of `and` into the parts that only depend on the left
+    /// Card, only on the right Card, or on both.
+    ///
+    /// The left-only part can be checked once per left Card, before looking
+    /// for a right Card.
+    pub fn split_conjunction(&self) -> Conjuncts {
+        let mut parts = vec![];
+        flatten_and(self, &mut parts);
+        let (mut left, mut right, mut mixed) = (vec![], vec![], vec![]);
+        for part in parts {
+            match part.sides() {
+                (true, false) => left.push(part.clone()),
+                (false, true) => right.push(part.clone()),
+                _ => mixed.push(part.clone()),
+            }
+        }
+        Conjuncts {
+            left: join_and(left),
+            right: join_and(right),
+            mixed: join_and(mixed),
+        }
+    }
+
+    /// Whether the expression reads variables of the left and right Cards
+    pub fn sides(&self) -> (bool, bool) {
+        let mut sides = (false, false);
+        visit_variables(self, &mut |side, _| match side {
+            EdgeSide::Left => sides.0 = true,
+            EdgeSide::Right => sides.1 = true,
+        });
+        sides
+    }
+
+    /// The variables read by the expression, in the order they first appear
+    pub fn references(&self) -> Vec<(EdgeSide, String)> {
+        let mut res: Vec<(EdgeSide, String)> = vec![];
+        visit_variables(self, &mut |side, key| {
+            if !res.iter().any(|(s, k)| *s == side && k == key) {
+                res.push((side, key.to_owned()));
+            }
+        });
+        res
+    }
+}
+
+/// A variable read by an expression
+#[derive(Debug, Clone, PartialEq, NifMap)]
+pub struct Reference {
+    pub side: EdgeSide,
+    pub key: String,
+    /// The type of the column in the table, None if it has no such column
+    pub column_type: Option<ExprType>,
+}
+
+/// What is known about the source of an expression without evaluating it
+#[derive(Debug, Clone, PartialEq, NifMap)]
+pub struct Analysis {
+    /// Empty if the source doesn't parse
+    pub references: Vec<Reference>,
+    /// None if the expression doesn't parse or type-check
+    pub return_type: Option<ExprType>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Parse and type-check the source against the tables, collecting every
+/// error instead of stopping at the first one.
+///
+/// The references are reported even when the type check fails, e.g. because
+/// a column they read was removed from the table.
+pub fn analyze(src: &str, left: &CardTable, right: &CardTable) -> Analysis {
+    let e = match expr(src) {
+        Ok(e) => e,
+        Err(diagnostics) => {
+            return Analysis {
+                references: vec![],
+                return_type: None,
+                diagnostics,
+            }
+        }
+    };
+    let references = e
+        .references()
+        .into_iter()
+        .map(|(side, key)| {
+            let table = match side {
+                EdgeSide::Left => left,
+                EdgeSide::Right => right,
+            };
+            Reference {
+                column_type: column_type(table, &key),
+                side,
+                key,
+            }
+        })
+        .collect();
+    let (return_type, diagnostics) = match e.optimize(left, right) {
+        Ok(ie) => (Some(ie.get_type()), vec![]),
+        Err(diagnostic) => (None, vec![diagnostic]),
+    };
+    Analysis {
+        references,
+        return_type,
+        diagnostics,
+    }
+}
+
+/// The type of the variable `key` of the table
+fn column_type(table: &CardTable, key: &str) -> Option<ExprType> {
+    if key == "Card" {
+        return Some(ExprType::String);
+    }
+    let sd = table.stat_defs.iter().find(|sd| sd.label == key)?;
+    Some(match sd.data {
+        StatArray::Number { .. } => ExprType::Number,
+        StatArray::Date { .. } => ExprType::Date,
+        StatArray::String { .. } => ExprType::String,
+        StatArray::LatLng { .. } => ExprType::LatLng,
+        StatArray::Bool { .. } => ExprType::Bool,
+    })
+}
+
+fn flatten_and<'e>(e: &'e Expression, parts: &mut Vec<&'e Expression>) {
+    match e {
+        Expression::Binary {
+            op: BinOp::And,
+            lhs,
+            rhs,
+        } => {
+            flatten_and(&lhs.0, parts);
+            flatten_and(&rhs.0, parts);
+        }
+        e => parts.push(e),
+    }
+}
+
+fn join_and(parts: Vec<Expression>) -> Option<Expression> {
+    parts.into_iter().reduce(|lhs, rhs| Expression::Binary {
+        op: BinOp::And,
+        lhs: lhs.into(),
+        rhs: rhs.into(),
+    })
+}
+
+/// Call `f` on every variable of the expression, in source order
+fn visit_variables(e: &Expression, f: &mut impl FnMut(EdgeSide, &str)) {
+    match e {
+        Expression::Number { .. }
+        | Expression::Date { .. }
+        | Expression::LatLng { .. }
+        | Expression::String { .. }
+        | Expression::Local { .. } => (),
+        Expression::Variable { side, key } => f(*side, key),
+        // the Card itself, like its title
+        Expression::Card { side } => f(*side, "Card"),
+        Expression::Unary { child, .. } => visit_variables(&child.0, f),
+        Expression::Call { args, .. } => {
+            for arg in args {
+                visit_variables(&arg.0, f);
+            }
+        }
+        Expression::Binary { lhs, rhs, .. } => {
+            visit_variables(&lhs.0, f);
+            visit_variables(&rhs.0, f);
+        }
+        Expression::Block { stmts, ret } => {
+            for stmt in stmts {
+                match stmt {
+                    Statement::Let { value, .. } => visit_variables(&value.0, f),
+                    Statement::Guard { cond } => visit_variables(&cond.0, f),
+                }
+            }
+            visit_variables(&ret.0, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tinylang::expr;
+
+    #[test]
+    fn test_split_conjunction() {
+        let e = expr(
+            r#"L"a" > 1 and R"b" and L"a" < R"a" and (L"c" or 2 > 1) and not R"d"? and 1 == 1"#,
+        )
+        .unwrap();
+        let parts = e.split_conjunction();
+        assert_eq!(
+            parts.left.unwrap().to_string(),
+            "(and (> (Left a) 1) (or (Left c) (> 2 1)))"
+        );
+        assert_eq!(
+            parts.right.unwrap().to_string(),
+            "(and (Right b) (not (? (Right d))))"
+        );
+        assert_eq!(
+            parts.mixed.unwrap().to_string(),
+            "(and (< (Left a) (Right a)) (== 1 1))"
+        );
+
+        let e = expr(r#"L"a" or R"b""#).unwrap();
+        let parts = e.split_conjunction();
+        assert_eq!(parts.left, None);
+        assert_eq!(parts.right, None);
+        assert_eq!(parts.mixed, Some(e));
+
+        let e = expr(r#"{ let x = L"a"; return x > 1 }"#).unwrap();
+        let parts = e.split_conjunction();
+        assert_eq!(parts.left, Some(e));
+    }
+}
@@ -0,0 +1,1329 @@
+use std::{borrow::Cow, fmt, sync::Arc};
+
+use smallvec::{smallvec, SmallVec};
+
+use crate::{
+    bitset::Bitset,
+    types::{Card, CardTable, EdgeSide, NaiveDateTimeExt, StatArray},
+};
+
+use super::{
+    diagnostic::Diagnostic,
+    graph::CardSet,
+    interpreter::{
+        add_days, bearing_deg, capture, days_between, distance_km, format_date, midpoint,
+        parse_date, parse_number, ExprType, IBool, ICards, IDate, IDuration, IExpr, ILatLng,
+        ILocal, INumber, IStmt, IString, IntermediateExpr, OwnedExprValue, PartialContext,
+        StringColumn,
+    },
+    parser::Expression,
+};
+
+/// Where a variable reads its values from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Title,
+    /// Index in `CardTable::stat_defs`
+    Stat(usize),
+}
+
+/// What a compiled node can see while it is evaluated
+struct Frame<'a> {
+    /// The bound columns, indexed like `Code::columns`
+    columns: &'a [Values<'a>],
+    strings: &'a [String],
+    /// Values of the let-bindings evaluated so far, indexed by slot
+    locals: &'a [Option<OwnedExprValue>],
+    left_idx: usize,
+    right_idx: usize,
+}
+
+impl Frame<'_> {
+    fn index(&self, side: EdgeSide) -> usize {
+        match side {
+            EdgeSide::Left => self.left_idx,
+            EdgeSide::Right => self.right_idx,
+        }
+    }
+}
+
+type Eval<T> = Box<dyn Fn(&Frame<'_>) -> Option<T> + Send + Sync>;
+type EvalStr = Box<dyn for<'a> Fn(&Frame<'a>) -> Option<Cow<'a, str>> + Send + Sync>;
+type EvalCards = Box<dyn Fn(&Frame<'_>) -> CardSet + Send + Sync>;
+
+// Closures only infer a signature that is generic over the Frame's lifetime
+// when they are passed straight to a function expecting one
+fn eval<T>(f: impl Fn(&Frame<'_>) -> Option<T> + Send + Sync + 'static) -> Eval<T> {
+    Box::new(f)
+}
+
+fn eval_str(
+    f: impl for<'a> Fn(&Frame<'a>) -> Option<Cow<'a, str>> + Send + Sync + 'static,
+) -> EvalStr {
+    Box::new(f)
+}
+
+fn binary<T: 'static, U: 'static, R>(
+    lhs: Eval<T>,
+    rhs: Eval<U>,
+    op: impl Fn(T, U) -> R + Send + Sync + 'static,
+) -> Eval<R> {
+    eval(move |f| Some(op(lhs(f)?, rhs(f)?)))
+}
+
+enum Node {
+    Bool(Eval<bool>),
+    Number(Eval<f64>),
+    LatLng(Eval<(f64, f64)>),
+    Date(Eval<NaiveDateTimeExt>),
+    Duration(Eval<f64>),
+    String(EvalStr),
+}
+
+impl Node {
+    fn get_type(&self) -> ExprType {
+        match self {
+            Node::Bool(_) => ExprType::Bool,
+            Node::Number(_) => ExprType::Number,
+            Node::LatLng(_) => ExprType::LatLng,
+            Node::Date(_) => ExprType::Date,
+            Node::Duration(_) => ExprType::Duration,
+            Node::String(_) => ExprType::String,
+        }
+    }
+
+    fn get_value(&self, f: &Frame<'_>) -> Option<OwnedExprValue> {
+        match self {
+            Node::Bool(inner) => inner(f).map(OwnedExprValue::Bool),
+            Node::Number(inner) => inner(f).map(OwnedExprValue::Number),
+            Node::LatLng(inner) => inner(f).map(OwnedExprValue::LatLng),
+            Node::Date(inner) => inner(f).map(OwnedExprValue::Date),
+            Node::Duration(inner) => inner(f).map(OwnedExprValue::Duration),
+            Node::String(inner) => inner(f).map(|s| OwnedExprValue::String(s.into_owned())),
+        }
+    }
+}
+
+enum Stmt {
+    Let(Node),
+    Guard(Eval<bool>),
+}
+
+struct Code {
+    stmts: Vec<Stmt>,
+    root: Node,
+    strings: Vec<String>,
+    /// The columns read by the program, and their type
+    columns: Vec<(EdgeSide, Column, ExprType)>,
+    /// The columns that must have a value for the result to have one
+    root_vars: Vec<(EdgeSide, usize)>,
+    is_const: bool,
+}
+
+impl fmt::Debug for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Code")
+            .field("type", &self.root.get_type())
+            .field("columns", &self.columns)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A type-checked expression, compiled into closures.
+///
+/// Variables are referred to by their column index in the tables the program
+/// was compiled with, so it can be kept as long as the Decks don't change, and
+/// bound to them for each evaluation. Clones share the closures.
+#[derive(Debug, Clone)]
+pub struct Program(Arc<Code>);
+
+impl Expression {
+    /// Type-check the expression against the tables, and compile it.
+    pub fn compile(&self, left: &CardTable, right: &CardTable) -> Result<Program, Diagnostic> {
+        Ok(self.optimize(left, right)?.compile())
+    }
+}
+
+impl IntermediateExpr<'_> {
+    pub fn compile(&self) -> Program {
+        let mut compiler = Compiler::default();
+        let stmts = self
+            .stmts
+            .iter()
+            .map(|stmt| match stmt {
+                IStmt::Let(ILocal::Bool(def)) => Stmt::Let(Node::Bool(compiler.bool(def))),
+                IStmt::Let(ILocal::Number(def)) => Stmt::Let(Node::Number(compiler.number(def))),
+                IStmt::Let(ILocal::LatLng(def)) => Stmt::Let(Node::LatLng(compiler.lat_lng(def))),
+                IStmt::Let(ILocal::Date(def)) => Stmt::Let(Node::Date(compiler.date(def))),
+                IStmt::Let(ILocal::Duration(def)) => {
+                    Stmt::Let(Node::Duration(compiler.duration(def)))
+                }
+                IStmt::Let(ILocal::String(def)) => Stmt::Let(Node::String(compiler.string(def))),
+                IStmt::Guard(cond) => Stmt::Guard(compiler.bool(cond)),
+            })
+            .collect();
+        let root = match &self.root {
+            IExpr::Bool(inner) => Node::Bool(compiler.bool(inner)),
+            IExpr::Number(inner) => Node::Number(compiler.number(inner)),
+            IExpr::LatLng(inner) => Node::LatLng(compiler.lat_lng(inner)),
+            IExpr::Date(inner) => Node::Date(compiler.date(inner)),
+            IExpr::Duration(inner) => Node::Duration(compiler.duration(inner)),
+            IExpr::String(inner) => Node::String(compiler.string(inner)),
+        };
+        let root_vars = compiler.vars(|vars| vars.expr(&self.root));
+        Program(Arc::new(Code {
+            stmts,
+            root,
+            strings: compiler.strings,
+            columns: compiler.columns,
+            root_vars,
+            is_const: self.is_const(),
+        }))
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    strings: Vec<String>,
+    columns: Vec<(EdgeSide, Column, ExprType)>,
+}
+
+/// Index of the column in `columns`, adding it if it isn't read yet
+fn column_index(
+    columns: &mut Vec<(EdgeSide, Column, ExprType)>,
+    side: EdgeSide,
+    column: Column,
+    ty: ExprType,
+) -> usize {
+    let key = (side, column, ty);
+    match columns.iter().position(|c| *c == key) {
+        Some(i) => i,
+        None => {
+            columns.push(key);
+            columns.len() - 1
+        }
+    }
+}
+
+impl Compiler {
+    /// Collect the columns that must have a value for a node to have one
+    fn vars(&mut self, collect: impl FnOnce(&mut Vars)) -> Vec<(EdgeSide, usize)> {
+        let mut vars = Vars {
+            columns: &mut self.columns,
+            indices: vec![],
+        };
+        collect(&mut vars);
+        vars.indices
+    }
+
+    fn present(&mut self, collect: impl FnOnce(&mut Vars)) -> Eval<bool> {
+        let vars = self.vars(collect);
+        eval(move |f| {
+            Some(
+                vars.iter()
+                    .all(|(side, i)| f.columns[*i].is_present(f.index(*side))),
+            )
+        })
+    }
+
+    fn column(&mut self, side: EdgeSide, column: Column, ty: ExprType) -> usize {
+        column_index(&mut self.columns, side, column, ty)
+    }
+
+    fn bool(&mut self, e: &IBool<'_>) -> Eval<bool> {
+        match e {
+            IBool::Bool { value } => {
+                let value = *value;
+                eval(move |_| Some(value))
+            }
+            IBool::BoolVariable { side, values } => {
+                let side = *side;
+                let i = self.column(side, Column::Stat(values.1), ExprType::Bool);
+                eval(move |f| match f.columns[i] {
+                    Values::Bool(values) => values.get(f.index(side)).copied().flatten(),
+                    _ => None,
+                })
+            }
+            IBool::NotNilBool { child } => self.present(|vars| vars.bool(child)),
+            IBool::NotNilNumber { child } => self.present(|vars| vars.number(child)),
+            IBool::NotNilLatLng { child } => self.present(|vars| vars.lat_lng(child)),
+            IBool::NotNilDate { child } => self.present(|vars| vars.date(child)),
+            IBool::NotNilDuration { child } => self.present(|vars| vars.duration(child)),
+            IBool::NotNilString { child } => self.present(|vars| vars.string(child)),
+            IBool::Not { child } => {
+                let child = self.bool(child);
+                eval(move |f| child(f).map(|x| !x))
+            }
+            IBool::EqBool { lhs, rhs, invert } => {
+                let invert = *invert;
+                binary(self.bool(lhs), self.bool(rhs), move |l, r| {
+                    invert != (l == r)
+                })
+            }
+            IBool::EqNumber { lhs, rhs, invert } => {
+                let invert = *invert;
+                binary(self.number(lhs), self.number(rhs), move |l, r| {
+                    invert != (l == r)
+                })
+            }
+            IBool::EqLatLng { lhs, rhs, invert } => {
+                let invert = *invert;
+                binary(self.lat_lng(lhs), self.lat_lng(rhs), move |l, r| {
+                    invert != (l == r)
+                })
+            }
+            IBool::EqDate { lhs, rhs, invert } => {
+                let invert = *invert;
+                binary(self.date(lhs), self.date(rhs), move |l, r| {
+                    invert != (l == r)
+                })
+            }
+            IBool::EqDuration { lhs, rhs, invert } => {
+                let invert = *invert;
+                binary(self.duration(lhs), self.duration(rhs), move |l, r| {
+                    invert != (l == r)
+                })
+            }
+            IBool::EqString { lhs, rhs, invert } => {
+                let invert = *invert;
+                let lhs = self.string(lhs);
+                let rhs = self.string(rhs);
+                eval(move |f| Some(invert != (lhs(f)? == rhs(f)?)))
+            }
+            IBool::Matches { lhs, regex } => {
+                let lhs = self.string(lhs);
+                let regex = regex.clone();
+                eval(move |f| Some(regex.is_match(&lhs(f)?)))
+            }
+            IBool::HasEdge {
+                adjacency,
+                lhs,
+                rhs,
+            } => {
+                let adjacency = adjacency.clone();
+                let (lhs, rhs) = (self.cards(lhs), self.cards(rhs));
+                eval(move |f| Some(adjacency.edge(&lhs(f), &rhs(f)).is_some()))
+            }
+            IBool::CmpNumber {
+                lhs,
+                rhs,
+                ordering,
+                invert,
+            } => {
+                let (ordering, invert) = (*ordering, *invert);
+                binary(self.number(lhs), self.number(rhs), move |l, r| {
+                    invert != (l.total_cmp(&r) == ordering)
+                })
+            }
+            IBool::CmpDate {
+                lhs,
+                rhs,
+                ordering,
+                invert,
+            } => {
+                let (ordering, invert) = (*ordering, *invert);
+                binary(self.date(lhs), self.date(rhs), move |l, r| {
+                    invert != (l.cmp(&r) == ordering)
+                })
+            }
+            IBool::CmpDuration {
+                lhs,
+                rhs,
+                ordering,
+                invert,
+            } => {
+                let (ordering, invert) = (*ordering, *invert);
+                binary(self.duration(lhs), self.duration(rhs), move |l, r| {
+                    invert != (l.total_cmp(&r) == ordering)
+                })
+            }
+            IBool::And { lhs, rhs } => {
+                let lhs = self.bool(lhs);
+                let rhs = self.bool(rhs);
+                eval(move |f| Some(lhs(f)? && rhs(f)?))
+            }
+            IBool::Or { lhs, rhs } => {
+                let lhs = self.bool(lhs);
+                let rhs = self.bool(rhs);
+                eval(move |f| Some(lhs(f)? || rhs(f)?))
+            }
+            IBool::Local { slot, def: _ } => {
+                let slot = *slot;
+                eval(move |f| match f.locals.get(slot) {
+                    Some(Some(OwnedExprValue::Bool(v))) => Some(*v),
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    fn number(&mut self, e: &INumber<'_>) -> Eval<f64> {
+        match e {
+            INumber::Number { value } => {
+                let value = *value;
+                eval(move |_| Some(value))
+            }
+            INumber::NumberVariable { side, values } => {
+                let side = *side;
+                let i = self.column(side, Column::Stat(values.1), ExprType::Number);
+                eval(move |f| match f.columns[i] {
+                    Values::Number(values) => values.get(f.index(side)).copied().flatten(),
+                    _ => None,
+                })
+            }
+            INumber::Aggregate { side, values, .. } => {
+                let side = *side;
+                let values = values.clone();
+                eval(move |f| values.get(f.index(side)).copied().flatten())
+            }
+            INumber::Neg { child } => {
+                let child = self.number(child);
+                eval(move |f| child(f).map(|x| -x))
+            }
+            INumber::Add { lhs, rhs } => binary(self.number(lhs), self.number(rhs), |l, r| l + r),
+            INumber::SubNumber { lhs, rhs } => {
+                binary(self.number(lhs), self.number(rhs), |l, r| l - r)
+            }
+            INumber::SubDate { lhs, rhs } => binary(self.date(lhs), self.date(rhs), days_between),
+            INumber::Mul { lhs, rhs } => binary(self.number(lhs), self.number(rhs), |l, r| l * r),
+            INumber::Div { lhs, rhs } => binary(self.number(lhs), self.number(rhs), |l, r| l / r),
+            INumber::Pow { lhs, rhs } => binary(self.number(lhs), self.number(rhs), f64::powf),
+            INumber::Dist { lhs, rhs } => binary(self.lat_lng(lhs), self.lat_lng(rhs), distance_km),
+            INumber::Bearing { lhs, rhs } => {
+                binary(self.lat_lng(lhs), self.lat_lng(rhs), bearing_deg)
+            }
+            INumber::DivDuration { lhs, rhs } => {
+                binary(self.duration(lhs), self.duration(rhs), |l, r| l / r)
+            }
+            INumber::ParseString { child } => {
+                let child = self.string(child);
+                eval(move |f| parse_number(&child(f)?))
+            }
+            INumber::Count { child } => {
+                let child = self.cards(child);
+                eval(move |f| Some(child(f).len() as f64))
+            }
+            INumber::Local { slot, def: _ } => {
+                let slot = *slot;
+                eval(move |f| match f.locals.get(slot) {
+                    Some(Some(OwnedExprValue::Number(v))) => Some(*v),
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    fn lat_lng(&mut self, e: &ILatLng<'_>) -> Eval<(f64, f64)> {
+        match e {
+            ILatLng::LatLng { value } => {
+                let value = *value;
+                eval(move |_| Some(value))
+            }
+            ILatLng::LatLngVariable { side, values } => {
+                let side = *side;
+                let i = self.column(side, Column::Stat(values.1), ExprType::LatLng);
+                eval(move |f| match f.columns[i] {
+                    Values::LatLng(values) => values.get(f.index(side)).copied().flatten(),
+                    _ => None,
+                })
+            }
+            ILatLng::Midpoint { lhs, rhs } => {
+                binary(self.lat_lng(lhs), self.lat_lng(rhs), midpoint)
+            }
+            ILatLng::Local { slot, def: _ } => {
+                let slot = *slot;
+                eval(move |f| match f.locals.get(slot) {
+                    Some(Some(OwnedExprValue::LatLng(v))) => Some(*v),
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    fn date(&mut self, e: &IDate<'_>) -> Eval<NaiveDateTimeExt> {
+        match e {
+            IDate::Date { value } => {
+                let value = *value;
+                eval(move |_| Some(value))
+            }
+            IDate::DateVariable { side, values } => {
+                let side = *side;
+                let i = self.column(side, Column::Stat(values.1), ExprType::Date);
+                eval(move |f| match f.columns[i] {
+                    Values::Date(values) => values.get(f.index(side)).copied().flatten(),
+                    _ => None,
+                })
+            }
+            IDate::ParseString { child } => {
+                let child = self.string(child);
+                eval(move |f| parse_date(&child(f)?))
+            }
+            IDate::AddDuration { lhs, rhs } => {
+                let lhs = self.date(lhs);
+                let rhs = self.duration(rhs);
+                eval(move |f| add_days(lhs(f)?, rhs(f)?))
+            }
+            IDate::Local { slot, def: _ } => {
+                let slot = *slot;
+                eval(move |f| match f.locals.get(slot) {
+                    Some(Some(OwnedExprValue::Date(v))) => Some(*v),
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    fn duration(&mut self, e: &IDuration<'_>) -> Eval<f64> {
+        match e {
+            IDuration::Duration { value } => {
+                let value = *value;
+                eval(move |_| Some(value))
+            }
+            IDuration::FromNumber {
+                child,
+                days_per_unit,
+            } => {
+                let days_per_unit = *days_per_unit;
+                let child = self.number(child);
+                eval(move |f| child(f).map(|x| x * days_per_unit))
+            }
+            IDuration::Neg { child } => {
+                let child = self.duration(child);
+                eval(move |f| child(f).map(|x| -x))
+            }
+            IDuration::Add { lhs, rhs } => {
+                binary(self.duration(lhs), self.duration(rhs), |l, r| l + r)
+            }
+            IDuration::Scale { lhs, rhs } => {
+                binary(self.duration(lhs), self.number(rhs), |l, r| l * r)
+            }
+            IDuration::Local { slot, def: _ } => {
+                let slot = *slot;
+                eval(move |f| match f.locals.get(slot) {
+                    Some(Some(OwnedExprValue::Duration(v))) => Some(*v),
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    fn string(&mut self, e: &IString<'_>) -> EvalStr {
+        match e {
+            IString::String { value } => {
+                self.strings.push(value.clone());
+                let i = self.strings.len() - 1;
+                eval_str(move |f| Some(Cow::Borrowed(f.strings[i].as_str())))
+            }
+            IString::StringVariable { side, values } => {
+                let side = *side;
+                let i = self.column(side, column(values), ExprType::String);
+                eval_str(move |f| match f.columns[i] {
+                    Values::String(values) => values
+                        .get(f.index(side))
+                        .and_then(|s| s.as_deref())
+                        .map(Cow::Borrowed),
+                    Values::Title(cards) => cards
+                        .get(f.index(side))
+                        .map(|c| Cow::Borrowed(c.title.as_str())),
+                    _ => None,
+                })
+            }
+            IString::FormatNumber { child } => {
+                let child = self.number(child);
+                eval_str(move |f| child(f).map(|x| Cow::Owned(x.to_string())))
+            }
+            IString::FormatDate { child } => {
+                let child = self.date(child);
+                eval_str(move |f| child(f).map(|x| Cow::Owned(format_date(x))))
+            }
+            IString::Capture { child, regex } => {
+                let child = self.string(child);
+                let regex = regex.clone();
+                eval_str(move |f| match child(f)? {
+                    Cow::Borrowed(s) => capture(&regex, s).map(Cow::Borrowed),
+                    Cow::Owned(s) => capture(&regex, &s).map(|x| Cow::Owned(x.to_owned())),
+                })
+            }
+            IString::EdgeInfo {
+                adjacency,
+                lhs,
+                rhs,
+            } => {
+                let adjacency = adjacency.clone();
+                let (lhs, rhs) = (self.cards(lhs), self.cards(rhs));
+                eval_str(move |f| {
+                    let info = adjacency.edge(&lhs(f), &rhs(f))??;
+                    Some(Cow::Owned(info.to_owned()))
+                })
+            }
+            IString::Local { slot, def: _ } => {
+                let slot = *slot;
+                eval_str(move |f| match f.locals.get(slot) {
+                    Some(Some(OwnedExprValue::String(v))) => Some(Cow::Borrowed(v.as_str())),
+                    _ => None,
+                })
+            }
+        }
+    }
+
+    fn cards(&mut self, e: &ICards) -> EvalCards {
+        match e {
+            ICards::Card { side } => {
+                let side = *side;
+                Box::new(move |f| smallvec![f.index(side)])
+            }
+            ICards::Neighbors { adjacency, child } => {
+                let adjacency = adjacency.clone();
+                let child = self.cards(child);
+                Box::new(move |f| adjacency.neighbors(&child(f)))
+            }
+        }
+    }
+}
+
+fn column(values: &StringColumn<'_>) -> Column {
+    match values {
+        StringColumn::Direct(values) => Column::Stat(values.1),
+        StringColumn::Title(_) => Column::Title,
+    }
+}
+
+/// The columns that must have a value for a node to have one, following
+/// let-bindings into their definitions. `?` always has a value.
+struct Vars<'c> {
+    columns: &'c mut Vec<(EdgeSide, Column, ExprType)>,
+    indices: Vec<(EdgeSide, usize)>,
+}
+
+impl Vars<'_> {
+    fn push(&mut self, side: EdgeSide, column: Column, ty: ExprType) {
+        let i = column_index(self.columns, side, column, ty);
+        self.indices.push((side, i));
+    }
+
+    fn expr(&mut self, e: &IExpr<'_>) {
+        match e {
+            IExpr::Bool(inner) => self.bool(inner),
+            IExpr::Number(inner) => self.number(inner),
+            IExpr::LatLng(inner) => self.lat_lng(inner),
+            IExpr::Date(inner) => self.date(inner),
+            IExpr::Duration(inner) => self.duration(inner),
+            IExpr::String(inner) => self.string(inner),
+        }
+    }
+
+    fn bool(&mut self, e: &IBool<'_>) {
+        match e {
+            IBool::BoolVariable { side, values } => {
+                self.push(*side, Column::Stat(values.1), ExprType::Bool)
+            }
+            IBool::Bool { .. }
+            | IBool::NotNilBool { .. }
+            | IBool::NotNilNumber { .. }
+            | IBool::NotNilLatLng { .. }
+            | IBool::NotNilDate { .. }
+            | IBool::NotNilDuration { .. }
+            | IBool::NotNilString { .. }
+            | IBool::HasEdge { .. } => (),
+            IBool::Not { child } => self.bool(child),
+            IBool::EqBool { lhs, rhs, .. } | IBool::And { lhs, rhs } | IBool::Or { lhs, rhs } => {
+                self.bool(lhs);
+                self.bool(rhs);
+            }
+            IBool::EqNumber { lhs, rhs, .. } | IBool::CmpNumber { lhs, rhs, .. } => {
+                self.number(lhs);
+                self.number(rhs);
+            }
+            IBool::EqLatLng { lhs, rhs, .. } => {
+                self.lat_lng(lhs);
+                self.lat_lng(rhs);
+            }
+            IBool::EqDate { lhs, rhs, .. } | IBool::CmpDate { lhs, rhs, .. } => {
+                self.date(lhs);
+                self.date(rhs);
+            }
+            IBool::EqDuration { lhs, rhs, .. } | IBool::CmpDuration { lhs, rhs, .. } => {
+                self.duration(lhs);
+                self.duration(rhs);
+            }
+            IBool::EqString { lhs, rhs, .. } => {
+                self.string(lhs);
+                self.string(rhs);
+            }
+            IBool::Matches { lhs, regex: _ } => self.string(lhs),
+            IBool::Local { slot: _, def } => self.bool(def),
+        }
+    }
+
+    fn number(&mut self, e: &INumber<'_>) {
+        match e {
+            INumber::Number { .. } => (),
+            INumber::NumberVariable { side, values }
+            | INumber::Aggregate {
+                side,
+                column: values,
+                ..
+            } => self.push(*side, Column::Stat(values.1), ExprType::Number),
+            INumber::Neg { child } => self.number(child),
+            INumber::Add { lhs, rhs }
+            | INumber::SubNumber { lhs, rhs }
+            | INumber::Mul { lhs, rhs }
+            | INumber::Div { lhs, rhs }
+            | INumber::Pow { lhs, rhs } => {
+                self.number(lhs);
+                self.number(rhs);
+            }
+            INumber::SubDate { lhs, rhs } => {
+                self.date(lhs);
+                self.date(rhs);
+            }
+            INumber::Dist { lhs, rhs } | INumber::Bearing { lhs, rhs } => {
+                self.lat_lng(lhs);
+                self.lat_lng(rhs);
+            }
+            INumber::DivDuration { lhs, rhs } => {
+                self.duration(lhs);
+                self.duration(rhs);
+            }
+            INumber::ParseString { child } => self.string(child),
+            INumber::Count { .. } => (),
+            INumber::Local { slot: _, def } => self.number(def),
+        }
+    }
+
+    fn lat_lng(&mut self, e: &ILatLng<'_>) {
+        match e {
+            ILatLng::LatLng { .. } => (),
+            ILatLng::LatLngVariable { side, values } => {
+                self.push(*side, Column::Stat(values.1), ExprType::LatLng)
+            }
+            ILatLng::Midpoint { lhs, rhs } => {
+                self.lat_lng(lhs);
+                self.lat_lng(rhs);
+            }
+            ILatLng::Local { slot: _, def } => self.lat_lng(def),
+        }
+    }
+
+    fn date(&mut self, e: &IDate<'_>) {
+        match e {
+            IDate::Date { .. } => (),
+            IDate::DateVariable { side, values } => {
+                self.push(*side, Column::Stat(values.1), ExprType::Date)
+            }
+            IDate::ParseString { child } => self.string(child),
+            IDate::AddDuration { lhs, rhs } => {
+                self.date(lhs);
+                self.duration(rhs);
+            }
+            IDate::Local { slot: _, def } => self.date(def),
+        }
+    }
+
+    fn duration(&mut self, e: &IDuration<'_>) {
+        match e {
+            IDuration::Duration { .. } => (),
+            IDuration::FromNumber { child, .. } => self.number(child),
+            IDuration::Neg { child } => self.duration(child),
+            IDuration::Add { lhs, rhs } => {
+                self.duration(lhs);
+                self.duration(rhs);
+            }
+            IDuration::Scale { lhs, rhs } => {
+                self.duration(lhs);
+                self.number(rhs);
+            }
+            IDuration::Local { slot: _, def } => self.duration(def),
+        }
+    }
+
+    fn string(&mut self, e: &IString<'_>) {
+        match e {
+            IString::String { .. } => (),
+            IString::StringVariable { side, values } => {
+                self.push(*side, column(values), ExprType::String)
+            }
+            IString::FormatNumber { child } => self.number(child),
+            IString::FormatDate { child } => self.date(child),
+            IString::Capture { child, regex: _ } => self.string(child),
+            IString::EdgeInfo { .. } => (),
+            IString::Local { slot: _, def } => self.string(def),
+        }
+    }
+}
+
+/// The values of a column in the bound tables
+#[derive(Clone, Copy)]
+enum Values<'a> {
+    Bool(&'a [Option<bool>]),
+    Number(&'a [Option<f64>]),
+    LatLng(&'a [Option<(f64, f64)>]),
+    Date(&'a [Option<NaiveDateTimeExt>]),
+    String(&'a [Option<String>]),
+    Title(&'a [Card]),
+    /// The column isn't in the table, or has another type
+    Missing,
+}
+
+impl<'a> Values<'a> {
+    fn new(table: &'a CardTable, column: Column, ty: ExprType) -> Self {
+        let data = match column {
+            Column::Title => return Values::Title(&table.cards),
+            Column::Stat(i) => table.stat_defs.get(i).map(|sd| &sd.data),
+        };
+        match (data, ty) {
+            (Some(StatArray::Bool { values }), ExprType::Bool) => Values::Bool(values),
+            (Some(StatArray::Number { unit: _, values }), ExprType::Number) => {
+                Values::Number(values)
+            }
+            (Some(StatArray::LatLng { values }), ExprType::LatLng) => Values::LatLng(values),
+            (Some(StatArray::Date { values }), ExprType::Date) => Values::Date(values),
+            (Some(StatArray::String { values }), ExprType::String) => Values::String(values),
+            _ => Values::Missing,
+        }
+    }
+
+    fn is_present(&self, index: usize) -> bool {
+        match self {
+            Values::Bool(values) => matches!(values.get(index), Some(Some(_))),
+            Values::Number(values) => matches!(values.get(index), Some(Some(_))),
+            Values::LatLng(values) => matches!(values.get(index), Some(Some(_))),
+            Values::Date(values) => matches!(values.get(index), Some(Some(_))),
+            Values::String(values) => matches!(values.get(index), Some(Some(_))),
+            Values::Title(cards) => index < cards.len(),
+            Values::Missing => false,
+        }
+    }
+
+    /// The indices below `bound` that have a value
+    fn presence(&self, bound: usize) -> Bitset {
+        match self {
+            Values::Title(cards) => Bitset::from_fn(bound, |i| i < cards.len()),
+            Values::Missing => Bitset::new(bound),
+            _ => Bitset::from_fn(bound, |i| self.is_present(i)),
+        }
+    }
+}
+
+/// A program bound to the tables its variables read from
+#[derive(Clone)]
+pub struct BoundProgram<'a> {
+    code: &'a Code,
+    columns: SmallVec<[Values<'a>; 4]>,
+    /// The number of Cards in the left and right tables
+    sizes: (usize, usize),
+}
+
+impl Program {
+    pub fn get_type(&self) -> ExprType {
+        self.0.root.get_type()
+    }
+
+    /// Look up the columns read by the program in the tables, which should
+    /// be the ones it was compiled with.
+    pub fn bind<'a>(&'a self, left: &'a CardTable, right: &'a CardTable) -> BoundProgram<'a> {
+        let columns = self
+            .0
+            .columns
+            .iter()
+            .map(|(side, column, ty)| match side {
+                EdgeSide::Left => Values::new(left, *column, *ty),
+                EdgeSide::Right => Values::new(right, *column, *ty),
+            })
+            .collect();
+        BoundProgram {
+            code: &self.0,
+            columns,
+            sizes: (left.cards.len(), right.cards.len()),
+        }
+    }
+}
+
+impl BoundProgram<'_> {
+    /// The value doesn't depend on the cards, the expression was folded into
+    /// a constant
+    pub fn is_const(&self) -> bool {
+        self.code.is_const
+    }
+
+    pub fn has_vars(&self, ctx: &PartialContext) -> bool {
+        self.code
+            .root_vars
+            .iter()
+            .all(|(side, i)| match (ctx, side) {
+                (PartialContext::Left(j), EdgeSide::Left) => self.columns[*i].is_present(*j),
+                (PartialContext::Right(j), EdgeSide::Right) => self.columns[*i].is_present(*j),
+                _ => true,
+            })
+    }
+
+    /// The Cards of the table on the side for which `has_vars` holds,
+    /// computed a column at a time
+    pub fn has_vars_all(&self, side: EdgeSide) -> Bitset {
+        let bound = self.size(side);
+        let mut res = Bitset::full(bound);
+        for (_, i) in self.code.root_vars.iter().filter(|(s, _)| *s == side) {
+            res.intersect_with(&self.columns[*i].presence(bound));
+        }
+        res
+    }
+
+    /// Evaluate the program for every Card of the table on the side, with
+    /// the first Card on the other side. Meant for programs that only read
+    /// one side.
+    pub fn get_values(&self, side: EdgeSide) -> Vec<Option<OwnedExprValue>> {
+        (0..self.size(side))
+            .map(|i| match side {
+                EdgeSide::Left => self.get_value(i, 0),
+                EdgeSide::Right => self.get_value(0, i),
+            })
+            .collect()
+    }
+
+    /// The Cards of the table on the side for which the Bool program is
+    /// true, like `get_values`
+    pub fn filter(&self, side: EdgeSide) -> Bitset {
+        let values = self.get_values(side);
+        Bitset::from_fn(values.len(), |i| {
+            values[i] == Some(OwnedExprValue::Bool(true))
+        })
+    }
+
+    fn size(&self, side: EdgeSide) -> usize {
+        match side {
+            EdgeSide::Left => self.sizes.0,
+            EdgeSide::Right => self.sizes.1,
+        }
+    }
+
+    pub fn get_value(&self, left_idx: usize, right_idx: usize) -> Option<OwnedExprValue> {
+        let mut locals: SmallVec<[Option<OwnedExprValue>; 4]> = SmallVec::new();
+        for stmt in self.code.stmts.iter() {
+            let frame = self.frame(left_idx, right_idx, &locals);
+            match stmt {
+                Stmt::Let(node) => {
+                    let value = node.get_value(&frame);
+                    locals.push(value);
+                }
+                Stmt::Guard(cond) => {
+                    if cond(&frame) == Some(true) {
+                        return None;
+                    }
+                }
+            }
+        }
+        self.code
+            .root
+            .get_value(&self.frame(left_idx, right_idx, &locals))
+    }
+
+    fn frame<'f>(
+        &'f self,
+        left_idx: usize,
+        right_idx: usize,
+        locals: &'f [Option<OwnedExprValue>],
+    ) -> Frame<'f> {
+        Frame {
+            columns: &self.columns,
+            strings: &self.code.strings,
+            locals,
+            left_idx,
+            right_idx,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime};
+    use proptest::prelude::*;
+
+    use crate::{
+        tinylang::{
+            expr,
+            parser::{BinOp, Expression, Statement, UnOp},
+            test_support::{card_table, CARDS},
+            PartialContext,
+        },
+        types::EdgeSide,
+    };
+
+    fn unary(op: UnOp, child: Expression) -> Expression {
+        Expression::Unary {
+            op,
+            child: child.into(),
+        }
+    }
+
+    fn binary(op: BinOp, lhs: Expression, rhs: Expression) -> Expression {
+        Expression::Binary {
+            op,
+            lhs: lhs.into(),
+            rhs: rhs.into(),
+        }
+    }
+
+    fn call(name: &str, args: Vec<Expression>) -> Expression {
+        Expression::Call {
+            name: name.into(),
+            args: args.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    fn variable(key: &'static str) -> impl Strategy<Value = Expression> {
+        prop::sample::select(vec![EdgeSide::Left, EdgeSide::Right]).prop_map(move |side| {
+            Expression::Variable {
+                side,
+                key: key.into(),
+            }
+        })
+    }
+
+    fn arb_pattern() -> impl Strategy<Value = Expression> {
+        prop::sample::select(vec!["^C", "1$", r"(?i)card (\d)", "(x)?"]).prop_map(|value| {
+            Expression::String {
+                value: value.into(),
+            }
+        })
+    }
+
+    fn arb_pairing() -> impl Strategy<Value = Expression> {
+        prop::sample::select(vec!["E", "F"]).prop_map(|value| Expression::String {
+            value: value.into(),
+        })
+    }
+
+    /// Strategies for well-typed expressions over `card_table`, one per type
+    #[derive(Clone)]
+    struct Typed {
+        bool: BoxedStrategy<Expression>,
+        number: BoxedStrategy<Expression>,
+        lat_lng: BoxedStrategy<Expression>,
+        date: BoxedStrategy<Expression>,
+        duration: BoxedStrategy<Expression>,
+        string: BoxedStrategy<Expression>,
+        /// Only passed to the functions over Pairings
+        cards: BoxedStrategy<Expression>,
+    }
+
+    impl Typed {
+        fn leaves() -> Self {
+            let number = prop_oneof![
+                (0u32..100).prop_map(f64::from),
+                prop::sample::select(vec![0.0, 0.5, 1e300, f64::INFINITY]),
+            ]
+            .prop_map(|value| Expression::Number { value });
+            let date = (1900i32..2100, 1u32..=365).prop_map(|(y, d)| Expression::Date {
+                value: NaiveDate::from_yo_opt(y, d)
+                    .unwrap()
+                    .and_time(NaiveTime::MIN)
+                    .into(),
+            });
+            let lat_lng =
+                (-90.0f64..=90.0, -180.0f64..=180.0).prop_map(|value| Expression::LatLng { value });
+            Typed {
+                bool: variable("B").boxed(),
+                number: prop_oneof![
+                    number,
+                    variable("N"),
+                    (
+                        prop::sample::select(vec![
+                            "mean",
+                            "median",
+                            "rank",
+                            "percentile",
+                            "zscore"
+                        ]),
+                        variable("N")
+                    )
+                        .prop_map(|(name, column)| call(name, vec![column])),
+                ]
+                .boxed(),
+                lat_lng: prop_oneof![lat_lng, variable("P")].boxed(),
+                date: prop_oneof![date, variable("D")].boxed(),
+                duration: (0u32..1000)
+                    .prop_map(|n| call("days", vec![Expression::Number { value: n.into() }]))
+                    .boxed(),
+                string: prop_oneof![
+                    variable("S"),
+                    variable("Card"),
+                    "[a-z0-9 ]{0,4}".prop_map(|value| Expression::String { value }),
+                ]
+                .boxed(),
+                cards: prop::sample::select(vec![EdgeSide::Left, EdgeSide::Right])
+                    .prop_map(|side| Expression::Card { side })
+                    .boxed(),
+            }
+        }
+
+        /// Expressions one level deeper than those of `self`
+        fn nest(&self) -> Self {
+            let t = self.clone();
+            let cmp = prop::sample::select(vec![
+                BinOp::Eq,
+                BinOp::Neq,
+                BinOp::Lt,
+                BinOp::Lte,
+                BinOp::Gt,
+                BinOp::Gte,
+            ]);
+            let arith = prop::sample::select(vec![
+                BinOp::Add,
+                BinOp::Sub,
+                BinOp::Mul,
+                BinOp::Div,
+                BinOp::Pow,
+            ]);
+            let any = prop_oneof![
+                t.bool.clone(),
+                t.number.clone(),
+                t.lat_lng.clone(),
+                t.date.clone(),
+                t.duration.clone(),
+                t.string.clone(),
+            ];
+            let bool = prop_oneof![
+                t.bool.clone(),
+                (cmp.clone(), t.number.clone(), t.number.clone())
+                    .prop_map(|(op, l, r)| binary(op, l, r)),
+                (cmp.clone(), t.date.clone(), t.date.clone())
+                    .prop_map(|(op, l, r)| binary(op, l, r)),
+                (cmp, t.duration.clone(), t.duration.clone())
+                    .prop_map(|(op, l, r)| binary(op, l, r)),
+                (
+                    prop::sample::select(vec![BinOp::Eq, BinOp::Neq]),
+                    prop_oneof![
+                        (t.string.clone(), t.string.clone()),
+                        (t.lat_lng.clone(), t.lat_lng.clone()),
+                        (t.bool.clone(), t.bool.clone()),
+                    ]
+                )
+                    .prop_map(|(op, (l, r))| binary(op, l, r)),
+                (
+                    prop::sample::select(vec![BinOp::And, BinOp::Or]),
+                    t.bool.clone(),
+                    t.bool.clone()
+                )
+                    .prop_map(|(op, l, r)| binary(op, l, r)),
+                t.bool.clone().prop_map(|c| unary(UnOp::Not, c)),
+                any.prop_map(|c| unary(UnOp::Bool, c)),
+                (t.string.clone(), arb_pattern()).prop_map(|(l, r)| binary(BinOp::Match, l, r)),
+                (arb_pairing(), t.cards.clone(), t.cards.clone())
+                    .prop_map(|(p, l, r)| call("has_edge", vec![p, l, r])),
+            ];
+            let number = prop_oneof![
+                t.number.clone(),
+                (arith, t.number.clone(), t.number.clone()).prop_map(|(op, l, r)| binary(op, l, r)),
+                t.number.clone().prop_map(|c| unary(UnOp::Neg, c)),
+                (t.date.clone(), t.date.clone()).prop_map(|(l, r)| binary(BinOp::Sub, l, r)),
+                (t.duration.clone(), t.duration.clone()).prop_map(|(l, r)| binary(
+                    BinOp::Div,
+                    l,
+                    r
+                )),
+                (t.lat_lng.clone(), t.lat_lng.clone()).prop_map(|(l, r)| binary(BinOp::Dist, l, r)),
+                (t.lat_lng.clone(), t.lat_lng.clone())
+                    .prop_map(|(l, r)| call("bearing", vec![l, r])),
+                t.cards.clone().prop_map(|c| call("count", vec![c])),
+            ];
+            let lat_lng = prop_oneof![
+                t.lat_lng.clone(),
+                (t.lat_lng.clone(), t.lat_lng.clone())
+                    .prop_map(|(l, r)| call("midpoint", vec![l, r])),
+            ];
+            let date = prop_oneof![
+                t.date.clone(),
+                (
+                    prop::sample::select(vec![BinOp::Add, BinOp::Sub]),
+                    t.date.clone(),
+                    t.duration.clone()
+                )
+                    .prop_map(|(op, l, r)| binary(op, l, r)),
+            ];
+            let duration = prop_oneof![
+                t.duration.clone(),
+                prop::sample::select(vec!["days", "years"]).prop_flat_map(move |name| t
+                    .number
+                    .clone()
+                    .prop_map(move |n| call(name, vec![n]))),
+                (
+                    prop::sample::select(vec![BinOp::Add, BinOp::Sub]),
+                    self.duration.clone(),
+                    self.duration.clone()
+                )
+                    .prop_map(|(op, l, r)| binary(op, l, r)),
+                (self.duration.clone(), self.number.clone()).prop_map(|(l, r)| binary(
+                    BinOp::Mul,
+                    r,
+                    l
+                )),
+                (self.duration.clone(), self.number.clone()).prop_map(|(l, r)| binary(
+                    BinOp::Div,
+                    l,
+                    r
+                )),
+                self.duration.clone().prop_map(|c| unary(UnOp::Neg, c)),
+            ];
+            Typed {
+                bool: bool.boxed(),
+                number: number.boxed(),
+                lat_lng: lat_lng.boxed(),
+                date: date.boxed(),
+                duration: duration.boxed(),
+                string: prop_oneof![
+                    self.string.clone(),
+                    (self.string.clone(), arb_pattern())
+                        .prop_map(|(l, r)| call("capture", vec![l, r])),
+                    (arb_pairing(), self.cards.clone(), self.cards.clone())
+                        .prop_map(|(p, l, r)| call("edge_info", vec![p, l, r])),
+                ]
+                .boxed(),
+                cards: prop_oneof![
+                    self.cards.clone(),
+                    (arb_pairing(), self.cards.clone())
+                        .prop_map(|(p, c)| call("neighbors", vec![p, c])),
+                ]
+                .boxed(),
+            }
+        }
+
+        fn any(&self) -> BoxedStrategy<Expression> {
+            prop_oneof![
+                self.bool.clone(),
+                self.number.clone(),
+                self.lat_lng.clone(),
+                self.date.clone(),
+                self.duration.clone(),
+                self.string.clone(),
+            ]
+            .boxed()
+        }
+    }
+
+    fn arb_typed() -> BoxedStrategy<Expression> {
+        let t = Typed::leaves().nest().nest().nest();
+        let local = || Expression::Local { name: "x".into() };
+        let block = (t.number.clone(), t.bool.clone(), t.number.clone()).prop_map(
+            move |(value, cond, ret)| Expression::Block {
+                stmts: vec![
+                    Statement::Let {
+                        name: "x".into(),
+                        ty: None,
+                        value: value.into(),
+                    },
+                    Statement::Guard {
+                        cond: binary(BinOp::Or, cond, binary(BinOp::Gt, local(), ret.clone()))
+                            .into(),
+                    },
+                ],
+                ret: binary(BinOp::Add, ret, local()).into(),
+            },
+        );
+        prop_oneof![4 => t.any(), 1 => block].boxed()
+    }
+
+    /// Tokens of the language, and a few that aren't
+    fn arb_source() -> impl Strategy<Value = String> {
+        let token = prop::sample::select(vec![
+            "(",
+            ")",
+            "{",
+            "}",
+            ":",
+            ";",
+            ",",
+            "=",
+            "==",
+            "!=",
+            "<",
+            ">=",
+            "+",
+            "-",
+            "*",
+            "**",
+            "/",
+            "?",
+            "<->",
+            "and",
+            "or",
+            "not",
+            "let",
+            "if",
+            "return",
+            "None",
+            "x",
+            "Number",
+            "Date",
+            "days",
+            "bearing",
+            "0",
+            "1.5",
+            ".",
+            "1e3",
+            "L\"N\"",
+            "R\"D\"",
+            "L\"P\"",
+            "R\"S\"",
+            "L\"B\"",
+            "L\"Card\"",
+            "D\"2000-01-01\"",
+            "D\"2000-13-01\"",
+            "C\"1,2\"",
+            "C\"91,0\"",
+            "=~",
+            "capture",
+            "L",
+            "R",
+            "neighbors",
+            "has_edge",
+            "count",
+            "\"E\"",
+            "\"^C\"",
+            "\"(\"",
+            "\"\\d\"",
+            "\"",
+            "\\",
+            "é",
+        ]);
+        prop_oneof![
+            prop::collection::vec(token, 0..24).prop_map(|tokens| tokens.join(" ")),
+            ".{0,32}",
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_compiled_agrees(e in arb_typed()) {
+            let card_table = card_table();
+            let ie = e.optimize(&card_table, &card_table).unwrap();
+            let program = e.compile(&card_table, &card_table).unwrap();
+            let bound = program.bind(&card_table, &card_table);
+            prop_assert_eq!(program.get_type(), ie.get_type());
+            for i in 0..CARDS {
+                for j in 0..CARDS {
+                    prop_assert_eq!(
+                        format!("{:?}", bound.get_value(i, j)),
+                        format!("{:?}", ie.get_value(i, j))
+                    );
+                }
+                for ctx in [PartialContext::Left(i), PartialContext::Right(i)] {
+                    prop_assert_eq!(bound.has_vars(&ctx), ie.has_vars(&ctx));
+                }
+            }
+        }
+
+        #[test]
+        fn test_no_panics(src in arb_source()) {
+            let card_table = card_table();
+            if let Ok(e) = expr(&src) {
+                let _ = e.references();
+                if let Ok(program) = e.compile(&card_table, &card_table) {
+                    let bound = program.bind(&card_table, &card_table);
+                    for i in 0..CARDS {
+                        let _ = bound.get_value(i, CARDS - 1 - i);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+
+use rustler::NifMap;
+
+/// Byte range into the source of an expression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, NifMap)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// An error found in the source of an expression.
+///
+/// Without a span, the error applies to the expression as a whole.
+#[derive(Debug, Clone, PartialEq, Eq, NifMap)]
+pub struct Diagnostic {
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(span: Span, message: impl Into<String>) -> Self {
+        Diagnostic {
+            span: Some(span),
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn or_span(self, span: Option<Span>) -> Self {
+        Diagnostic {
+            span: self.span.or(span),
+            ..self
+        }
+    }
+
+    /// Show the line of `src` containing the error, with the offending range
+    /// underlined.
+    ///
+    /// ```text
+    /// 1 | 4 == == 5
+    ///   |      ^^ expected expression, got `==`
+    /// ```
+    pub fn render(&self, src: &str) -> String {
+        let span = self.span.unwrap_or(Span {
+            start: 0,
+            end: src.len(),
+        });
+        let start = floor_char_boundary(src, span.start);
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let end = floor_char_boundary(src, span.end.clamp(start, line_end));
+        let line_no = src[..line_start].matches('\n').count() + 1;
+
+        let gutter = line_no.to_string();
+        let pad = src[line_start..start].chars().count();
+        let width = src[start..end].chars().count().max(1);
+        format!(
+            "{} | {}\n{} | {}{} {}",
+            gutter,
+            &src[line_start..line_end],
+            " ".repeat(gutter.len()),
+            " ".repeat(pad),
+            "^".repeat(width),
+            self.message
+        )
+    }
+}
+
+fn floor_char_boundary(src: &str, mut i: usize) -> usize {
+    i = i.min(src.len());
+    while !src.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Diagnostic {
+            span: None,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some(Span { start, end }) => write!(f, "{}..{}: {}", start, end, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Render all the errors, one after the other.
+pub fn render_all(src: &str, diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.render(src))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let d = Diagnostic::new(Span { start: 5, end: 7 }, "expected expression");
+        assert_eq!(
+            d.render("4 == == 5"),
+            "1 | 4 == == 5\n  |      ^^ expected expression"
+        );
+
+        let src = "{\n  let x = é +\n  return x\n}";
+        let at = src.find('+').unwrap();
+        let d = Diagnostic::new(
+            Span {
+                start: at,
+                end: at + 1,
+            },
+            "oops",
+        );
+        assert_eq!(d.render(src), "2 |   let x = é +\n  |             ^ oops");
+
+        let d = Diagnostic::new(
+            Span {
+                start: src.len(),
+                end: src.len(),
+            },
+            "eof",
+        );
+        assert_eq!(d.render(src), "4 | }\n  |  ^ eof");
+
+        let d = Diagnostic::from("Stat Foo not found".to_owned());
+        assert_eq!(
+            d.render("L\"Foo\""),
+            "1 | L\"Foo\"\n  | ^^^^^^ Stat Foo not found"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use smallvec::SmallVec;
+
+use crate::types::{CardTable, Pairing};
+
+/// Indices of Cards of a deck, sorted and without duplicates
+pub(super) type CardSet = SmallVec<[usize; 4]>;
+
+/// The edges of a Pairing by the Card they leave from, with their info. The
+/// edges of a symmetric Pairing go both ways. Like in an `ActiveDeck`, the
+/// edges from or to a disabled Card are left out.
+pub(super) struct Adjacency(Vec<Vec<(usize, Option<String>)>>);
+
+impl Adjacency {
+    pub(super) fn new(table: &CardTable, pairing: &Pairing) -> Self {
+        let n = table.cards.len();
+        let mut edges = vec![vec![]; n];
+        let enabled = |i: usize| i < n && !table.cards[i].is_disabled;
+        for edge in pairing.data.iter() {
+            let (li, ri) = (edge.left as usize, edge.right as usize);
+            if !enabled(li) || !enabled(ri) {
+                continue;
+            }
+            edges[li].push((ri, edge.info.clone()));
+            if pairing.is_symmetric && li != ri {
+                edges[ri].push((li, edge.info.clone()));
+            }
+        }
+        Self(edges)
+    }
+
+    fn edges_from(&self, card: usize) -> &[(usize, Option<String>)] {
+        self.0.get(card).map_or(&[], Vec::as_slice)
+    }
+
+    /// The Cards at the end of an edge from any of the `cards`, which may
+    /// include some of the `cards` themselves
+    pub(super) fn neighbors(&self, cards: &[usize]) -> CardSet {
+        let mut res: CardSet = cards
+            .iter()
+            .flat_map(|i| self.edges_from(*i))
+            .map(|(j, _)| *j)
+            .collect();
+        res.sort_unstable();
+        res.dedup();
+        res
+    }
+
+    /// The info of the first edge from one of the Cards `from` to one of the
+    /// Cards `to`, None if there is no such edge
+    pub(super) fn edge(&self, from: &[usize], to: &[usize]) -> Option<Option<&str>> {
+        from.iter()
+            .flat_map(|i| self.edges_from(*i))
+            .find(|(j, _)| to.binary_search(j).is_ok())
+            .map(|(_, info)| info.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Adjacency;
+    use crate::{
+        tinylang::test_support::card,
+        types::{Card, CardTable, Edge, Pairing},
+    };
+
+    #[test]
+    fn test_adjacency() {
+        let cards = (0..5)
+            .map(|i| Card {
+                is_disabled: i == 4,
+                ..card(format!("Card {}", i))
+            })
+            .collect();
+        let table = CardTable {
+            cards,
+            ..Default::default()
+        };
+        let edges = vec![
+            Edge::new(0, 1, Some("a".into())),
+            Edge::new(1, 2, None),
+            Edge::new(2, 4, Some("disabled".into())),
+            Edge::new(3, 9, Some("out of range".into())),
+        ];
+        let directed = Adjacency::new(
+            &table,
+            &Pairing {
+                label: "Directed".into(),
+                is_symmetric: false,
+                data: edges.clone(),
+            },
+        );
+        assert_eq!(directed.neighbors(&[0]).as_slice(), &[1]);
+        assert_eq!(directed.neighbors(&[1]).as_slice(), &[2]);
+        assert_eq!(directed.neighbors(&[0, 1]).as_slice(), &[1, 2]);
+        assert!(directed.neighbors(&[2, 3, 4]).is_empty());
+        assert_eq!(directed.edge(&[0], &[1]), Some(Some("a")));
+        assert_eq!(directed.edge(&[0, 1], &[2, 3]), Some(None));
+        assert_eq!(directed.edge(&[1], &[0]), None);
+
+        let symmetric = Adjacency::new(
+            &table,
+            &Pairing {
+                label: "Symmetric".into(),
+                is_symmetric: true,
+                data: edges,
+            },
+        );
+        assert_eq!(symmetric.neighbors(&[1]).as_slice(), &[0, 2]);
+        assert_eq!(symmetric.neighbors(&[0, 2]).as_slice(), &[1]);
+        assert_eq!(symmetric.edge(&[1], &[0]), Some(Some("a")));
+    }
+}
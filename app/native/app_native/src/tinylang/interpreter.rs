@@ -1,20 +1,30 @@
-use std::{borrow::Cow, cmp::Ordering, f64::consts::PI};
+use std::{borrow::Cow, cmp::Ordering, f64::consts::PI, rc::Rc, sync::Arc};
 
 extern crate derive_more;
+use chrono::{NaiveDate, NaiveTime};
 use derive_more::{Display, From};
+use regex::Regex;
 use rustler::NifUnitEnum;
-use smallvec::SmallVec;
+use serde::{Deserialize, Serialize};
+use smallvec::{smallvec, SmallVec};
 
 use crate::types::{Card, CardTable, EdgeSide, NaiveDateTimeExt, StatArray};
 
-use super::parser::{BinOp, Expression, UnOp};
+use super::{
+    aggregate::Population,
+    diagnostic::Diagnostic,
+    graph::{Adjacency, CardSet},
+    parser::{BinOp, BoxedExpression, Expression, Statement, UnOp},
+};
 
-#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, NifUnitEnum)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, NifUnitEnum)]
 pub enum ExprType {
     Bool,
     Number,
     LatLng,
     Date,
+    /// A length of time, in days
+    Duration,
     String,
     #[allow(dead_code)]
     IntArray,
@@ -28,6 +38,8 @@ pub enum OwnedExprValue {
     Number(f64),
     LatLng((f64, f64)),
     Date(NaiveDateTimeExt),
+    #[from(ignore)]
+    Duration(f64),
     String(String),
     IntArray(Vec<i64>),
     StringArray(SmallVec<[String; 2]>),
@@ -37,7 +49,8 @@ trait ColumnGet<'a, T> {
     fn get(&'a self, index: usize) -> Option<&'a T>;
 }
 
-pub struct DirectColumn<'a, T>(&'a [Option<T>]);
+/// The values of a stat column, and its index in `CardTable::stat_defs`
+pub struct DirectColumn<'a, T>(&'a [Option<T>], pub(super) usize);
 
 impl<'a, T> ColumnGet<'a, T> for DirectColumn<'a, T> {
     fn get(&'a self, index: usize) -> Option<&'a T> {
@@ -69,9 +82,11 @@ impl<'a> ColumnGet<'a, String> for StringColumn<'a> {
 }
 
 #[derive(Debug, Clone, Copy)]
-struct EvalContext {
+struct EvalContext<'c> {
     left_idx: usize,
     right_idx: usize,
+    /// Values of the let-bindings evaluated so far, indexed by slot
+    locals: &'c [Option<OwnedExprValue>],
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -81,11 +96,18 @@ pub enum PartialContext {
 }
 
 trait Evaluate<'a, T> {
-    fn evaluate(&'a self, ctx: EvalContext) -> Option<T>;
+    fn evaluate(&'a self, ctx: EvalContext<'_>) -> Option<T>;
     fn has_vars(&'a self, ctx: &PartialContext) -> bool;
 }
 
-enum IBool<'a> {
+pub(super) enum IBool<'a> {
+    Bool {
+        value: bool,
+    },
+    BoolVariable {
+        side: EdgeSide,
+        values: DirectColumn<'a, bool>,
+    },
     NotNilBool {
         child: Box<IBool<'a>>,
     },
@@ -98,6 +120,9 @@ enum IBool<'a> {
     NotNilDate {
         child: Box<IDate<'a>>,
     },
+    NotNilDuration {
+        child: Box<IDuration<'a>>,
+    },
     NotNilString {
         child: Box<IString<'a>>,
     },
@@ -124,11 +149,26 @@ enum IBool<'a> {
         rhs: Box<IDate<'a>>,
         invert: bool,
     },
+    EqDuration {
+        lhs: Box<IDuration<'a>>,
+        rhs: Box<IDuration<'a>>,
+        invert: bool,
+    },
     EqString {
         lhs: Box<IString<'a>>,
         rhs: Box<IString<'a>>,
         invert: bool,
     },
+    Matches {
+        lhs: Box<IString<'a>>,
+        regex: Regex,
+    },
+    /// An edge of the Pairing from one of the Cards `lhs` to one of `rhs`
+    HasEdge {
+        adjacency: Arc<Adjacency>,
+        lhs: ICards,
+        rhs: ICards,
+    },
     CmpNumber {
         lhs: Box<INumber<'a>>,
         rhs: Box<INumber<'a>>,
@@ -141,6 +181,12 @@ enum IBool<'a> {
         ordering: Ordering,
         invert: bool,
     },
+    CmpDuration {
+        lhs: Box<IDuration<'a>>,
+        rhs: Box<IDuration<'a>>,
+        ordering: Ordering,
+        invert: bool,
+    },
     And {
         lhs: Box<IBool<'a>>,
         rhs: Box<IBool<'a>>,
@@ -149,9 +195,13 @@ enum IBool<'a> {
         lhs: Box<IBool<'a>>,
         rhs: Box<IBool<'a>>,
     },
+    Local {
+        slot: usize,
+        def: Rc<IBool<'a>>,
+    },
 }
 
-enum INumber<'a> {
+pub(super) enum INumber<'a> {
     Number {
         value: f64,
     },
@@ -159,6 +209,13 @@ enum INumber<'a> {
         side: EdgeSide,
         values: DirectColumn<'a, f64>,
     },
+    /// A statistic of the Card's value in the column relative to the deck,
+    /// like its rank, computed for every Card at once
+    Aggregate {
+        side: EdgeSide,
+        column: DirectColumn<'a, f64>,
+        values: Arc<[Option<f64>]>,
+    },
     Neg {
         child: Box<INumber<'a>>,
     },
@@ -190,16 +247,46 @@ enum INumber<'a> {
         lhs: Box<ILatLng<'a>>,
         rhs: Box<ILatLng<'a>>,
     },
+    Bearing {
+        lhs: Box<ILatLng<'a>>,
+        rhs: Box<ILatLng<'a>>,
+    },
+    /// How many times `rhs` fits in `lhs`
+    DivDuration {
+        lhs: Box<IDuration<'a>>,
+        rhs: Box<IDuration<'a>>,
+    },
+    ParseString {
+        child: Box<IString<'a>>,
+    },
+    Count {
+        child: ICards,
+    },
+    Local {
+        slot: usize,
+        def: Rc<INumber<'a>>,
+    },
 }
 
-enum ILatLng<'a> {
+pub(super) enum ILatLng<'a> {
+    LatLng {
+        value: (f64, f64),
+    },
     LatLngVariable {
         side: EdgeSide,
         values: DirectColumn<'a, (f64, f64)>,
     },
+    Midpoint {
+        lhs: Box<ILatLng<'a>>,
+        rhs: Box<ILatLng<'a>>,
+    },
+    Local {
+        slot: usize,
+        def: Rc<ILatLng<'a>>,
+    },
 }
 
-enum IDate<'a> {
+pub(super) enum IDate<'a> {
     Date {
         value: NaiveDateTimeExt,
     },
@@ -207,18 +294,228 @@ enum IDate<'a> {
         side: EdgeSide,
         values: DirectColumn<'a, NaiveDateTimeExt>,
     },
+    ParseString {
+        child: Box<IString<'a>>,
+    },
+    /// `Date - Duration` adds the negated Duration
+    AddDuration {
+        lhs: Box<IDate<'a>>,
+        rhs: Box<IDuration<'a>>,
+    },
+    Local {
+        slot: usize,
+        def: Rc<IDate<'a>>,
+    },
+}
+
+/// A length of time in days, which may be negative
+pub(super) enum IDuration<'a> {
+    Duration {
+        value: f64,
+    },
+    /// `days(x)`, `years(x)`
+    FromNumber {
+        child: Box<INumber<'a>>,
+        days_per_unit: f64,
+    },
+    Neg {
+        child: Box<IDuration<'a>>,
+    },
+    Add {
+        lhs: Box<IDuration<'a>>,
+        rhs: Box<IDuration<'a>>,
+    },
+    /// `Duration * Number`, and `Duration / Number` by the inverse
+    Scale {
+        lhs: Box<IDuration<'a>>,
+        rhs: Box<INumber<'a>>,
+    },
+    Local {
+        slot: usize,
+        def: Rc<IDuration<'a>>,
+    },
 }
 
-enum IString<'a> {
+pub(super) enum IString<'a> {
+    String {
+        value: String,
+    },
     StringVariable {
         side: EdgeSide,
         values: StringColumn<'a>,
     },
+    FormatNumber {
+        child: Box<INumber<'a>>,
+    },
+    FormatDate {
+        child: Box<IDate<'a>>,
+    },
+    /// The first group captured by the pattern, or the whole match if it has
+    /// no groups
+    Capture {
+        child: Box<IString<'a>>,
+        regex: Regex,
+    },
+    /// The info of the edge `IBool::HasEdge` looks for
+    EdgeInfo {
+        adjacency: Arc<Adjacency>,
+        lhs: ICards,
+        rhs: ICards,
+    },
+    Local {
+        slot: usize,
+        def: Rc<IString<'a>>,
+    },
+}
+
+/// Cards of a deck, which are only passed to the functions over Pairings
+pub(super) enum ICards {
+    Card {
+        side: EdgeSide,
+    },
+    Neighbors {
+        adjacency: Arc<Adjacency>,
+        child: Box<ICards>,
+    },
+}
+
+impl ICards {
+    /// The side of the deck the Cards belong to
+    fn side(&self) -> EdgeSide {
+        match self {
+            ICards::Card { side } => *side,
+            ICards::Neighbors { child, .. } => child.side(),
+        }
+    }
+
+    fn evaluate(&self, ctx: EvalContext<'_>) -> CardSet {
+        match self {
+            ICards::Card { side } => smallvec![left_or_right(side, ctx.left_idx, ctx.right_idx)],
+            ICards::Neighbors { adjacency, child } => adjacency.neighbors(&child.evaluate(ctx)),
+        }
+    }
+}
+
+/// Days from `rhs` to `lhs`
+pub(super) fn days_between(lhs: NaiveDateTimeExt, rhs: NaiveDateTimeExt) -> f64 {
+    let ms = lhs.signed_duration_since(*rhs).num_milliseconds() as f64;
+    ms / 1000.0 / 60.0 / 60.0 / 24.0
+}
+
+/// Distance in kilometers between two (lat, lng) points on the WGS84
+/// ellipsoid
+pub fn distance_km(lhs: (f64, f64), rhs: (f64, f64)) -> f64 {
+    const FLATTENING: f64 = 1.0 / 298.257223563;
+    const RADIUS_KM: f64 = 6378.137;
+    let (mut lat1, mut lon1) = lhs;
+    let (mut lat2, mut lon2) = rhs;
+    lat1 *= PI / 180.0;
+    lon1 *= PI / 180.0;
+    lat2 *= PI / 180.0;
+    lon2 *= PI / 180.0;
+
+    #[inline]
+    fn haversin(x: f64) -> f64 {
+        let res = f64::sin(x / 2.0);
+        res * res
+    }
+    #[inline]
+    fn sin2(x: f64) -> f64 {
+        let res = f64::sin(x);
+        res * res
+    }
+    #[inline]
+    fn cos2(x: f64) -> f64 {
+        let res = f64::cos(x);
+        res * res
+    }
+
+    // lambert's formula
+    let b1 = f64::atan((1.0 - FLATTENING) * lat1.tan());
+    let b2 = f64::atan((1.0 - FLATTENING) * lat2.tan());
+    let dlambda = f64::abs(lon1 - lon2);
+    let dphi = f64::abs(b1 - b2);
+    let central2 =
+        haversin(dphi) + haversin(dlambda) * (1.0 - haversin(dphi) - haversin(lat1 + lat2));
+    let halfcentral = central2.sqrt().asin();
+    let central = 2.0 * halfcentral;
+    let p = 0.5 * (b1 + b2);
+    let q = 0.5 * (b2 - b1);
+    let x = (central - f64::sin(central)) * sin2(p) * cos2(q) / cos2(halfcentral);
+    let y = (central + f64::sin(central)) * sin2(q) * cos2(p) / sin2(halfcentral);
+    RADIUS_KM * (central - 0.5 * FLATTENING * (x + y))
+}
+
+/// Initial bearing in degrees, clockwise from north, to follow the great
+/// circle from `lhs` to `rhs`
+pub(super) fn bearing_deg(lhs: (f64, f64), rhs: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (lhs.0.to_radians(), lhs.1.to_radians());
+    let (lat2, lon2) = (rhs.0.to_radians(), rhs.1.to_radians());
+    let dlon = lon2 - lon1;
+    let y = dlon.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+    y.atan2(x).to_degrees().rem_euclid(360.0)
+}
+
+/// Halfway point along the great circle between two (lat, lng) points
+pub(super) fn midpoint(lhs: (f64, f64), rhs: (f64, f64)) -> (f64, f64) {
+    let (lat1, lon1) = (lhs.0.to_radians(), lhs.1.to_radians());
+    let (lat2, lon2) = (rhs.0.to_radians(), rhs.1.to_radians());
+    let dlon = lon2 - lon1;
+    let bx = lat2.cos() * dlon.cos();
+    let by = lat2.cos() * dlon.sin();
+    let lat = f64::atan2(
+        lat1.sin() + lat2.sin(),
+        ((lat1.cos() + bx).powi(2) + by * by).sqrt(),
+    );
+    let lon = lon1 + f64::atan2(by, lat1.cos() + bx);
+    // keep the longitude in [-180, 180)
+    let lon = (lon.to_degrees() + 180.0).rem_euclid(360.0) - 180.0;
+    (lat.to_degrees(), lon)
+}
+
+/// Calendar years are counted as an average Gregorian year
+pub(super) const DAYS_PER_YEAR: f64 = 365.2425;
+
+/// The date some days later, or None if it can't be represented
+pub(super) fn add_days(date: NaiveDateTimeExt, days: f64) -> Option<NaiveDateTimeExt> {
+    let ms = (days * 24.0 * 60.0 * 60.0 * 1000.0).round();
+    // longer than the range of dates, but short enough for chrono not to
+    // panic
+    if ms.is_nan() || ms.abs() >= 1e17 {
+        return None;
+    }
+    date.checked_add_signed(chrono::Duration::milliseconds(ms as i64))
+        .map(Into::into)
+}
+
+pub(super) fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse().ok()
+}
+
+pub(super) fn parse_date(s: &str) -> Option<NaiveDateTimeExt> {
+    let date = NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok()?;
+    Some(date.and_time(NaiveTime::MIN).into())
+}
+
+pub(super) fn format_date(date: NaiveDateTimeExt) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+/// What `capture` returns for the string, see `IString::Capture`
+pub(super) fn capture<'s>(regex: &Regex, s: &'s str) -> Option<&'s str> {
+    let captures = regex.captures(s)?;
+    let group = if captures.len() > 1 { 1 } else { 0 };
+    captures.get(group).map(|m| m.as_str())
 }
 
 impl Evaluate<'_, bool> for IBool<'_> {
-    fn evaluate(&self, ctx: EvalContext) -> Option<bool> {
+    fn evaluate(&self, ctx: EvalContext<'_>) -> Option<bool> {
         match self {
+            IBool::Bool { value } => Some(*value),
+            IBool::BoolVariable { side, values } => values
+                .get(left_or_right(side, ctx.left_idx, ctx.right_idx))
+                .copied(),
             IBool::NotNilBool { child } => Some(
                 child.has_vars(&PartialContext::Left(ctx.left_idx))
                     && child.has_vars(&PartialContext::Right(ctx.right_idx)),
@@ -235,6 +532,10 @@ impl Evaluate<'_, bool> for IBool<'_> {
                 child.has_vars(&PartialContext::Left(ctx.left_idx))
                     && child.has_vars(&PartialContext::Right(ctx.right_idx)),
             ),
+            IBool::NotNilDuration { child } => Some(
+                child.has_vars(&PartialContext::Left(ctx.left_idx))
+                    && child.has_vars(&PartialContext::Right(ctx.right_idx)),
+            ),
             IBool::NotNilString { child } => Some(
                 child.has_vars(&PartialContext::Left(ctx.left_idx))
                     && child.has_vars(&PartialContext::Right(ctx.right_idx)),
@@ -252,9 +553,22 @@ impl Evaluate<'_, bool> for IBool<'_> {
             IBool::EqDate { lhs, rhs, invert } => {
                 Some(*invert != (lhs.evaluate(ctx)? == rhs.evaluate(ctx)?))
             }
+            IBool::EqDuration { lhs, rhs, invert } => {
+                Some(*invert != (lhs.evaluate(ctx)? == rhs.evaluate(ctx)?))
+            }
             IBool::EqString { lhs, rhs, invert } => {
                 Some(*invert != (lhs.evaluate(ctx)? == rhs.evaluate(ctx)?))
             }
+            IBool::Matches { lhs, regex } => Some(regex.is_match(&lhs.evaluate(ctx)?)),
+            IBool::HasEdge {
+                adjacency,
+                lhs,
+                rhs,
+            } => Some(
+                adjacency
+                    .edge(&lhs.evaluate(ctx), &rhs.evaluate(ctx))
+                    .is_some(),
+            ),
             IBool::CmpNumber {
                 lhs,
                 rhs,
@@ -275,47 +589,74 @@ impl Evaluate<'_, bool> for IBool<'_> {
                 let rv = rhs.evaluate(ctx)?;
                 Some(*invert != (lv.cmp(&rv) == *ordering))
             }
+            IBool::CmpDuration {
+                lhs,
+                rhs,
+                ordering,
+                invert,
+            } => {
+                let lv = lhs.evaluate(ctx)?;
+                let rv = rhs.evaluate(ctx)?;
+                Some(*invert != (lv.total_cmp(&rv) == *ordering))
+            }
             IBool::And { lhs, rhs } => Some(lhs.evaluate(ctx)? && rhs.evaluate(ctx)?),
             IBool::Or { lhs, rhs } => Some(lhs.evaluate(ctx)? || rhs.evaluate(ctx)?),
+            IBool::Local { slot, def: _ } => match ctx.locals.get(*slot) {
+                Some(Some(OwnedExprValue::Bool(v))) => Some(*v),
+                _ => None,
+            },
         }
     }
 
     fn has_vars(&self, ctx: &PartialContext) -> bool {
         match self {
+            IBool::Bool { value: _ } => true,
+            IBool::BoolVariable { side, values } => match (ctx, side) {
+                (PartialContext::Left(i), EdgeSide::Left) => values.get(*i).is_some(),
+                (PartialContext::Right(i), EdgeSide::Right) => values.get(*i).is_some(),
+                _ => true,
+            },
             IBool::NotNilBool { child: _ } => true,
             IBool::NotNilNumber { child: _ } => true,
             IBool::NotNilLatLng { child: _ } => true,
             IBool::NotNilDate { child: _ } => true,
+            IBool::NotNilDuration { child: _ } => true,
             IBool::NotNilString { child: _ } => true,
             IBool::Not { child } => child.has_vars(ctx),
             IBool::EqBool { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::EqNumber { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::EqLatLng { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::EqDate { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IBool::EqDuration { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::EqString { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IBool::Matches { lhs, regex: _ } => lhs.has_vars(ctx),
+            IBool::HasEdge { .. } => true,
             IBool::CmpNumber { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::CmpDate { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IBool::CmpDuration { lhs, rhs, .. } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::And { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             IBool::Or { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IBool::Local { slot: _, def } => def.has_vars(ctx),
         }
     }
 }
 
 impl Evaluate<'_, f64> for INumber<'_> {
-    fn evaluate(&self, ctx: EvalContext) -> Option<f64> {
+    fn evaluate(&self, ctx: EvalContext<'_>) -> Option<f64> {
         match self {
             INumber::Number { value } => Some(*value),
             INumber::NumberVariable { side, values } => values
                 .get(left_or_right(side, ctx.left_idx, ctx.right_idx))
                 .copied(),
+            INumber::Aggregate { side, values, .. } => values
+                .get(left_or_right(side, ctx.left_idx, ctx.right_idx))
+                .copied()
+                .flatten(),
             INumber::Neg { child } => child.evaluate(ctx).map(|x| -x),
             INumber::Add { lhs, rhs } => Some(lhs.evaluate(ctx)? + rhs.evaluate(ctx)?),
             INumber::SubNumber { lhs, rhs } => Some(lhs.evaluate(ctx)? - rhs.evaluate(ctx)?),
             INumber::SubDate { lhs, rhs } => {
-                let lv = lhs.evaluate(ctx)?;
-                let rv = rhs.evaluate(ctx)?;
-                let ms = lv.signed_duration_since(*rv).num_milliseconds() as f64;
-                Some(ms / 1000.0 / 60.0 / 60.0 / 24.0)
+                Some(days_between(lhs.evaluate(ctx)?, rhs.evaluate(ctx)?))
             }
             INumber::Mul { lhs, rhs } => Some(lhs.evaluate(ctx)? * rhs.evaluate(ctx)?),
             INumber::Div { lhs, rhs } => Some(lhs.evaluate(ctx)? / rhs.evaluate(ctx)?),
@@ -324,55 +665,29 @@ impl Evaluate<'_, f64> for INumber<'_> {
                 let rv = rhs.evaluate(ctx)?;
                 Some(lv.powf(rv))
             }
-            INumber::Dist { lhs, rhs } => {
-                const FLATTENING: f64 = 1.0 / 298.257223563;
-                const RADIUS_KM: f64 = 6378.137;
-                let (mut lat1, mut lon1) = lhs.evaluate(ctx)?;
-                let (mut lat2, mut lon2) = rhs.evaluate(ctx)?;
-                lat1 *= PI / 180.0;
-                lon1 *= PI / 180.0;
-                lat2 *= PI / 180.0;
-                lon2 *= PI / 180.0;
-
-                #[inline]
-                fn haversin(x: f64) -> f64 {
-                    let res = f64::sin(x / 2.0);
-                    res * res
-                }
-                #[inline]
-                fn sin2(x: f64) -> f64 {
-                    let res = f64::sin(x);
-                    res * res
-                }
-                #[inline]
-                fn cos2(x: f64) -> f64 {
-                    let res = f64::cos(x);
-                    res * res
-                }
-
-                // lambert's formula
-                let b1 = f64::atan((1.0 - FLATTENING) * lat1.tan());
-                let b2 = f64::atan((1.0 - FLATTENING) * lat2.tan());
-                let dlambda = f64::abs(lon1 - lon2);
-                let dphi = f64::abs(b1 - b2);
-                let central2 = haversin(dphi)
-                    + haversin(dlambda) * (1.0 - haversin(dphi) - haversin(lat1 + lat2));
-                let halfcentral = central2.sqrt().asin();
-                let central = 2.0 * halfcentral;
-                let p = 0.5 * (b1 + b2);
-                let q = 0.5 * (b2 - b1);
-                let x = (central - f64::sin(central)) * sin2(p) * cos2(q) / cos2(halfcentral);
-                let y = (central + f64::sin(central)) * sin2(q) * cos2(p) / sin2(halfcentral);
-                let dist_km = RADIUS_KM * (central - 0.5 * FLATTENING * (x + y));
-                Some(dist_km)
+            INumber::Dist { lhs, rhs } => Some(distance_km(lhs.evaluate(ctx)?, rhs.evaluate(ctx)?)),
+            INumber::Bearing { lhs, rhs } => {
+                Some(bearing_deg(lhs.evaluate(ctx)?, rhs.evaluate(ctx)?))
             }
+            INumber::DivDuration { lhs, rhs } => Some(lhs.evaluate(ctx)? / rhs.evaluate(ctx)?),
+            INumber::ParseString { child } => parse_number(&child.evaluate(ctx)?),
+            INumber::Count { child } => Some(child.evaluate(ctx).len() as f64),
+            INumber::Local { slot, def: _ } => match ctx.locals.get(*slot) {
+                Some(Some(OwnedExprValue::Number(v))) => Some(*v),
+                _ => None,
+            },
         }
     }
 
     fn has_vars(&self, ctx: &PartialContext) -> bool {
         match self {
             INumber::Number { value: _ } => true,
-            INumber::NumberVariable { side, values } => match (ctx, side) {
+            INumber::NumberVariable { side, values }
+            | INumber::Aggregate {
+                side,
+                column: values,
+                ..
+            } => match (ctx, side) {
                 (PartialContext::Left(i), EdgeSide::Left) => values.get(*i).is_some(),
                 (PartialContext::Right(i), EdgeSide::Right) => values.get(*i).is_some(),
                 _ => true,
@@ -385,37 +700,59 @@ impl Evaluate<'_, f64> for INumber<'_> {
             INumber::Div { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             INumber::Pow { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
             INumber::Dist { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            INumber::Bearing { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            INumber::DivDuration { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            INumber::ParseString { child } => child.has_vars(ctx),
+            INumber::Count { .. } => true,
+            INumber::Local { slot: _, def } => def.has_vars(ctx),
         }
     }
 }
 
 impl Evaluate<'_, (f64, f64)> for ILatLng<'_> {
-    fn evaluate(&self, ctx: EvalContext) -> Option<(f64, f64)> {
+    fn evaluate(&self, ctx: EvalContext<'_>) -> Option<(f64, f64)> {
         match self {
+            ILatLng::LatLng { value } => Some(*value),
             ILatLng::LatLngVariable { side, values } => values
                 .get(left_or_right(side, ctx.left_idx, ctx.right_idx))
                 .copied(),
+            ILatLng::Midpoint { lhs, rhs } => {
+                Some(midpoint(lhs.evaluate(ctx)?, rhs.evaluate(ctx)?))
+            }
+            ILatLng::Local { slot, def: _ } => match ctx.locals.get(*slot) {
+                Some(Some(OwnedExprValue::LatLng(v))) => Some(*v),
+                _ => None,
+            },
         }
     }
 
     fn has_vars(&self, ctx: &PartialContext) -> bool {
         match self {
+            ILatLng::LatLng { value: _ } => true,
             ILatLng::LatLngVariable { side, values } => match (ctx, side) {
                 (PartialContext::Left(i), EdgeSide::Left) => values.get(*i).is_some(),
                 (PartialContext::Right(i), EdgeSide::Right) => values.get(*i).is_some(),
                 _ => true,
             },
+            ILatLng::Midpoint { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            ILatLng::Local { slot: _, def } => def.has_vars(ctx),
         }
     }
 }
 
 impl Evaluate<'_, NaiveDateTimeExt> for IDate<'_> {
-    fn evaluate(&self, ctx: EvalContext) -> Option<NaiveDateTimeExt> {
+    fn evaluate(&self, ctx: EvalContext<'_>) -> Option<NaiveDateTimeExt> {
         match self {
             IDate::Date { value } => Some(*value),
             IDate::DateVariable { side, values } => values
                 .get(left_or_right(side, ctx.left_idx, ctx.right_idx))
                 .copied(),
+            IDate::ParseString { child } => parse_date(&child.evaluate(ctx)?),
+            IDate::AddDuration { lhs, rhs } => add_days(lhs.evaluate(ctx)?, rhs.evaluate(ctx)?),
+            IDate::Local { slot, def: _ } => match ctx.locals.get(*slot) {
+                Some(Some(OwnedExprValue::Date(v))) => Some(*v),
+                _ => None,
+            },
         }
     }
 
@@ -427,59 +764,731 @@ impl Evaluate<'_, NaiveDateTimeExt> for IDate<'_> {
                 (PartialContext::Right(i), EdgeSide::Right) => values.get(*i).is_some(),
                 _ => true,
             },
+            IDate::ParseString { child } => child.has_vars(ctx),
+            IDate::AddDuration { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IDate::Local { slot: _, def } => def.has_vars(ctx),
+        }
+    }
+}
+
+impl Evaluate<'_, f64> for IDuration<'_> {
+    fn evaluate(&self, ctx: EvalContext<'_>) -> Option<f64> {
+        match self {
+            IDuration::Duration { value } => Some(*value),
+            IDuration::FromNumber {
+                child,
+                days_per_unit,
+            } => child.evaluate(ctx).map(|x| x * days_per_unit),
+            IDuration::Neg { child } => child.evaluate(ctx).map(|x| -x),
+            IDuration::Add { lhs, rhs } => Some(lhs.evaluate(ctx)? + rhs.evaluate(ctx)?),
+            IDuration::Scale { lhs, rhs } => Some(lhs.evaluate(ctx)? * rhs.evaluate(ctx)?),
+            IDuration::Local { slot, def: _ } => match ctx.locals.get(*slot) {
+                Some(Some(OwnedExprValue::Duration(v))) => Some(*v),
+                _ => None,
+            },
+        }
+    }
+
+    fn has_vars(&self, ctx: &PartialContext) -> bool {
+        match self {
+            IDuration::Duration { value: _ } => true,
+            IDuration::FromNumber { child, .. } => child.has_vars(ctx),
+            IDuration::Neg { child } => child.has_vars(ctx),
+            IDuration::Add { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IDuration::Scale { lhs, rhs } => lhs.has_vars(ctx) && rhs.has_vars(ctx),
+            IDuration::Local { slot: _, def } => def.has_vars(ctx),
         }
     }
 }
 
 impl<'a> Evaluate<'a, Cow<'a, str>> for IString<'a> {
-    fn evaluate(&'a self, ctx: EvalContext) -> Option<Cow<'a, str>> {
+    fn evaluate(&'a self, ctx: EvalContext<'_>) -> Option<Cow<'a, str>> {
         match self {
+            IString::String { value } => Some(value.into()),
             IString::StringVariable { side, values } => values
                 .get(left_or_right(side, ctx.left_idx, ctx.right_idx))
                 .map(|x| x.into()),
+            IString::FormatNumber { child } => child.evaluate(ctx).map(|x| x.to_string().into()),
+            IString::FormatDate { child } => child.evaluate(ctx).map(|x| format_date(x).into()),
+            IString::Capture { child, regex } => {
+                capture(regex, &child.evaluate(ctx)?).map(|x| x.to_owned().into())
+            }
+            IString::EdgeInfo {
+                adjacency,
+                lhs,
+                rhs,
+            } => adjacency
+                .edge(&lhs.evaluate(ctx), &rhs.evaluate(ctx))
+                .flatten()
+                .map(Cow::Borrowed),
+            IString::Local { slot, def: _ } => match ctx.locals.get(*slot) {
+                Some(Some(OwnedExprValue::String(v))) => Some(v.clone().into()),
+                _ => None,
+            },
         }
     }
 
     fn has_vars(&self, ctx: &PartialContext) -> bool {
         match self {
+            IString::String { value: _ } => true,
             IString::StringVariable { side, values } => match (ctx, side) {
                 (PartialContext::Left(i), EdgeSide::Left) => values.get(*i).is_some(),
                 (PartialContext::Right(i), EdgeSide::Right) => values.get(*i).is_some(),
                 _ => true,
             },
+            IString::FormatNumber { child } => child.has_vars(ctx),
+            IString::FormatDate { child } => child.has_vars(ctx),
+            IString::Capture { child, regex: _ } => child.has_vars(ctx),
+            IString::EdgeInfo { .. } => true,
+            IString::Local { slot: _, def } => def.has_vars(ctx),
+        }
+    }
+}
+
+impl<'a> IBool<'a> {
+    /// The node doesn't depend on any variable
+    fn is_const(&self) -> bool {
+        match self {
+            IBool::Bool { value: _ } => true,
+            IBool::BoolVariable { .. } => false,
+            IBool::NotNilBool { child } => child.is_const(),
+            IBool::NotNilNumber { child } => child.is_const(),
+            IBool::NotNilLatLng { child } => child.is_const(),
+            IBool::NotNilDate { child } => child.is_const(),
+            IBool::NotNilDuration { child } => child.is_const(),
+            IBool::NotNilString { child } => child.is_const(),
+            IBool::Not { child } => child.is_const(),
+            IBool::EqBool { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::EqNumber { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::EqLatLng { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::EqDate { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::EqDuration { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::EqString { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::Matches { lhs, regex: _ } => lhs.is_const(),
+            IBool::HasEdge { .. } => false,
+            IBool::CmpNumber { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::CmpDate { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::CmpDuration { lhs, rhs, .. } => lhs.is_const() && rhs.is_const(),
+            IBool::And { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            IBool::Or { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            IBool::Local { .. } => false,
+        }
+    }
+
+    /// Short-circuit `and`/`or` with a constant operand, and drop double
+    /// negations. A missing left operand makes `and`/`or` None, so only a
+    /// constant left operand can decide the result: `x and false` and
+    /// `x or true` are kept.
+    fn simplify(self) -> Self {
+        match self {
+            IBool::And { lhs, rhs } => match (*lhs, *rhs) {
+                (IBool::Bool { value: false }, _) => IBool::Bool { value: false },
+                (IBool::Bool { value: true }, other) | (other, IBool::Bool { value: true }) => {
+                    other
+                }
+                (lhs, rhs) => IBool::And {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            },
+            IBool::Or { lhs, rhs } => match (*lhs, *rhs) {
+                (IBool::Bool { value: true }, _) => IBool::Bool { value: true },
+                (IBool::Bool { value: false }, other) | (other, IBool::Bool { value: false }) => {
+                    other
+                }
+                (lhs, rhs) => IBool::Or {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            },
+            IBool::Not { child } => match *child {
+                IBool::Not { child } => *child,
+                child => IBool::Not {
+                    child: Box::new(child),
+                },
+            },
+            other => other,
+        }
+    }
+}
+
+impl<'a> INumber<'a> {
+    /// The node doesn't depend on any variable
+    fn is_const(&self) -> bool {
+        match self {
+            INumber::Number { value: _ } => true,
+            INumber::NumberVariable { .. } => false,
+            INumber::Aggregate { .. } => false,
+            INumber::Neg { child } => child.is_const(),
+            INumber::Add { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::SubNumber { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::SubDate { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::Mul { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::Div { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::Pow { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::Dist { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::Bearing { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::DivDuration { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            INumber::ParseString { child } => child.is_const(),
+            INumber::Count { .. } => false,
+            INumber::Local { .. } => false,
+        }
+    }
+
+    /// Drop the operations that leave their operand unchanged: `x + 0`,
+    /// `x - 0`, `x * 1`, `x / 1`, `x ** 1` and `- -x`
+    fn simplify(self) -> Self {
+        use INumber::Number;
+        match self {
+            INumber::Add { lhs, rhs } => match (*lhs, *rhs) {
+                (Number { value }, other) | (other, Number { value }) if value == 0.0 => other,
+                (lhs, rhs) => INumber::Add {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            },
+            INumber::Mul { lhs, rhs } => match (*lhs, *rhs) {
+                (Number { value }, other) | (other, Number { value }) if value == 1.0 => other,
+                (lhs, rhs) => INumber::Mul {
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+            },
+            INumber::SubNumber { lhs, rhs } if matches!(*rhs, Number { value } if value == 0.0) => {
+                *lhs
+            }
+            INumber::Div { lhs, rhs } if matches!(*rhs, Number { value } if value == 1.0) => *lhs,
+            INumber::Pow { lhs, rhs } if matches!(*rhs, Number { value } if value == 1.0) => *lhs,
+            INumber::Neg { child } => match *child {
+                INumber::Neg { child } => *child,
+                child => INumber::Neg {
+                    child: Box::new(child),
+                },
+            },
+            other => other,
+        }
+    }
+}
+
+impl ILatLng<'_> {
+    /// The node doesn't depend on any variable
+    fn is_const(&self) -> bool {
+        match self {
+            ILatLng::LatLng { value: _ } => true,
+            ILatLng::LatLngVariable { .. } => false,
+            ILatLng::Midpoint { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            ILatLng::Local { .. } => false,
+        }
+    }
+}
+
+impl IDate<'_> {
+    /// The node doesn't depend on any variable
+    fn is_const(&self) -> bool {
+        match self {
+            IDate::Date { value: _ } => true,
+            IDate::DateVariable { .. } => false,
+            IDate::ParseString { child } => child.is_const(),
+            IDate::AddDuration { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            IDate::Local { .. } => false,
+        }
+    }
+}
+
+impl IDuration<'_> {
+    /// The node doesn't depend on any variable
+    fn is_const(&self) -> bool {
+        match self {
+            IDuration::Duration { value: _ } => true,
+            IDuration::FromNumber { child, .. } => child.is_const(),
+            IDuration::Neg { child } => child.is_const(),
+            IDuration::Add { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            IDuration::Scale { lhs, rhs } => lhs.is_const() && rhs.is_const(),
+            IDuration::Local { .. } => false,
+        }
+    }
+}
+
+impl IString<'_> {
+    /// The node doesn't depend on any variable
+    fn is_const(&self) -> bool {
+        match self {
+            IString::String { value: _ } => true,
+            IString::StringVariable { .. } => false,
+            IString::FormatNumber { child } => child.is_const(),
+            IString::FormatDate { child } => child.is_const(),
+            IString::Capture { child, regex: _ } => child.is_const(),
+            IString::EdgeInfo { .. } => false,
+            IString::Local { .. } => false,
         }
     }
 }
 
 #[derive(From)]
-enum IExpr<'a> {
+pub(super) enum IExpr<'a> {
     Bool(IBool<'a>),
     Number(INumber<'a>),
     LatLng(ILatLng<'a>),
     Date(IDate<'a>),
+    Duration(IDuration<'a>),
     String(IString<'a>),
 }
 
-#[derive(From)]
-pub struct IntermediateExpr<'a>(IExpr<'a>);
+/// The value of a let-binding, shared between the statement that evaluates
+/// it and the references to it.
+#[derive(Clone)]
+pub(super) enum ILocal<'a> {
+    Bool(Rc<IBool<'a>>),
+    Number(Rc<INumber<'a>>),
+    LatLng(Rc<ILatLng<'a>>),
+    Date(Rc<IDate<'a>>),
+    Duration(Rc<IDuration<'a>>),
+    String(Rc<IString<'a>>),
+}
+
+impl<'a> From<IExpr<'a>> for ILocal<'a> {
+    fn from(value: IExpr<'a>) -> Self {
+        match value {
+            IExpr::Bool(inner) => ILocal::Bool(Rc::new(inner)),
+            IExpr::Number(inner) => ILocal::Number(Rc::new(inner)),
+            IExpr::LatLng(inner) => ILocal::LatLng(Rc::new(inner)),
+            IExpr::Date(inner) => ILocal::Date(Rc::new(inner)),
+            IExpr::Duration(inner) => ILocal::Duration(Rc::new(inner)),
+            IExpr::String(inner) => ILocal::String(Rc::new(inner)),
+        }
+    }
+}
+
+impl<'a> ILocal<'a> {
+    fn reference(&self, slot: usize) -> IExpr<'a> {
+        match self {
+            ILocal::Bool(def) => IBool::Local {
+                slot,
+                def: def.clone(),
+            }
+            .into(),
+            ILocal::Number(def) => INumber::Local {
+                slot,
+                def: def.clone(),
+            }
+            .into(),
+            ILocal::LatLng(def) => ILatLng::Local {
+                slot,
+                def: def.clone(),
+            }
+            .into(),
+            ILocal::Date(def) => IDate::Local {
+                slot,
+                def: def.clone(),
+            }
+            .into(),
+            ILocal::Duration(def) => IDuration::Local {
+                slot,
+                def: def.clone(),
+            }
+            .into(),
+            ILocal::String(def) => IString::Local {
+                slot,
+                def: def.clone(),
+            }
+            .into(),
+        }
+    }
+
+    fn get_value(&self, ctx: EvalContext<'_>) -> Option<OwnedExprValue> {
+        match self {
+            ILocal::Bool(inner) => inner.evaluate(ctx).map(OwnedExprValue::Bool),
+            ILocal::Number(inner) => inner.evaluate(ctx).map(OwnedExprValue::Number),
+            ILocal::LatLng(inner) => inner.evaluate(ctx).map(OwnedExprValue::LatLng),
+            ILocal::Date(inner) => inner.evaluate(ctx).map(OwnedExprValue::Date),
+            ILocal::Duration(inner) => inner.evaluate(ctx).map(OwnedExprValue::Duration),
+            ILocal::String(inner) => inner
+                .evaluate(ctx)
+                .map(|x| OwnedExprValue::String(x.into_owned())),
+        }
+    }
+}
+
+pub(super) enum IStmt<'a> {
+    /// Evaluates the binding into the next slot
+    Let(ILocal<'a>),
+    /// Returns None from the block if the condition is true
+    Guard(IBool<'a>),
+}
+
+/// The names bound so far by the enclosing block, and the statements that
+/// will compute their values.
+#[derive(Default)]
+struct Scope<'a> {
+    bindings: Vec<(String, ILocal<'a>)>,
+    stmts: Vec<IStmt<'a>>,
+}
+
+impl<'a> Scope<'a> {
+    fn bind(&mut self, name: String, local: ILocal<'a>) {
+        self.bindings.push((name, local.clone()));
+        self.stmts.push(IStmt::Let(local));
+    }
+
+    fn lookup(&self, name: &str) -> Option<IExpr<'a>> {
+        // later bindings shadow earlier ones
+        self.bindings
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, (nm, _))| nm == name)
+            .map(|(slot, (_, local))| local.reference(slot))
+    }
+}
+
+fn coerce(value: IExpr<'_>, ty: ExprType) -> Result<IExpr<'_>, String> {
+    match (value, ty) {
+        (value, ty) if value.ty() == ty => Ok(value),
+        (IExpr::String(child), ExprType::Number) => Ok(INumber::ParseString {
+            child: Box::new(child),
+        }
+        .into()),
+        (IExpr::String(child), ExprType::Date) => Ok(IDate::ParseString {
+            child: Box::new(child),
+        }
+        .into()),
+        (IExpr::Number(child), ExprType::String) => Ok(IString::FormatNumber {
+            child: Box::new(child),
+        }
+        .into()),
+        (IExpr::Date(child), ExprType::String) => Ok(IString::FormatDate {
+            child: Box::new(child),
+        }
+        .into()),
+        (value, ty) => Err(format!("({}) can not be coerced to ({})", value.ty(), ty)),
+    }
+}
+
+fn neg_duration(child: IDuration<'_>) -> IDuration<'_> {
+    match child {
+        IDuration::Duration { value } => IDuration::Duration { value: -value },
+        child => IDuration::Neg {
+            child: Box::new(child),
+        },
+    }
+}
+
+fn inverse(child: INumber<'_>) -> INumber<'_> {
+    match child {
+        INumber::Number { value } => INumber::Number { value: 1.0 / value },
+        child => INumber::Div {
+            lhs: Box::new(INumber::Number { value: 1.0 }),
+            rhs: Box::new(child),
+        },
+    }
+}
+
+/// The built-in functions, which are called with `name(arg, ...)`
+const FUNCTIONS: [&str; 10] = [
+    "bearing",
+    "midpoint",
+    "days",
+    "years",
+    "capture",
+    "mean",
+    "median",
+    "rank",
+    "percentile",
+    "zscore",
+];
+
+/// Compile the pattern of `=~` or `capture`. It must be a literal, so that it
+/// is compiled once.
+fn pattern(op: &str, e: IExpr<'_>) -> Result<Regex, String> {
+    let IExpr::String(IString::String { value }) = e else {
+        return Err(format!("the pattern of `{}` must be a string literal", op));
+    };
+    Regex::new(&value).map_err(|e| {
+        // syntax errors end with the reason, after a copy of the pattern
+        let message = e.to_string();
+        let reason = message.lines().last().unwrap_or_default();
+        format!("invalid pattern: {}", reason.trim_start_matches("error: "))
+    })
+}
+
+/// The functions over Pairings, which take Cards rather than values, and how
+/// they are called
+const PAIRING_FUNCTIONS: [(&str, &str); 4] = [
+    ("neighbors", r#"neighbors("Couple", L)"#),
+    ("has_edge", r#"has_edge("Couple", L, R)"#),
+    ("edge_info", r#"edge_info("Couple", L, R)"#),
+    ("count", r#"count(neighbors("Couple", L))"#),
+];
+
+fn cards_only(what: &str) -> String {
+    format!(
+        "{} can only be passed to `neighbors`, `has_edge`, `edge_info` or `count`",
+        what
+    )
+}
+
+fn pairing_usage(name: &str) -> Diagnostic {
+    let (_, usage) = PAIRING_FUNCTIONS
+        .iter()
+        .find(|(nm, _)| *nm == name)
+        .unwrap_or(&PAIRING_FUNCTIONS[0]);
+    Diagnostic::from(format!("`{}` is called like `{}`", name, usage))
+}
+
+/// The edges of the Pairing of the deck whose label is the literal `label`
+fn adjacency(
+    name: &str,
+    label: &BoxedExpression,
+    table: &CardTable,
+) -> Result<Arc<Adjacency>, Diagnostic> {
+    let res = match &*label.0 {
+        Expression::String { value } => table
+            .pairings
+            .iter()
+            .find(|p| &p.label == value)
+            .map(|pairing| Arc::new(Adjacency::new(table, pairing)))
+            .ok_or_else(|| format!("Pairing {} not found", value)),
+        _ => Err(format!(
+            "the pairing of `{}` must be a string literal",
+            name
+        )),
+    };
+    res.map_err(|e| Diagnostic::from(e).or_span(label.span()))
+}
+
+/// Calls of the functions over Pairings that return a value. The Pairing is
+/// looked up in the deck of the first Cards.
+fn pairing_call<'a>(
+    name: &str,
+    args: &[BoxedExpression],
+    left: &CardTable,
+    right: &CardTable,
+) -> Result<IExpr<'a>, Diagnostic> {
+    match (name, args) {
+        ("has_edge" | "edge_info", [label, lhs, rhs]) => {
+            let lhs = lhs.optimize_cards(left, right)?;
+            let rhs = rhs.optimize_cards(left, right)?;
+            let adjacency = adjacency(name, label, left_or_right(&lhs.side(), left, right))?;
+            Ok(match name {
+                "has_edge" => IBool::HasEdge {
+                    adjacency,
+                    lhs,
+                    rhs,
+                }
+                .into(),
+                _ => IString::EdgeInfo {
+                    adjacency,
+                    lhs,
+                    rhs,
+                }
+                .into(),
+            })
+        }
+        ("count", [cards]) => Ok(INumber::Count {
+            child: cards.optimize_cards(left, right)?,
+        }
+        .into()),
+        ("neighbors", [_, _]) => Err(cards_only("the Cards `neighbors` returns").into()),
+        _ => Err(pairing_usage(name)),
+    }
+}
+
+fn call<'a>(
+    name: &str,
+    args: Vec<IExpr<'a>>,
+    left: &'a CardTable,
+    right: &'a CardTable,
+) -> Result<IExpr<'a>, String> {
+    if !FUNCTIONS.contains(&name) {
+        return Err(format!("unknown function `{}`", name));
+    }
+    let types: Vec<String> = args.iter().map(|arg| arg.ty().to_string()).collect();
+    let mut args = args.into_iter();
+    match (name, args.next(), args.next(), args.next()) {
+        ("bearing", Some(IExpr::LatLng(lhs)), Some(IExpr::LatLng(rhs)), None) => {
+            Ok(INumber::Bearing {
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            }
+            .into())
+        }
+        ("midpoint", Some(IExpr::LatLng(lhs)), Some(IExpr::LatLng(rhs)), None) => {
+            Ok(ILatLng::Midpoint {
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            }
+            .into())
+        }
+        ("days", Some(IExpr::Number(child)), None, None) => Ok(IDuration::FromNumber {
+            child: Box::new(child),
+            days_per_unit: 1.0,
+        }
+        .into()),
+        ("years", Some(IExpr::Number(child)), None, None) => Ok(IDuration::FromNumber {
+            child: Box::new(child),
+            days_per_unit: DAYS_PER_YEAR,
+        }
+        .into()),
+        (
+            "mean" | "median" | "rank" | "percentile" | "zscore",
+            Some(IExpr::Number(INumber::NumberVariable { side, values })),
+            None,
+            None,
+        ) => {
+            let cards = &left_or_right(&side, left, right).cards;
+            let population = Population::new(
+                cards
+                    .iter()
+                    .zip(values.0)
+                    .filter(|(card, _)| !card.is_disabled)
+                    .filter_map(|(_, value)| *value),
+            );
+            let per_card = |f: &dyn Fn(f64) -> Option<f64>| -> Arc<[Option<f64>]> {
+                values.0.iter().map(|value| value.and_then(f)).collect()
+            };
+            let derived = match name {
+                "mean" => {
+                    return Ok(INumber::Number {
+                        value: population.mean(),
+                    }
+                    .into())
+                }
+                "median" => {
+                    return Ok(INumber::Number {
+                        value: population.median(),
+                    }
+                    .into())
+                }
+                "rank" => per_card(&|x| population.rank(x)),
+                "percentile" => per_card(&|x| population.percentile(x)),
+                _ => per_card(&|x| population.zscore(x)),
+            };
+            Ok(INumber::Aggregate {
+                side,
+                column: values,
+                values: derived,
+            }
+            .into())
+        }
+        (
+            "mean" | "median" | "rank" | "percentile" | "zscore",
+            Some(IExpr::Number(_)),
+            None,
+            None,
+        ) => Err(format!(
+            "`{}` is computed over a column, like `{}(R\"Population\")`",
+            name, name
+        )),
+        ("capture", Some(IExpr::String(child)), Some(rhs @ IExpr::String(_)), None) => {
+            Ok(IString::Capture {
+                child: Box::new(child),
+                regex: pattern("capture", rhs)?,
+            }
+            .into())
+        }
+        _ => Err(format!(
+            "`{}` is not defined for ({})",
+            name,
+            types.join(", ")
+        )),
+    }
+}
+
+pub struct IntermediateExpr<'a> {
+    pub(super) stmts: Vec<IStmt<'a>>,
+    pub(super) root: IExpr<'a>,
+}
+
+impl<'a> From<IExpr<'a>> for IntermediateExpr<'a> {
+    fn from(root: IExpr<'a>) -> Self {
+        Self {
+            stmts: vec![],
+            root,
+        }
+    }
+}
 
 impl Expression {
     pub fn optimize<'a>(
         &self,
         left: &'a CardTable,
         right: &'a CardTable,
-    ) -> Result<IntermediateExpr<'a>, String> {
-        Ok(self.optimize_impl(left, right)?.into())
+    ) -> Result<IntermediateExpr<'a>, Diagnostic> {
+        let mut scope = Scope::default();
+        let root = match self {
+            Expression::Block { stmts, ret } => {
+                for stmt in stmts {
+                    match stmt {
+                        Statement::Let { name, ty, value } => {
+                            let mut ie = value.optimize_impl(left, right, &scope)?;
+                            if let Some(ty) = ty {
+                                ie = coerce(ie, *ty).map(IExpr::fold).map_err(|msg| {
+                                    Diagnostic::from(format!("let {}: {}", name, msg))
+                                        .or_span(value.span())
+                                })?;
+                            }
+                            scope.bind(name.clone(), ie.into());
+                        }
+                        Statement::Guard { cond } => {
+                            match cond.optimize_impl(left, right, &scope)? {
+                                IExpr::Bool(ie) => scope.stmts.push(IStmt::Guard(ie)),
+                                other => {
+                                    return Err(Diagnostic::from(format!(
+                                        "`if` condition must be (Bool), got ({})",
+                                        other.ty()
+                                    ))
+                                    .or_span(cond.span()))
+                                }
+                            }
+                        }
+                    }
+                }
+                ret.optimize_impl(left, right, &scope)?
+            }
+            _ => self.optimize_impl(left, right, &scope)?,
+        };
+        Ok(IntermediateExpr {
+            stmts: scope.stmts,
+            root,
+        })
     }
 
     fn optimize_impl<'a>(
         &self,
         left: &'a CardTable,
         right: &'a CardTable,
-    ) -> Result<IExpr<'a>, String> {
-        match self {
+        scope: &Scope<'a>,
+    ) -> Result<IExpr<'a>, Diagnostic> {
+        // Errors about this node are left without a span, the caller knows
+        // where it is in the source.
+        let res: Result<IExpr<'a>, String> = match self {
+            Expression::Local { name } => scope
+                .lookup(name)
+                .ok_or_else(|| format!("Name {} not found", name)),
+            Expression::Block { .. } => Err("a block must be the outermost expression".into()),
+            Expression::Card { side } => Err(cards_only(match side {
+                EdgeSide::Left => "the Card `L`",
+                EdgeSide::Right => "the Card `R`",
+            })),
             Expression::Number { value } => Ok(INumber::Number { value: *value }.into()),
             Expression::Date { value } => Ok(IDate::Date { value: *value }.into()),
+            Expression::LatLng { value } => Ok(ILatLng::LatLng { value: *value }.into()),
+            Expression::String { value } => Ok(IString::String {
+                value: value.clone(),
+            }
+            .into()),
+            Expression::Call { name, args }
+                if PAIRING_FUNCTIONS.iter().any(|(nm, _)| nm == name) =>
+            {
+                return pairing_call(name, args, left, right);
+            }
+            Expression::Call { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.optimize_impl(left, right, scope))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(name, args, left, right)
+            }
             Expression::Variable { side, key } => {
                 if key == "Card" {
                     let data: &CardTable = left_or_right(side, left, right);
@@ -490,38 +1499,43 @@ impl Expression {
                     return Ok(ie.into());
                 }
                 let mut iter = match side {
-                    EdgeSide::Left => left.stat_defs.iter(),
-                    EdgeSide::Right => right.stat_defs.iter(),
+                    EdgeSide::Left => left.stat_defs.iter().enumerate(),
+                    EdgeSide::Right => right.stat_defs.iter().enumerate(),
                 };
-                let col = iter
-                    .find(|sd| &sd.label == key)
+                let (column, col) = iter
+                    .find(|(_, sd)| &sd.label == key)
                     .ok_or_else(|| format!("Stat {} not found", key))?;
                 let ie = match &col.data {
                     StatArray::Number { unit: _, values } => (INumber::NumberVariable {
                         side: *side,
-                        values: DirectColumn(values.as_slice()),
+                        values: DirectColumn(values.as_slice(), column),
                     })
                     .into(),
                     StatArray::Date { values } => (IDate::DateVariable {
                         side: *side,
-                        values: DirectColumn(values.as_slice()),
+                        values: DirectColumn(values.as_slice(), column),
                     })
                     .into(),
                     StatArray::String { values } => (IString::StringVariable {
                         side: *side,
-                        values: DirectColumn(values.as_slice()).into(),
+                        values: DirectColumn(values.as_slice(), column).into(),
                     })
                     .into(),
                     StatArray::LatLng { values } => (ILatLng::LatLngVariable {
                         side: *side,
-                        values: DirectColumn(values.as_slice()),
+                        values: DirectColumn(values.as_slice(), column),
+                    })
+                    .into(),
+                    StatArray::Bool { values } => (IBool::BoolVariable {
+                        side: *side,
+                        values: DirectColumn(values.as_slice(), column),
                     })
                     .into(),
                 };
                 Ok(ie)
             }
             Expression::Unary { op, child } => {
-                let ce = child.0.optimize_impl(left, right)?;
+                let ce = child.optimize_impl(left, right, scope)?;
                 match op {
                     UnOp::Bool => match ce {
                         IExpr::Bool(child) => Ok(IBool::NotNilBool {
@@ -540,6 +1554,10 @@ impl Expression {
                             child: Box::new(child),
                         }
                         .into()),
+                        IExpr::Duration(child) => Ok(IBool::NotNilDuration {
+                            child: Box::new(child),
+                        }
+                        .into()),
                         IExpr::String(child) => Ok(IBool::NotNilString {
                             child: Box::new(child),
                         }
@@ -557,13 +1575,14 @@ impl Expression {
                             child: Box::new(child),
                         }
                         .into()),
+                        IExpr::Duration(child) => Ok(neg_duration(child).into()),
                         other => Err(format!("`-` is not defined for ({})", other.ty())),
                     },
                 }
             }
             Expression::Binary { op, lhs, rhs } => {
-                let lhs = lhs.0.optimize_impl(left, right)?;
-                let rhs = rhs.0.optimize_impl(left, right)?;
+                let lhs = lhs.optimize_impl(left, right, scope)?;
+                let rhs = rhs.optimize_impl(left, right, scope)?;
                 match op {
                     op @ (BinOp::Eq | BinOp::Neq) => {
                         let invert = matches!(op, BinOp::Neq);
@@ -592,6 +1611,14 @@ impl Expression {
                                 invert,
                             }
                             .into()),
+                            (IExpr::Duration(left), IExpr::Duration(right)) => {
+                                Ok(IBool::EqDuration {
+                                    lhs: Box::new(left),
+                                    rhs: Box::new(right),
+                                    invert,
+                                }
+                                .into())
+                            }
                             (IExpr::String(left), IExpr::String(right)) => Ok(IBool::EqString {
                                 lhs: Box::new(left),
                                 rhs: Box::new(right),
@@ -612,7 +1639,7 @@ impl Expression {
                             BinOp::Lte => (Ordering::Greater, true),
                             BinOp::Gt => (Ordering::Greater, false),
                             BinOp::Gte => (Ordering::Less, true),
-                            _ => panic!(),
+                            _ => unreachable!(),
                         };
                         match (lhs, rhs) {
                             (IExpr::Number(left), IExpr::Number(right)) => Ok(IBool::CmpNumber {
@@ -629,6 +1656,15 @@ impl Expression {
                                 invert,
                             }
                             .into()),
+                            (IExpr::Duration(left), IExpr::Duration(right)) => {
+                                Ok(IBool::CmpDuration {
+                                    lhs: Box::new(left),
+                                    rhs: Box::new(right),
+                                    ordering,
+                                    invert,
+                                }
+                                .into())
+                            }
                             (l, r) => Err(format!(
                                 "`{}` is not defined for ({}, {})",
                                 op,
@@ -659,6 +1695,19 @@ impl Expression {
                             rhs: Box::new(right),
                         }
                         .into()),
+                        (IExpr::Date(date), IExpr::Duration(duration))
+                        | (IExpr::Duration(duration), IExpr::Date(date)) => {
+                            Ok(IDate::AddDuration {
+                                lhs: Box::new(date),
+                                rhs: Box::new(duration),
+                            }
+                            .into())
+                        }
+                        (IExpr::Duration(left), IExpr::Duration(right)) => Ok(IDuration::Add {
+                            lhs: Box::new(left),
+                            rhs: Box::new(right),
+                        }
+                        .into()),
                         (l, r) => Err(format!("`+` is not defined for ({}, {})", l.ty(), r.ty())),
                     },
                     BinOp::Sub => match (lhs, rhs) {
@@ -672,6 +1721,16 @@ impl Expression {
                             rhs: Box::new(right),
                         }
                         .into()),
+                        (IExpr::Date(left), IExpr::Duration(right)) => Ok(IDate::AddDuration {
+                            lhs: Box::new(left),
+                            rhs: Box::new(neg_duration(right)),
+                        }
+                        .into()),
+                        (IExpr::Duration(left), IExpr::Duration(right)) => Ok(IDuration::Add {
+                            lhs: Box::new(left),
+                            rhs: Box::new(neg_duration(right)),
+                        }
+                        .into()),
                         (l, r) => Err(format!("`-` is not defined for ({}, {})", l.ty(), r.ty())),
                     },
                     BinOp::Mul => match (lhs, rhs) {
@@ -680,6 +1739,14 @@ impl Expression {
                             rhs: Box::new(right),
                         }
                         .into()),
+                        (IExpr::Duration(duration), IExpr::Number(factor))
+                        | (IExpr::Number(factor), IExpr::Duration(duration)) => {
+                            Ok(IDuration::Scale {
+                                lhs: Box::new(duration),
+                                rhs: Box::new(factor),
+                            }
+                            .into())
+                        }
                         (l, r) => Err(format!("`*` is not defined for ({}, {})", l.ty(), r.ty())),
                     },
                     BinOp::Div => match (lhs, rhs) {
@@ -688,6 +1755,18 @@ impl Expression {
                             rhs: Box::new(right),
                         }
                         .into()),
+                        (IExpr::Duration(left), IExpr::Number(right)) => Ok(IDuration::Scale {
+                            lhs: Box::new(left),
+                            rhs: Box::new(inverse(right)),
+                        }
+                        .into()),
+                        (IExpr::Duration(left), IExpr::Duration(right)) => {
+                            Ok(INumber::DivDuration {
+                                lhs: Box::new(left),
+                                rhs: Box::new(right),
+                            }
+                            .into())
+                        }
                         (l, r) => Err(format!("`/` is not defined for ({}, {})", l.ty(), r.ty())),
                     },
                     BinOp::Pow => match (lhs, rhs) {
@@ -706,9 +1785,53 @@ impl Expression {
                         .into()),
                         (l, r) => Err(format!("`+` is not defined for ({}, {})", l.ty(), r.ty())),
                     },
+                    BinOp::Match => match (lhs, rhs) {
+                        (IExpr::String(left), rhs @ IExpr::String(_)) => Ok(IBool::Matches {
+                            lhs: Box::new(left),
+                            regex: pattern("=~", rhs)?,
+                        }
+                        .into()),
+                        (l, r) => Err(format!("`=~` is not defined for ({}, {})", l.ty(), r.ty())),
+                    },
                 }
             }
-        }
+        };
+        res.map(IExpr::fold).map_err(Diagnostic::from)
+    }
+}
+
+impl BoxedExpression {
+    fn optimize_impl<'a>(
+        &self,
+        left: &'a CardTable,
+        right: &'a CardTable,
+        scope: &Scope<'a>,
+    ) -> Result<IExpr<'a>, Diagnostic> {
+        self.0
+            .optimize_impl(left, right, scope)
+            .map_err(|e| e.or_span(self.span()))
+    }
+
+    /// The argument of a function over Pairings that is a set of Cards
+    fn optimize_cards(&self, left: &CardTable, right: &CardTable) -> Result<ICards, Diagnostic> {
+        let res = match &*self.0 {
+            Expression::Card { side } => Ok(ICards::Card { side: *side }),
+            Expression::Call { name, args } if name == "neighbors" => match args.as_slice() {
+                [label, cards] => {
+                    let child = cards.optimize_cards(left, right)?;
+                    let table = left_or_right(&child.side(), left, right);
+                    Ok(ICards::Neighbors {
+                        adjacency: adjacency(name, label, table)?,
+                        child: Box::new(child),
+                    })
+                }
+                _ => Err(pairing_usage(name)),
+            },
+            _ => Err(Diagnostic::from(
+                "expected Cards, like `L` or `neighbors(\"Couple\", R)`".to_owned(),
+            )),
+        };
+        res.map_err(|e| e.or_span(self.span()))
     }
 }
 
@@ -721,12 +1844,79 @@ fn left_or_right<T>(side: &EdgeSide, left: T, right: T) -> T {
 }
 
 impl<'a> IExpr<'a> {
+    fn is_const(&self) -> bool {
+        match self {
+            IExpr::Bool(inner) => inner.is_const(),
+            IExpr::Number(inner) => inner.is_const(),
+            IExpr::LatLng(inner) => inner.is_const(),
+            IExpr::Date(inner) => inner.is_const(),
+            IExpr::Duration(inner) => inner.is_const(),
+            IExpr::String(inner) => inner.is_const(),
+        }
+    }
+
+    /// Replace a node that doesn't depend on any variable by its value, or
+    /// simplify it otherwise. Its children should already be folded.
+    fn fold(self) -> IExpr<'a> {
+        let literal = matches!(
+            self,
+            IExpr::Bool(IBool::Bool { .. })
+                | IExpr::Number(INumber::Number { .. })
+                | IExpr::LatLng(ILatLng::LatLng { .. })
+                | IExpr::Date(IDate::Date { .. })
+                | IExpr::Duration(IDuration::Duration { .. })
+                | IExpr::String(IString::String { .. })
+        );
+        if literal {
+            return self;
+        }
+        if !self.is_const() {
+            return match self {
+                IExpr::Bool(inner) => inner.simplify().into(),
+                IExpr::Number(inner) => inner.simplify().into(),
+                other => other,
+            };
+        }
+        let ctx = EvalContext {
+            left_idx: 0,
+            right_idx: 0,
+            locals: &[],
+        };
+        let value: Option<IExpr<'a>> = match &self {
+            IExpr::Bool(inner) => inner
+                .evaluate(ctx)
+                .map(|value| IBool::Bool { value }.into()),
+            IExpr::Number(inner) => inner
+                .evaluate(ctx)
+                .map(|value| INumber::Number { value }.into()),
+            IExpr::LatLng(inner) => inner
+                .evaluate(ctx)
+                .map(|value| ILatLng::LatLng { value }.into()),
+            IExpr::Date(inner) => inner
+                .evaluate(ctx)
+                .map(|value| IDate::Date { value }.into()),
+            IExpr::Duration(inner) => inner
+                .evaluate(ctx)
+                .map(|value| IDuration::Duration { value }.into()),
+            IExpr::String(inner) => inner.evaluate(ctx).map(|value| {
+                IString::String {
+                    value: value.into_owned(),
+                }
+                .into()
+            }),
+        };
+        // a constant that evaluates to None, like a bad date string, is left
+        // for evaluation to return None
+        value.unwrap_or(self)
+    }
+
     pub fn ty(&self) -> ExprType {
         match self {
             IExpr::Bool(_) => ExprType::Bool,
             IExpr::Number(_) => ExprType::Number,
             IExpr::LatLng(_) => ExprType::LatLng,
             IExpr::Date(_) => ExprType::Date,
+            IExpr::Duration(_) => ExprType::Duration,
             IExpr::String(_) => ExprType::String,
         }
     }
@@ -734,29 +1924,57 @@ impl<'a> IExpr<'a> {
 
 impl<'a> IntermediateExpr<'a> {
     pub fn get_type(&self) -> ExprType {
-        self.0.ty()
+        self.root.ty()
+    }
+
+    /// The value doesn't depend on the cards, the expression was folded into
+    /// a constant
+    pub fn is_const(&self) -> bool {
+        self.stmts.is_empty() && self.root.is_const()
     }
 
     pub fn has_vars(&self, ctx: &PartialContext) -> bool {
-        match &self.0 {
+        match &self.root {
             IExpr::Bool(inner) => inner.has_vars(ctx),
             IExpr::Number(inner) => inner.has_vars(ctx),
             IExpr::LatLng(inner) => inner.has_vars(ctx),
             IExpr::Date(inner) => inner.has_vars(ctx),
+            IExpr::Duration(inner) => inner.has_vars(ctx),
             IExpr::String(inner) => inner.has_vars(ctx),
         }
     }
 
     pub fn get_value(&self, left_idx: usize, right_idx: usize) -> Option<OwnedExprValue> {
+        let mut locals = Vec::with_capacity(self.stmts.len());
+        for stmt in self.stmts.iter() {
+            let ctx = EvalContext {
+                left_idx,
+                right_idx,
+                locals: &locals,
+            };
+            match stmt {
+                IStmt::Let(local) => {
+                    let value = local.get_value(ctx);
+                    locals.push(value);
+                }
+                IStmt::Guard(cond) => {
+                    if cond.evaluate(ctx) == Some(true) {
+                        return None;
+                    }
+                }
+            }
+        }
         let ctx = EvalContext {
             left_idx,
             right_idx,
+            locals: &locals,
         };
-        match &self.0 {
+        match &self.root {
             IExpr::Bool(inner) => inner.evaluate(ctx).map(OwnedExprValue::Bool),
             IExpr::Number(inner) => inner.evaluate(ctx).map(OwnedExprValue::Number),
             IExpr::LatLng(inner) => inner.evaluate(ctx).map(OwnedExprValue::LatLng),
             IExpr::Date(inner) => inner.evaluate(ctx).map(OwnedExprValue::Date),
+            IExpr::Duration(inner) => inner.evaluate(ctx).map(OwnedExprValue::Duration),
             IExpr::String(inner) => inner
                 .evaluate(ctx)
                 .map(|x| OwnedExprValue::String(x.into_owned())),
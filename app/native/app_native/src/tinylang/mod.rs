@@ -1,5 +1,17 @@
+mod aggregate;
+mod analysis;
+mod compiled;
+mod diagnostic;
+mod graph;
 mod interpreter;
 mod parser;
+mod printer;
+#[cfg(any(test, fuzzing, feature = "bench"))]
+pub mod test_support;
 
-pub use interpreter::{ExprType, IntermediateExpr, OwnedExprValue, PartialContext};
+pub use analysis::{analyze, Analysis, Reference};
+pub use compiled::{BoundProgram, Program};
+pub use diagnostic::{render_all, Diagnostic};
+pub use interpreter::{distance_km, ExprType, IntermediateExpr, OwnedExprValue, PartialContext};
 pub use parser::{expr, Expression};
+pub use printer::print;
@@ -6,11 +6,10 @@ use std::{fmt, num::ParseFloatError};
 use chrono::{NaiveDate, NaiveTime};
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag, take_till1, take_while, take_while1},
-    character::complete::{char, none_of, one_of, satisfy},
-    combinator::{all_consuming, fail, map, opt, recognize},
-    multi::many0,
-    sequence::{delimited, preceded, terminated, tuple},
+    bytes::complete::{escaped, is_not, tag, take_till1, take_while, take_while1},
+    character::complete::{anychar, char, satisfy},
+    combinator::{fail, map, opt, recognize},
+    sequence::{delimited, tuple},
     IResult,
 };
 use rustler::{Decoder, Encoder, Env, NifResult, NifTaggedEnum, NifUnitEnum, Term};
@@ -18,6 +17,11 @@ use serde::{Deserialize, Serialize};
 
 use crate::types::{EdgeSide, NaiveDateTimeExt};
 
+use super::{
+    diagnostic::{Diagnostic, Span},
+    interpreter::ExprType,
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, NifUnitEnum)]
 pub enum UnOp {
     Bool,
@@ -64,6 +68,7 @@ pub enum BinOp {
     Div,
     Pow,
     Dist,
+    Match,
 }
 
 impl TryFrom<&str> for BinOp {
@@ -85,6 +90,7 @@ impl TryFrom<&str> for BinOp {
             "/" => Ok(BinOp::Div),
             "**" => Ok(BinOp::Pow),
             "<->" => Ok(BinOp::Dist),
+            "=~" => Ok(BinOp::Match),
             v => Err(format!("not a unary op: {:?}", v)),
         }
     }
@@ -107,6 +113,7 @@ impl fmt::Display for BinOp {
             BinOp::Div => write!(f, "/"),
             BinOp::Pow => write!(f, "**"),
             BinOp::Dist => write!(f, "<->"),
+            BinOp::Match => write!(f, "=~"),
         }
     }
 }
@@ -120,10 +127,21 @@ pub enum Expression {
     Date {
         value: NaiveDateTimeExt,
     },
+    LatLng {
+        value: (f64, f64),
+    },
+    String {
+        value: String,
+    },
     Variable {
         side: EdgeSide,
         key: String,
     },
+    /// The Card itself, `L` or `R`, which is only passed to the functions
+    /// over Pairings
+    Card {
+        side: EdgeSide,
+    },
     Unary {
         op: UnOp,
         child: BoxedExpression,
@@ -133,14 +151,55 @@ pub enum Expression {
         lhs: BoxedExpression,
         rhs: BoxedExpression,
     },
+    Local {
+        name: String,
+    },
+    Call {
+        name: String,
+        args: Vec<BoxedExpression>,
+    },
+    Block {
+        stmts: Vec<Statement>,
+        ret: BoxedExpression,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, NifTaggedEnum)]
+#[serde(tag = "kind")]
+pub enum Statement {
+    /// Binds the value to a name for the rest of the block. With a type
+    /// annotation, the value is coerced to that type. If the coercion fails
+    /// the name is bound to None, and the rest of the block still runs.
+    Let {
+        name: String,
+        ty: Option<ExprType>,
+        value: BoxedExpression,
+    },
+    /// `if cond { return None }`
+    Guard { cond: BoxedExpression },
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct BoxedExpression(pub(crate) Box<Expression>);
+/// A subexpression, along with where it was found in the source if it was
+/// parsed. The span is only used to report errors, it is ignored when
+/// comparing or serializing expressions.
+#[derive(Debug, Clone)]
+pub struct BoxedExpression(pub(crate) Box<Expression>, pub(crate) Option<Span>);
+
+impl BoxedExpression {
+    pub fn span(&self) -> Option<Span> {
+        self.1
+    }
+}
+
+impl PartialEq for BoxedExpression {
+    fn eq(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
 
 impl From<Expression> for BoxedExpression {
     fn from(value: Expression) -> Self {
-        Self(Box::new(value))
+        Self(Box::new(value), None)
     }
 }
 
@@ -158,14 +217,14 @@ impl<'b> Deserialize<'b> for BoxedExpression {
     where
         D: serde::Deserializer<'b>,
     {
-        Deserialize::deserialize(deserializer).map(|e| BoxedExpression(Box::new(e)))
+        Deserialize::deserialize(deserializer).map(|e: Expression| e.into())
     }
 }
 
 impl<'b> Decoder<'b> for BoxedExpression {
     fn decode(term: Term<'b>) -> NifResult<Self> {
-        let e = term.decode()?;
-        Ok(Self(Box::new(e)))
+        let e: Expression = term.decode()?;
+        Ok(e.into())
     }
 }
 
@@ -180,9 +239,45 @@ impl fmt::Display for Expression {
         match self {
             Expression::Number { value } => write!(f, "{}", value),
             Expression::Date { value } => write!(f, "(date {})", **value),
+            Expression::LatLng { value } => write!(f, "(latlng {} {})", value.0, value.1),
+            Expression::String { value } => write!(f, "{:?}", value),
             Expression::Variable { side, key } => write!(f, "({:?} {})", side, key),
+            Expression::Card { side } => write!(f, "{:?}", side),
             Expression::Unary { op, child } => write!(f, "({} {})", op, child.0),
             Expression::Binary { op, lhs, rhs } => write!(f, "({} {} {})", op, lhs.0, rhs.0),
+            Expression::Local { name } => write!(f, "{}", name),
+            Expression::Call { name, args } => {
+                write!(f, "({}", name)?;
+                for arg in args {
+                    write!(f, " {}", arg.0)?;
+                }
+                write!(f, ")")
+            }
+            Expression::Block { stmts, ret } => {
+                write!(f, "(block")?;
+                for stmt in stmts {
+                    write!(f, " {}", stmt)?;
+                }
+                write!(f, " (return {}))", ret.0)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statement::Let {
+                name,
+                ty: Some(ty),
+                value,
+            } => write!(f, "(let {}: {} {})", name, ty, value.0),
+            Statement::Let {
+                name,
+                ty: None,
+                value,
+            } => write!(f, "(let {} {})", name, value.0),
+            Statement::Guard { cond } => write!(f, "(if {} (return None))", cond.0),
         }
     }
 }
@@ -191,13 +286,29 @@ impl fmt::Display for Expression {
 enum Token<'a> {
     Number(&'a str),
     Str(char, &'a str),
+    Text(&'a str),
+    Ident(&'a str),
     Op(&'a str),
     Error(&'a str),
     Eof,
 }
 
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(it) | Token::Ident(it) | Token::Op(it) | Token::Error(it) => {
+                write!(f, "`{}`", it)
+            }
+            Token::Str(k, it) => write!(f, "`{}\"{}\"`", k, it),
+            Token::Text(it) => write!(f, "`\"{}\"`", it),
+            Token::Eof => write!(f, "end of input"),
+        }
+    }
+}
+
 struct Lexer<'a> {
-    tokens: Vec<Token<'a>>,
+    tokens: Vec<(Token<'a>, Span)>,
+    eof: Span,
 }
 
 fn digits(inp: &str) -> IResult<&str, &str> {
@@ -214,7 +325,10 @@ fn number(inp: &str) -> IResult<&str, Token> {
 fn quoted(inp: &str) -> IResult<&str, &str> {
     delimited(
         char('"'),
-        escaped(opt(none_of(r#"\""#)), '\\', one_of(r#"\"rnt"#)),
+        map(
+            opt(escaped(is_not(r#"\""#), '\\', anychar)),
+            Option::unwrap_or_default,
+        ),
         char('"'),
     )(inp)
 }
@@ -226,21 +340,71 @@ fn str(inp: &str) -> IResult<&str, Token> {
     )(inp)
 }
 
+fn text(inp: &str) -> IResult<&str, Token> {
+    map(quoted, Token::Text)(inp)
+}
+
+/// The value of a string literal. `\"`, `\\`, `\n`, `\r` and `\t` are
+/// escapes, any other backslash is kept, so that patterns like `"\d+"` read
+/// as written.
+fn unescape(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => res.push('\n'),
+            Some('r') => res.push('\r'),
+            Some('t') => res.push('\t'),
+            Some(c @ ('"' | '\\')) => res.push(c),
+            Some(c) => {
+                res.push('\\');
+                res.push(c);
+            }
+            None => res.push('\\'),
+        }
+    }
+    res
+}
+
+fn ident(inp: &str) -> IResult<&str, Token> {
+    map(
+        recognize(tuple((
+            satisfy(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_'),
+        ))),
+        |s| match s {
+            "and" | "or" | "not" => Token::Op(s),
+            _ => Token::Ident(s),
+        },
+    )(inp)
+}
+
 fn op(inp: &str) -> IResult<&str, Token> {
     map(
         alt((
             tag("("),
             tag(")"),
-            tag("<->"),
-            tag("=="),
-            tag("!="),
-            tag("<="),
-            tag("<"),
-            tag(">="),
-            tag(">"),
-            tag("and"),
-            tag("or"),
-            tag("not"),
+            tag("{"),
+            tag("}"),
+            tag(":"),
+            tag(";"),
+            tag(","),
+            // nested, alt takes at most 21 parsers
+            alt((
+                tag("<->"),
+                tag("=="),
+                tag("=~"),
+                tag("="),
+                tag("!="),
+                tag("<="),
+                tag("<"),
+                tag(">="),
+                tag(">"),
+            )),
             tag("+"),
             tag("-"),
             tag("**"),
@@ -270,157 +434,513 @@ fn error_token(inp: &str) -> IResult<&str, Token> {
 }
 
 fn token(inp: &str) -> IResult<&str, Token> {
-    alt((number, str, op, error_token))(inp)
+    alt((number, str, text, ident, op, error_token))(inp)
 }
 
-fn all_tokens(inp: &str) -> IResult<&str, Vec<Token>> {
-    all_consuming(preceded(
-        take_while(|c: char| c.is_ascii_whitespace()),
-        many0(terminated(
-            token,
-            take_while(|c: char| c.is_ascii_whitespace()),
-        )),
-    ))(inp)
+fn skip_whitespace(inp: &str) -> &str {
+    inp.trim_start_matches(|c: char| c.is_ascii_whitespace())
+}
+
+fn all_tokens(input: &str) -> Vec<(Token, Span)> {
+    let mut tokens = vec![];
+    let mut rest = skip_whitespace(input);
+    while let Some(c) = rest.chars().next() {
+        let (next, tok) = token(rest).unwrap_or_else(|_| {
+            // error_token doesn't cover everything, e.g. a lone "."
+            let (it, next) = rest.split_at(c.len_utf8());
+            (next, Token::Error(it))
+        });
+        let span = Span {
+            start: input.len() - rest.len(),
+            end: input.len() - next.len(),
+        };
+        tokens.push((tok, span));
+        rest = skip_whitespace(next);
+    }
+    tokens
 }
 
 impl<'a> Lexer<'a> {
     fn new(input: &'a str) -> Lexer<'a> {
-        let (_, mut tokens) = all_tokens(input).unwrap();
+        let mut tokens = all_tokens(input);
         tokens.reverse();
-        Lexer { tokens }
+        let eof = Span {
+            start: input.len(),
+            end: input.len(),
+        };
+        Lexer { tokens, eof }
     }
 
-    fn next(&mut self) -> Token {
-        self.tokens.pop().unwrap_or(Token::Eof)
+    fn next(&mut self) -> (Token<'a>, Span) {
+        self.tokens.pop().unwrap_or((Token::Eof, self.eof))
     }
-    fn peek(&mut self) -> Token {
-        self.tokens.last().copied().unwrap_or(Token::Eof)
+    fn peek(&self) -> (Token<'a>, Span) {
+        self.tokens
+            .last()
+            .copied()
+            .unwrap_or((Token::Eof, self.eof))
     }
 }
 
-#[allow(clippy::let_and_return)]
-pub fn expr(input: &str) -> Result<Expression, String> {
-    let mut lexer = Lexer::new(input);
-    // println!("\n{}", input);
-    // println!("\n{:?}", lexer.tokens);
-    let re = expr_bp(&mut lexer, 0);
-    // println!();
-    re
+/// Parse an expression, or a block.
+///
+/// The parser doesn't stop at the first error: it skips over the offending
+/// tokens and carries on, so that every error can be reported at once.
+pub fn expr(input: &str) -> Result<Expression, Vec<Diagnostic>> {
+    let mut p = Parser {
+        lexer: Lexer::new(input),
+        errors: vec![],
+    };
+    let (re, _) = if p.at(Token::Op("{")) {
+        p.block()
+    } else {
+        p.expr_bp(0)
+    };
+    let (tok, span) = p.lexer.peek();
+    if tok != Token::Eof {
+        p.error(
+            span.to(p.lexer.eof),
+            format!("unexpected {} after end of expression", tok),
+        );
+    }
+    if p.errors.is_empty() {
+        Ok(re)
+    } else {
+        Err(p.errors)
+    }
 }
 
-/// Parse the expression at the head of the lexer.
-///
-/// Start state should be a literal, paren, or prefix operator.
-/// Literal -> set as lhs
-/// Paren -> consume, recurse inside to get lhs
-/// Prefix op -> consume, recurse with higher min_bp to get lhs
-///
-/// Starting with lhs, we build an expression from the following tokens, but
-/// stop before any operator with `left_bp < min_bp`. This is the rhs
-/// of the above call.
-///
-fn expr_bp(lexer: &mut Lexer, min_bp: u8) -> Result<Expression, String> {
-    let mut lhs = match lexer.next() {
-        Token::Number(it) => {
-            // print!("{} ", it);
-            let value = it.parse().map_err(|e: ParseFloatError| e.to_string())?;
-            Ok(Expression::Number { value })
+const KEYWORDS: [&str; 4] = ["let", "if", "return", "None"];
+
+/// Stands in for the parts of the source that failed to parse. It is never
+/// returned, since the parse fails as soon as an error is recorded.
+fn placeholder() -> Expression {
+    Expression::Number { value: 0.0 }
+}
+
+fn boxed(e: Expression, span: Span) -> BoxedExpression {
+    BoxedExpression(Box::new(e), Some(span))
+}
+
+fn unexpected(tok: Token) -> String {
+    match tok {
+        Token::Error(it) if it.starts_with('"') => "unterminated string".into(),
+        t => format!("unexpected {}", t),
+    }
+}
+
+struct Parser<'a> {
+    lexer: Lexer<'a>,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&mut self, span: Span, message: impl Into<String>) {
+        self.errors.push(Diagnostic::new(span, message));
+    }
+
+    fn at(&self, tok: Token) -> bool {
+        self.lexer.peek().0 == tok
+    }
+
+    fn at_keyword(&self) -> bool {
+        matches!(self.lexer.peek().0, Token::Ident(it) if KEYWORDS.contains(&it))
+    }
+
+    /// Consume the expected token, or report an error without consuming
+    /// anything.
+    fn expect(&mut self, expected: Token) -> Option<Span> {
+        let (tok, span) = self.lexer.peek();
+        if tok == expected {
+            self.lexer.next();
+            Some(span)
+        } else {
+            self.error(span, format!("expected {}, got {}", expected, tok));
+            None
+        }
+    }
+
+    fn skip_semicolon(&mut self) {
+        if self.at(Token::Op(";")) {
+            self.lexer.next();
+        }
+    }
+
+    /// Skip to the start of the next statement, or the end of the block.
+    fn recover(&mut self) {
+        while !(self.at_keyword() || self.at(Token::Op("}")) || self.at(Token::Eof)) {
+            self.lexer.next();
+        }
+    }
+
+    /// Parse a block, starting at the opening brace.
+    ///
+    /// ```text
+    /// {
+    ///   let name = expr
+    ///   let name: Type = expr
+    ///   if expr { return None }
+    ///   return expr
+    /// }
+    /// ```
+    ///
+    /// Statements may be separated by `;`, but it isn't required since each
+    /// one starts with a keyword. A statement that fails to parse is skipped
+    /// up to the next keyword.
+    fn block(&mut self) -> (Expression, Span) {
+        let (_, open) = self.lexer.next();
+        let mut stmts = vec![];
+        loop {
+            let (tok, span) = self.lexer.peek();
+            match tok {
+                Token::Ident("let") => {
+                    self.lexer.next();
+                    match self.let_stmt() {
+                        Some(stmt) => stmts.push(stmt),
+                        None => self.recover(),
+                    }
+                }
+                Token::Ident("if") => {
+                    self.lexer.next();
+                    match self.guard_stmt() {
+                        Some(stmt) => stmts.push(stmt),
+                        None => self.recover(),
+                    }
+                }
+                Token::Ident("return") => {
+                    self.lexer.next();
+                    let (ret, ret_span) = self.expr_bp(0);
+                    self.skip_semicolon();
+                    let close = self.expect(Token::Op("}")).unwrap_or(ret_span);
+                    let ret = boxed(ret, ret_span);
+                    return (Expression::Block { stmts, ret }, open.to(close));
+                }
+                Token::Op("}") | Token::Eof => {
+                    self.error(span, format!("expected `return`, got {}", tok));
+                    self.lexer.next();
+                    let ret = boxed(placeholder(), span);
+                    return (Expression::Block { stmts, ret }, open.to(span));
+                }
+                t => {
+                    self.error(span, format!("expected statement, got {}", t));
+                    self.lexer.next();
+                    self.recover();
+                }
+            }
+            self.skip_semicolon();
         }
-        Token::Str(k, it) => {
-            // print!("{}\"{}\"", k, it);
-            match k {
-                'L' | 'l' => Ok(Expression::Variable {
+    }
+
+    fn let_stmt(&mut self) -> Option<Statement> {
+        let name = match self.lexer.peek() {
+            (Token::Ident(it), span) => {
+                self.lexer.next();
+                if KEYWORDS.contains(&it) {
+                    self.error(span, format!("`{}` is a keyword, not a name", it));
+                }
+                it.to_owned()
+            }
+            (t, span) => {
+                self.error(span, format!("expected name after `let`, got {}", t));
+                return None;
+            }
+        };
+        let ty = if self.at(Token::Op(":")) {
+            self.lexer.next();
+            match self.lexer.peek() {
+                (Token::Ident(it), span) => {
+                    self.lexer.next();
+                    let ty = type_name(it);
+                    if ty.is_none() {
+                        self.error(span, format!("not a type name: `{}`", it));
+                    }
+                    ty
+                }
+                (t, span) => {
+                    self.error(span, format!("expected type name, got {}", t));
+                    return None;
+                }
+            }
+        } else {
+            None
+        };
+        self.expect(Token::Op("="))?;
+        let (value, span) = self.expr_bp(0);
+        Some(Statement::Let {
+            name,
+            ty,
+            value: boxed(value, span),
+        })
+    }
+
+    fn guard_stmt(&mut self) -> Option<Statement> {
+        let (cond, span) = self.expr_bp(0);
+        self.expect(Token::Op("{"))?;
+        let body = self
+            .expect(Token::Ident("return"))
+            .and_then(|_| self.expect(Token::Ident("None")));
+        if body.is_none() {
+            self.recover();
+        }
+        self.skip_semicolon();
+        self.expect(Token::Op("}"))?;
+        Some(Statement::Guard {
+            cond: boxed(cond, span),
+        })
+    }
+
+    /// Parse a literal, a parenthesized expression, or a prefix operator
+    /// applied to its operand.
+    ///
+    /// Tokens that can't start an expression are reported and skipped, except
+    /// for those that end one, which are left for the caller.
+    fn primary(&mut self) -> (Expression, Span) {
+        // Only the first of a run of bad tokens is reported.
+        let mut skipped = false;
+        loop {
+            let (tok, span) = self.lexer.peek();
+            let ends_expr = match tok {
+                Token::Eof | Token::Op(")" | "}" | ";" | ",") => true,
+                Token::Ident(it) => KEYWORDS.contains(&it),
+                _ => false,
+            };
+            if ends_expr {
+                if !skipped {
+                    self.error(span, format!("expected expression, got {}", tok));
+                }
+                return (placeholder(), span);
+            }
+            self.lexer.next();
+
+            let e = match tok {
+                Token::Number(it) => match it.parse() {
+                    Ok(value) => Expression::Number { value },
+                    Err(e) => {
+                        self.error(span, (e as ParseFloatError).to_string());
+                        placeholder()
+                    }
+                },
+                Token::Str('L' | 'l', it) => Expression::Variable {
                     side: EdgeSide::Left,
                     key: it.to_owned(),
-                }),
-                'R' | 'r' => Ok(Expression::Variable {
+                },
+                Token::Str('R' | 'r', it) => Expression::Variable {
                     side: EdgeSide::Right,
                     key: it.to_owned(),
-                }),
-                'D' | 'd' => {
-                    let value = NaiveDate::parse_from_str(it, "%Y-%m-%d")
-                        .map_err(|e: chrono::ParseError| e.to_string())?;
-                    Ok(Expression::Date {
+                },
+                Token::Str('D' | 'd', it) => match NaiveDate::parse_from_str(it, "%Y-%m-%d") {
+                    Ok(value) => Expression::Date {
                         value: value.and_time(NaiveTime::MIN).into(),
-                    })
+                    },
+                    Err(e) => {
+                        self.error(span, e.to_string());
+                        placeholder()
+                    }
+                },
+                Token::Str('C' | 'c', it) => match lat_lng(it) {
+                    Some(value) => Expression::LatLng { value },
+                    None => {
+                        self.error(span, "expected coordinates as `lat,lng` in degrees");
+                        placeholder()
+                    }
+                },
+                Token::Str(k, _) => {
+                    self.error(span, format!("invalid string prefix: `{}`", k));
+                    placeholder()
                 }
-                _ => Err("Invalid string".into()),
-            }
+                Token::Text(it) => Expression::String {
+                    value: unescape(it),
+                },
+                Token::Ident(it) if self.at(Token::Op("(")) => {
+                    self.lexer.next();
+                    let (args, close) = self.args();
+                    let e = Expression::Call {
+                        name: it.to_owned(),
+                        args,
+                    };
+                    return (e, span.to(close));
+                }
+                Token::Ident("L") => Expression::Card {
+                    side: EdgeSide::Left,
+                },
+                Token::Ident("R") => Expression::Card {
+                    side: EdgeSide::Right,
+                },
+                Token::Ident(it) => Expression::Local {
+                    name: it.to_owned(),
+                },
+                Token::Op("(") => {
+                    let (inner, inner_span) = self.expr_bp(0);
+                    let close = self.expect(Token::Op(")")).unwrap_or(inner_span);
+                    return (inner, span.to(close));
+                }
+                Token::Op(op) => {
+                    let Some(((), r_bp)) = prefix_binding_power(op) else {
+                        if !skipped {
+                            self.error(span, format!("expected expression, got {}", tok));
+                        }
+                        skipped = true;
+                        continue;
+                    };
+                    let (child, child_span) = self.expr_bp(r_bp);
+                    let e = match UnOp::try_from(op) {
+                        Ok(op) => Expression::Unary {
+                            op,
+                            child: boxed(child, child_span),
+                        },
+                        Err(e) => {
+                            self.error(span, e);
+                            placeholder()
+                        }
+                    };
+                    return (e, span.to(child_span));
+                }
+                Token::Error(_) => {
+                    if !skipped {
+                        self.error(span, unexpected(tok));
+                    }
+                    skipped = true;
+                    continue;
+                }
+                Token::Eof => unreachable!(),
+            };
+            return (e, span);
         }
-        Token::Op("(") => {
-            let lhs = expr_bp(lexer, 0)?;
-            if lexer.next() != Token::Op(")") {
-                Err("Mismatched (".into())
-            } else {
-                Ok(lhs)
-            }
+    }
+
+    /// Parse the arguments of a call, after the opening paren, and return
+    /// them along with the span of the closing paren.
+    fn args(&mut self) -> (Vec<BoxedExpression>, Span) {
+        let mut args = vec![];
+        if self.at(Token::Op(")")) {
+            let (_, close) = self.lexer.next();
+            return (args, close);
         }
-        Token::Op(op_borrow) => {
-            let op = op_borrow.to_owned();
-            let ((), r_bp) = prefix_binding_power(&op)?;
-            let rhs = expr_bp(lexer, r_bp)?;
-            // print!("{} ", op);
-            op.as_str().try_into().map(|op| Expression::Unary {
-                op,
-                child: rhs.into(),
-            })
+        loop {
+            let (arg, span) = self.expr_bp(0);
+            args.push(boxed(arg, span));
+            if !self.at(Token::Op(",")) {
+                let close = self.expect(Token::Op(")")).unwrap_or(span);
+                return (args, close);
+            }
+            self.lexer.next();
         }
-        t => Err(format!("bad token: {:?}", t)),
-    }?;
-
-    loop {
-        let op = match lexer.peek() {
-            Token::Eof => break,
-            Token::Op(op) => Ok(op.to_owned()),
-            t => Err(format!("not infix or postfix op: {:?}", t)),
-        }?;
-
-        if let Some((l_bp, ())) = postfix_binding_power(&op) {
-            if l_bp < min_bp {
-                break;
+    }
+
+    /// Parse the expression at the head of the lexer.
+    ///
+    /// Start state should be a literal, paren, or prefix operator.
+    /// Literal -> set as lhs
+    /// Paren -> consume, recurse inside to get lhs
+    /// Prefix op -> consume, recurse with higher min_bp to get lhs
+    ///
+    /// Starting with lhs, we build an expression from the following tokens, but
+    /// stop before any operator with `left_bp < min_bp`. This is the rhs
+    /// of the above call.
+    ///
+    /// An operand where an operator is expected is reported and skipped.
+    fn expr_bp(&mut self, min_bp: u8) -> (Expression, Span) {
+        let (mut lhs, mut lhs_span) = self.primary();
+
+        loop {
+            let (tok, span) = self.lexer.peek();
+            let op = match tok {
+                Token::Eof => break,
+                Token::Ident(it) if KEYWORDS.contains(&it) => break,
+                Token::Op(op) => op,
+                _ => "",
+            };
+
+            if let Some((l_bp, ())) = postfix_binding_power(op) {
+                if l_bp < min_bp {
+                    break;
+                }
+                self.lexer.next();
+
+                lhs = match UnOp::try_from(op) {
+                    Ok(op) => Expression::Unary {
+                        op,
+                        child: boxed(lhs, lhs_span),
+                    },
+                    Err(e) => {
+                        self.error(span, e);
+                        placeholder()
+                    }
+                };
+                lhs_span = lhs_span.to(span);
+                continue;
             }
-            lexer.next();
 
-            lhs = op.as_str().try_into().map(|op| Expression::Unary {
-                op,
-                child: lhs.into(),
-            })?;
-            continue;
-        }
+            if let Some((l_bp, r_bp)) = infix_binding_power(op) {
+                if l_bp < min_bp {
+                    break;
+                }
+                self.lexer.next();
 
-        if let Some((l_bp, r_bp)) = infix_binding_power(&op) {
-            if l_bp < min_bp {
+                let (rhs, rhs_span) = self.expr_bp(r_bp);
+                lhs = match BinOp::try_from(op) {
+                    Ok(op) => Expression::Binary {
+                        op,
+                        lhs: boxed(lhs, lhs_span),
+                        rhs: boxed(rhs, rhs_span),
+                    },
+                    Err(e) => {
+                        self.error(span, e);
+                        placeholder()
+                    }
+                };
+                lhs_span = lhs_span.to(rhs_span);
+                continue;
+            }
+
+            if matches!(op, ")" | "{" | "}" | ";" | ":" | "=" | ",") {
                 break;
             }
-            lexer.next();
-
-            let rhs = expr_bp(lexer, r_bp)?;
-            lhs = op.as_str().try_into().map(|op| Expression::Binary {
-                op,
-                lhs: lhs.into(),
-                rhs: rhs.into(),
-            })?;
-            continue;
-        }
 
-        if matches!(op.as_str(), ")") {
-            break;
-        } else {
-            return Err(format!("not infix or postfix op: {:?}", op));
+            // Something that isn't an operator: report it once and skip the
+            // whole operand it starts.
+            match tok {
+                Token::Error(_) => self.error(span, unexpected(tok)),
+                t => self.error(span, format!("expected operator, got {}", t)),
+            }
+            let errors = self.errors.len();
+            self.primary();
+            self.errors.truncate(errors);
         }
+
+        (lhs, lhs_span)
     }
+}
+
+fn type_name(name: &str) -> Option<ExprType> {
+    match name {
+        "Bool" => Some(ExprType::Bool),
+        "Number" => Some(ExprType::Number),
+        "LatLng" => Some(ExprType::LatLng),
+        "Date" => Some(ExprType::Date),
+        "Duration" => Some(ExprType::Duration),
+        "String" => Some(ExprType::String),
+        _ => None,
+    }
+}
 
-    Ok(lhs)
+/// `lat,lng` in degrees, within the range of valid coordinates
+fn lat_lng(s: &str) -> Option<(f64, f64)> {
+    let (lat, lng) = s.split_once(',')?;
+    let lat: f64 = lat.trim().parse().ok()?;
+    let lng: f64 = lng.trim().parse().ok()?;
+    let valid = (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lng);
+    valid.then_some((lat, lng))
 }
 
-fn prefix_binding_power(op: &str) -> Result<((), u8), String> {
+pub(super) fn prefix_binding_power(op: &str) -> Option<((), u8)> {
     match op {
-        "not" | "+" | "-" => Ok(((), 13)),
-        _ => Err(format!("not prefix op: {:?}", op)),
+        "not" | "+" | "-" => Some(((), 13)),
+        _ => None,
     }
 }
 
-fn postfix_binding_power(op: &str) -> Option<(u8, ())> {
+pub(super) fn postfix_binding_power(op: &str) -> Option<(u8, ())> {
     let res = match op {
         "?" => (15, ()),
         _ => return None,
@@ -428,11 +948,11 @@ fn postfix_binding_power(op: &str) -> Option<(u8, ())> {
     Some(res)
 }
 
-fn infix_binding_power(op: &str) -> Option<(u8, u8)> {
+pub(super) fn infix_binding_power(op: &str) -> Option<(u8, u8)> {
     let res = match op {
         "or" => (1, 2),
         "and" => (3, 4),
-        "==" | "!=" | "<" | "<=" | ">" | ">=" => (5, 6),
+        "==" | "!=" | "<" | "<=" | ">" | ">=" | "=~" => (5, 6),
         "+" | "-" | "<->" => (7, 8),
         "*" | "/" => (9, 10),
         "**" => (11, 12),
@@ -443,10 +963,10 @@ fn infix_binding_power(op: &str) -> Option<(u8, u8)> {
 
 #[cfg(test)]
 mod tests {
-    use crate::tinylang::expr;
+    use crate::tinylang::{expr, render_all, Diagnostic};
 
     #[test]
-    fn test_expr() -> Result<(), String> {
+    fn test_expr() -> Result<(), Vec<Diagnostic>> {
         let s = expr("1")?;
         assert_eq!(s.to_string(), "1");
 
@@ -471,6 +991,27 @@ mod tests {
             "(/ (** (- (Left start) (date 1970-01-01 00:00:00)) 2) 365.25)"
         );
 
+        let s = expr("bearing(L\"at\", c\"48.85, -2.35\") < 90 + f()")?;
+        assert_eq!(
+            s.to_string(),
+            "(< (bearing (Left at) (latlng 48.85 -2.35)) (+ 90 (f)))"
+        );
+
+        let s = expr("L\"born\" + years(2 * 5) - days(1)")?;
+        assert_eq!(s.to_string(), "(- (+ (Left born) (years (* 2 5))) (days 1))");
+
+        let s = expr(r#"not (R"Card" =~ "^The ") and capture(L"x", "\d\"\\") != """#)?;
+        assert_eq!(
+            s.to_string(),
+            r#"(and (not (=~ (Right Card) "^The ")) (!= (capture (Left x) "\\d\"\\") ""))"#
+        );
+
+        let s = expr(r#"has_edge("Couple", neighbors("Couple", L), R) and l > 1"#)?;
+        assert_eq!(
+            s.to_string(),
+            r#"(and (has_edge "Couple" (neighbors "Couple" Left) Right) (> l 1))"#
+        );
+
         if let Ok(s) = expr("4 == == 5") {
             panic!("{}", s.to_string());
         };
@@ -491,6 +1032,114 @@ mod tests {
             panic!("{}", s.to_string());
         };
 
+        if let Ok(s) = expr("1 )") {
+            panic!("{}", s.to_string());
+        };
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_block() -> Result<(), Vec<Diagnostic>> {
+        let s = expr("{ return 1 }")?;
+        assert_eq!(s.to_string(), "(block (return 1))");
+
+        let s = expr(
+            r#"{
+              let b1: Date = L"Birth date"
+              let b2: Date = R"Birth date";
+              if b1 == b2 { return None }
+              let diff = (b2 - b1) / 365.25
+              return -0.01 * diff ** 2
+            }"#,
+        )?;
+        assert_eq!(
+            s.to_string(),
+            "(block (let b1: Date (Left Birth date)) (let b2: Date (Right Birth date)) \
+             (if (== b1 b2) (return None)) (let diff (/ (- b2 b1) 365.25)) \
+             (return (* (- 0.01) (** diff 2))))"
+        );
+
+        let s = expr("{ let order = 1; let android = order and not origin; return android }")?;
+        assert_eq!(
+            s.to_string(),
+            "(block (let order 1) (let android (and order (not origin))) (return android))"
+        );
+
+        if let Ok(s) = expr("{ let x = 1 }") {
+            panic!("{}", s.to_string());
+        };
+
+        if let Ok(s) = expr("{ let return = 1; return 2 }") {
+            panic!("{}", s.to_string());
+        };
+
+        if let Ok(s) = expr("{ let x: Foo = 1; return x }") {
+            panic!("{}", s.to_string());
+        };
+
+        if let Ok(s) = expr("{ if x { return 1 } return 2 }") {
+            panic!("{}", s.to_string());
+        };
+
+        if let Ok(s) = expr("{ return { return 1 } }") {
+            panic!("{}", s.to_string());
+        };
+
+        if let Ok(s) = expr("{ return 1 } 2") {
+            panic!("{}", s.to_string());
+        };
+
         Ok(())
     }
+
+    fn errors(src: &str) -> Vec<String> {
+        match expr(src) {
+            Ok(e) => panic!("{}", e),
+            Err(errors) => errors.iter().map(|d| d.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(errors("4 == == 5"), ["5..7: expected expression, got `==`"]);
+        assert_eq!(errors("-1 3"), ["3..4: expected operator, got `3`"]);
+        assert_eq!(errors("(1 + 2"), ["6..6: expected `)`, got end of input"]);
+        assert_eq!(errors("L\""), ["1..2: unterminated string"]);
+        assert_eq!(errors("L\"x\" =~ \"a"), ["8..10: unterminated string"]);
+        assert_eq!(errors("."), ["0..1: unexpected `.`"]);
+        assert_eq!(errors("1 ) + 2"), ["2..7: unexpected `)` after end of expression"]);
+        assert_eq!(errors("C\"91,0\""), ["0..7: expected coordinates as `lat,lng` in degrees"]);
+        assert_eq!(errors("f(1,)"), ["4..5: expected expression, got `)`"]);
+        assert_eq!(errors("f(1 2)"), ["4..5: expected operator, got `2`"]);
+        assert_eq!(errors("f(1"), ["3..3: expected `)`, got end of input"]);
+        assert_eq!(errors("1, 2"), ["1..4: unexpected `,` after end of expression"]);
+
+        // one error per mistake, not only the first one
+        assert_eq!(
+            errors("u\"x\" + (2 * ) + 3 4"),
+            [
+                "0..4: invalid string prefix: `u`",
+                "12..13: expected expression, got `)`",
+                "18..19: expected operator, got `4`",
+            ]
+        );
+        assert_eq!(
+            errors("{ let = 1; let y: Foo = 2; if y { return 2 } let return = 3 }"),
+            [
+                "6..7: expected name after `let`, got `=`",
+                "18..21: not a type name: `Foo`",
+                "41..42: expected `None`, got `2`",
+                "49..55: `return` is a keyword, not a name",
+                "60..61: expected `return`, got `}`",
+            ]
+        );
+
+        let src = "{\n  let x = 1 +\n  return x\n}";
+        let rendered = render_all(src, &expr(src).unwrap_err());
+        assert_eq!(
+            rendered,
+            "3 |   return x\n  |   ^^^^^^ expected expression, got `return`"
+        );
+    }
 }
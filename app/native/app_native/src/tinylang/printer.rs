@@ -0,0 +1,318 @@
+use std::fmt::Write;
+
+use crate::types::EdgeSide;
+
+use super::parser::{
+    infix_binding_power, postfix_binding_power, prefix_binding_power, Expression, Statement, UnOp,
+};
+
+/// Print an expression in the syntax read by [`expr`](super::expr), with only
+/// the parentheses needed to keep its structure.
+///
+/// Every expression returned by the parser prints back to an equivalent
+/// source. Negative numbers, and blocks anywhere but at the root, can't be
+/// read back.
+pub fn print(e: &Expression) -> String {
+    let mut out = String::new();
+    match e {
+        Expression::Block { stmts, ret } => {
+            out.push_str("{\n");
+            for stmt in stmts {
+                out.push_str("  ");
+                write_stmt(&mut out, stmt);
+                out.push('\n');
+            }
+            out.push_str("  return ");
+            write_expr(&mut out, &ret.0);
+            out.push_str("\n}");
+        }
+        e => write_expr(&mut out, e),
+    }
+    out
+}
+
+/// How tightly the expression holds on to an operator written on its left.
+fn left_bp(e: &Expression) -> u8 {
+    match e {
+        Expression::Binary { op, .. } => infix_binding_power(&op.to_string()).map(|(l, _)| l),
+        Expression::Unary { op: UnOp::Bool, .. } => postfix_binding_power("?").map(|(l, ())| l),
+        _ => None,
+    }
+    .unwrap_or(u8::MAX)
+}
+
+/// How tightly the expression holds on to an operator written on its right.
+fn right_bp(e: &Expression) -> u8 {
+    match e {
+        Expression::Binary { op, .. } => infix_binding_power(&op.to_string()).map(|(_, r)| r),
+        Expression::Unary { op, .. } if *op != UnOp::Bool => {
+            prefix_binding_power(&op.to_string()).map(|((), r)| r)
+        }
+        _ => None,
+    }
+    .unwrap_or(u8::MAX)
+}
+
+fn write_operand(out: &mut String, e: &Expression, parens: bool) {
+    if parens {
+        out.push('(');
+        write_expr(out, e);
+        out.push(')');
+    } else {
+        write_expr(out, e);
+    }
+}
+
+fn write_expr(out: &mut String, e: &Expression) {
+    match e {
+        Expression::Number { value } => write!(out, "{}", value).unwrap(),
+        Expression::Date { value } => write!(out, "D\"{}\"", value.format("%Y-%m-%d")).unwrap(),
+        Expression::LatLng { value } => write!(out, "C\"{},{}\"", value.0, value.1).unwrap(),
+        Expression::String { value } => {
+            // a backslash is only escaped where it would read as an escape
+            out.push('"');
+            let mut chars = value.chars().peekable();
+            while let Some(c) = chars.next() {
+                let escape = match c {
+                    '"' => true,
+                    '\\' => matches!(chars.peek(), None | Some('n' | 'r' | 't' | '"' | '\\')),
+                    _ => false,
+                };
+                if escape {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+            out.push('"');
+        }
+        Expression::Variable { side, key } => match side {
+            EdgeSide::Left => write!(out, "L\"{}\"", key).unwrap(),
+            EdgeSide::Right => write!(out, "R\"{}\"", key).unwrap(),
+        },
+        Expression::Card { side } => match side {
+            EdgeSide::Left => out.push('L'),
+            EdgeSide::Right => out.push('R'),
+        },
+        Expression::Local { name } => out.push_str(name),
+        Expression::Call { name, args } => {
+            write!(out, "{}(", name).unwrap();
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                write_expr(out, &arg.0);
+            }
+            out.push(')');
+        }
+        Expression::Unary {
+            op: UnOp::Bool,
+            child,
+        } => {
+            let (l_bp, ()) = postfix_binding_power("?").unwrap();
+            write_operand(out, &child.0, right_bp(&child.0) <= l_bp);
+            out.push('?');
+        }
+        Expression::Unary { op, child } => {
+            let ((), r_bp) = prefix_binding_power(&op.to_string()).unwrap();
+            match op {
+                UnOp::Not => out.push_str("not "),
+                _ => write!(out, "{}", op).unwrap(),
+            }
+            write_operand(out, &child.0, left_bp(&child.0) < r_bp);
+        }
+        Expression::Binary { op, lhs, rhs } => {
+            let (l_bp, r_bp) = infix_binding_power(&op.to_string()).unwrap();
+            write_operand(out, &lhs.0, right_bp(&lhs.0) <= l_bp);
+            write!(out, " {} ", op).unwrap();
+            write_operand(out, &rhs.0, left_bp(&rhs.0) < r_bp);
+        }
+        Expression::Block { stmts, ret } => {
+            out.push_str("{ ");
+            for stmt in stmts {
+                write_stmt(out, stmt);
+                out.push_str("; ");
+            }
+            out.push_str("return ");
+            write_expr(out, &ret.0);
+            out.push_str(" }");
+        }
+    }
+}
+
+fn write_stmt(out: &mut String, stmt: &Statement) {
+    match stmt {
+        Statement::Let { name, ty, value } => {
+            write!(out, "let {}", name).unwrap();
+            if let Some(ty) = ty {
+                write!(out, ": {}", ty).unwrap();
+            }
+            out.push_str(" = ");
+            write_expr(out, &value.0);
+        }
+        Statement::Guard { cond } => {
+            out.push_str("if ");
+            write_expr(out, &cond.0);
+            out.push_str(" { return None }");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveTime};
+    use proptest::prelude::*;
+
+    use crate::{
+        tinylang::{
+            expr,
+            parser::{BinOp, Expression, Statement, UnOp},
+            ExprType,
+        },
+        types::EdgeSide,
+    };
+
+    use super::print;
+
+    #[test]
+    fn test_print() {
+        let cases = [
+            ("1 + 2 * 3", "1 + 2 * 3"),
+            ("(1 + 2) * 3", "(1 + 2) * 3"),
+            ("((1 - 2)) - 3", "1 - 2 - 3"),
+            ("1 - (2 - 3)", "1 - (2 - 3)"),
+            ("-(L\"a\" ** 2)", "-(L\"a\" ** 2)"),
+            ("(-L\"a\") ** 2", "-L\"a\" ** 2"),
+            ("(-L\"a\")?", "(-L\"a\")?"),
+            ("-(L\"a\"?)", "-L\"a\"?"),
+            ("not (x and y) or z", "not (x and y) or z"),
+            ("l\"a\"<->r\"b\"", "L\"a\" <-> R\"b\""),
+            (
+                "bearing( L\"a\",c\"1.50, -2\" )",
+                "bearing(L\"a\", C\"1.5,-2\")",
+            ),
+            ("-years((1 + 2))*f()", "-years(1 + 2) * f()"),
+            (
+                r#"not (R"Card"=~"^\d+ \"\\")"#,
+                r#"not (R"Card" =~ "^\d+ \"\\")"#,
+            ),
+            (
+                "D\"2000-01-31\" < d\"2001-01-01\"",
+                "D\"2000-01-31\" < D\"2001-01-01\"",
+            ),
+            (
+                "{let x:Date=L\"b\";if x==D\"2000-01-01\"{return None}return 0.5}",
+                "{\n  let x: Date = L\"b\"\n  if x == D\"2000-01-01\" { return None }\n  \
+                 return 0.5\n}",
+            ),
+        ];
+        for (src, printed) in cases {
+            assert_eq!(print(&expr(src).unwrap()), printed);
+        }
+    }
+
+    fn arb_name() -> impl Strategy<Value = String> {
+        "[a-z_][a-z0-9_]{0,5}".prop_filter("keyword", |s| {
+            !matches!(s.as_str(), "let" | "if" | "return" | "and" | "or" | "not")
+        })
+    }
+
+    fn arb_expression() -> impl Strategy<Value = Expression> {
+        let leaf = prop_oneof![
+            prop_oneof![(0u32..1000).prop_map(f64::from), 0.0f64..1e300]
+                .prop_map(|value| Expression::Number { value }),
+            (1i32..=9999, 1u32..=365).prop_map(|(y, d)| Expression::Date {
+                value: NaiveDate::from_yo_opt(y, d)
+                    .unwrap()
+                    .and_time(NaiveTime::MIN)
+                    .into(),
+            }),
+            (
+                prop::sample::select(vec![EdgeSide::Left, EdgeSide::Right]),
+                "[A-Za-z0-9 _]{0,8}"
+            )
+                .prop_map(|(side, key)| Expression::Variable { side, key }),
+            prop::sample::select(vec![EdgeSide::Left, EdgeSide::Right])
+                .prop_map(|side| Expression::Card { side }),
+            arb_name().prop_map(|name| Expression::Local { name }),
+            (-90.0f64..=90.0, -180.0f64..=180.0).prop_map(|value| Expression::LatLng { value }),
+            r#"[a-z^$.\\"\n]{0,6}"#.prop_map(|value| Expression::String { value }),
+        ];
+        leaf.prop_recursive(5, 48, 2, |inner| {
+            prop_oneof![
+                (
+                    prop::sample::select(vec![UnOp::Bool, UnOp::Not, UnOp::Neg]),
+                    inner.clone()
+                )
+                    .prop_map(|(op, child)| Expression::Unary {
+                        op,
+                        child: child.into(),
+                    }),
+                (
+                    prop::sample::select(vec![
+                        BinOp::Eq,
+                        BinOp::Neq,
+                        BinOp::Lt,
+                        BinOp::Lte,
+                        BinOp::Gt,
+                        BinOp::Gte,
+                        BinOp::And,
+                        BinOp::Or,
+                        BinOp::Add,
+                        BinOp::Sub,
+                        BinOp::Mul,
+                        BinOp::Div,
+                        BinOp::Pow,
+                        BinOp::Dist,
+                        BinOp::Match,
+                    ]),
+                    inner.clone(),
+                    inner.clone()
+                )
+                    .prop_map(|(op, lhs, rhs)| Expression::Binary {
+                        op,
+                        lhs: lhs.into(),
+                        rhs: rhs.into(),
+                    }),
+                (arb_name(), prop::collection::vec(inner, 0..3)).prop_map(|(name, args)| {
+                    Expression::Call {
+                        name,
+                        args: args.into_iter().map(Into::into).collect(),
+                    }
+                }),
+            ]
+        })
+    }
+
+    fn arb_block() -> impl Strategy<Value = Expression> {
+        let ty = prop::option::of(prop::sample::select(vec![
+            ExprType::Bool,
+            ExprType::Number,
+            ExprType::LatLng,
+            ExprType::Date,
+            ExprType::Duration,
+            ExprType::String,
+        ]));
+        let stmt = prop_oneof![
+            (arb_name(), ty, arb_expression()).prop_map(|(name, ty, value)| Statement::Let {
+                name,
+                ty,
+                value: value.into(),
+            }),
+            arb_expression().prop_map(|cond| Statement::Guard { cond: cond.into() }),
+        ];
+        (prop::collection::vec(stmt, 0..4), arb_expression()).prop_map(|(stmts, ret)| {
+            Expression::Block {
+                stmts,
+                ret: ret.into(),
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(e in prop_oneof![arb_expression(), arb_block()]) {
+            let src = print(&e);
+            prop_assert_eq!(expr(&src), Ok(e), "{}", src);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Cards and a deck shared by the tests, the benches and the fuzz target.
+
+use chrono::{NaiveDate, NaiveTime};
+
+use crate::types::{Card, CardTable, Edge, Pairing, StatArray, StatDef};
+
+pub const CARDS: usize = 6;
+
+/// An enabled Card of average popularity, with no id, notes or category.
+/// The other fields can be set with struct update syntax.
+pub fn card(title: impl Into<String>) -> Card {
+    Card {
+        title: title.into(),
+        unique_id: None,
+        is_disabled: false,
+        notes: None,
+        popularity: 0.5,
+        category: None,
+    }
+}
+
+/// A small deck with a column of every type, where every third Card
+/// misses its stats, and two Pairings
+pub fn card_table() -> CardTable {
+    card_table_of(CARDS)
+}
+
+/// Like `card_table`, with `n` Cards
+pub fn card_table_of(n: usize) -> CardTable {
+    let present = |i: usize| i % 3 != 2;
+    let cards = (0..n).map(|i| card(format!("Card {}", i))).collect();
+    let column = |label: &str, data| StatDef {
+        label: label.into(),
+        data,
+    };
+    let stat_defs = vec![
+        column(
+            "N",
+            StatArray::Number {
+                unit: None,
+                values: (0..n)
+                    .map(|i| present(i).then_some(i as f64 * 1.5 - 2.0))
+                    .collect(),
+            },
+        ),
+        column(
+            "D",
+            StatArray::Date {
+                values: (0..n)
+                    .map(|i| {
+                        let date = NaiveDate::from_ymd_opt(1990 + i as i32, 1 + i as u32 % 12, 1);
+                        present(i).then(|| date.unwrap().and_time(NaiveTime::MIN).into())
+                    })
+                    .collect(),
+            },
+        ),
+        column(
+            "P",
+            StatArray::LatLng {
+                values: (0..n)
+                    .map(|i| {
+                        let (lat, lng) = (i * 20 % 140, i * 45 % 360);
+                        present(i).then_some((lat as f64 - 50.0, lng as f64))
+                    })
+                    .collect(),
+            },
+        ),
+        column(
+            "S",
+            StatArray::String {
+                values: (0..n)
+                    .map(|i| present(i).then(|| format!("{}", i % 2)))
+                    .collect(),
+            },
+        ),
+        column(
+            "B",
+            StatArray::Bool {
+                values: (0..n).map(|i| present(i).then_some(i % 2 == 0)).collect(),
+            },
+        ),
+    ];
+    let pairings = vec![
+        Pairing {
+            label: "E".into(),
+            is_symmetric: true,
+            data: (0..n as u64 - 1)
+                .map(|i| Edge::new(i, i + 1, (i % 2 == 0).then(|| format!("{}", i))))
+                .collect(),
+        },
+        Pairing {
+            label: "F".into(),
+            is_symmetric: false,
+            data: (0..n as u64)
+                .map(|i| Edge::new(i, i * 2 % n as u64, None))
+                .collect(),
+        },
+    ];
+    CardTable {
+        cards,
+        tag_defs: vec![],
+        stat_defs,
+        pairings,
+    }
+}
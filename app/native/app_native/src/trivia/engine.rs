@@ -1,17 +1,18 @@
-use std::collections::HashSet;
+use std::{cmp::Ordering, collections::HashSet};
 
 use crate::{
+    bitset::Bitset,
     probability::ReservoirSample,
-    tinylang::{self, IntermediateExpr, OwnedExprValue, PartialContext},
+    tinylang::{self, BoundProgram, OwnedExprValue, PartialContext},
     types::EdgeSide,
 };
 
 use super::types::{
     instances,
     selectors::{self, PairingNested},
-    ActiveDeck, GradeableTrivia, TriviaDefCommon,
+    ActiveDeck, Continuation, GradeableTrivia, TriviaDefCommon,
 };
-use super::Result;
+use super::{ErrorKind, Result};
 
 pub trait Select {
     type Item;
@@ -63,6 +64,9 @@ type PairingIndex = usize;
 pub enum CardCond {
     /// The selected Card belongs to the instance Category
     Category(instances::Category),
+    /// The selected Card belongs to a Category other than the instance
+    /// Category
+    NoCategory(instances::Category),
     /// The pairing at the index has a link from the selected Card to any
     /// Card
     EdgeOut(PairingIndex),
@@ -71,19 +75,80 @@ pub enum CardCond {
     NoEdge(CardIndex, PairingIndex),
     /// The expression evaluates to true when `left` is the instance Card and
     /// `right` is the selected Card
-    Predicate(tinylang::Expression, Option<CardIndex>),
+    Predicate(tinylang::Program, Option<CardIndex>),
+    /// The expression evaluates to true when `left` is the selected Card
+    PredicateOut(tinylang::Program),
+    /// The Number expression, evaluated like a `Predicate`, multiplies the
+    /// selection weight of the selected Card. A result of None or a
+    /// non-positive score rejects the Card. Selecting with a score visits
+    /// every enabled Card, O(n) per call instead of sampling the deck's tree
+    Score(tinylang::Program, Option<CardIndex>),
+    /// The Number or Date expression, evaluated with the selected Card on the
+    /// right, compares to the value with the ordering
+    Compare(tinylang::Program, OwnedExprValue, Ordering),
+    /// The selected Card is not the instance Card, and the LatLng
+    /// expression is at a distance in km from the instance Card to the
+    /// selected Card that compares to the value with the ordering
+    Distance(tinylang::Program, CardIndex, f64, Ordering),
     /// All left-side variables in the expression are present on the selected
     /// Card
-    ExpressionOut(tinylang::Expression),
+    ExpressionOut(tinylang::Program),
     /// All right-side variables in the expression are present on the selected
     /// Card
-    ExpressionIn(tinylang::Expression),
+    ExpressionIn(tinylang::Program),
     /// The selected Card has a Tag matching the instance Tag
     Tag(instances::Tag),
     /// The selected Card has no Tag matching the instance Tag
     NoTag(instances::Tag),
     /// The selected Card has a Tag for the tag definition at the index
     TagOut(usize),
+    /// The selected Card is not the instance Card
+    Other(CardIndex),
+    /// The selected Card is in the set, usually precomputed by
+    /// `CardCond::eligible`. Only the Cards in the set are sampled
+    Eligible(Bitset),
+}
+
+impl CardCond {
+    /// The Cards satisfying all the conds, which must not depend on an
+    /// instance. Computing them once per TriviaDef spares checking each
+    /// sampled Card on every call.
+    pub fn eligible(deck: &ActiveDeck, conds: &[CardCond]) -> Result<Bitset> {
+        let bound = deck.data.cards.len();
+        let mut res = Bitset::full(bound);
+        for cond in conds {
+            let set = match cond {
+                CardCond::ExpressionOut(expr) => expr
+                    .bind(&deck.data, &deck.data)
+                    .has_vars_all(EdgeSide::Left),
+                CardCond::ExpressionIn(expr) => expr
+                    .bind(&deck.data, &deck.data)
+                    .has_vars_all(EdgeSide::Right),
+                CardCond::PredicateOut(expr) => {
+                    expr.bind(&deck.data, &deck.data).filter(EdgeSide::Left)
+                }
+                CardCond::EdgeOut(which) => {
+                    let mut set = Bitset::new(bound);
+                    for (i, _) in deck.pairings[*which].edge_infos.keys() {
+                        set.insert(*i);
+                    }
+                    set
+                }
+                CardCond::TagOut(which) => {
+                    Bitset::from_fn(bound, |i| !deck.data.tag_defs[*which].values[i].is_empty())
+                }
+                CardCond::Eligible(set) => set.clone(),
+                _ => {
+                    return Err(ErrorKind::Msg(
+                        "CardCond::eligible: conds must not depend on an instance".into(),
+                    )
+                    .into())
+                }
+            };
+            res.intersect_with(&set);
+        }
+        Ok(res)
+    }
 }
 
 impl Select for selectors::Card {
@@ -115,27 +180,59 @@ impl Select for selectors::Card {
             .iter()
             .map(|stat| {
                 (
-                    stat.expression.optimize(&deck.data, &deck.data).unwrap(),
+                    stat.expression.bind(&deck.data, &deck.data),
                     stat.return_type,
                 )
             })
             .collect();
         // Left for Out since we're on the left end of the arrow. Right for In
-        let mut analyze_exprs: Vec<(IntermediateExpr<'_>, EdgeSide)> = vec![];
-        let mut eval_exprs: Vec<(IntermediateExpr<'_>, Option<usize>)> = vec![];
+        let mut analyze_exprs: Vec<(BoundProgram<'_>, EdgeSide)> = vec![];
+        let mut eval_exprs: Vec<(BoundProgram<'_>, Option<usize>)> = vec![];
+        let mut out_exprs: Vec<BoundProgram<'_>> = vec![];
+        let mut score_exprs: Vec<(BoundProgram<'_>, Option<usize>)> = vec![];
+        let mut cmp_exprs: Vec<(BoundProgram<'_>, &OwnedExprValue, Ordering)> = vec![];
+        let mut dist_exprs: Vec<(BoundProgram<'_>, (f64, f64), f64, Ordering)> = vec![];
         let mut prohibited = HashSet::new();
+        let mut eligible: Option<&Bitset> = None;
         for c in conds.iter() {
             match c {
-                CardCond::ExpressionOut(expr) => analyze_exprs.push((
-                    expr.optimize(&deck.data, &deck.data).unwrap(),
-                    EdgeSide::Left,
-                )),
-                CardCond::ExpressionIn(expr) => analyze_exprs.push((
-                    expr.optimize(&deck.data, &deck.data).unwrap(),
-                    EdgeSide::Right,
-                )),
+                CardCond::ExpressionOut(expr) => {
+                    analyze_exprs.push((expr.bind(&deck.data, &deck.data), EdgeSide::Left))
+                }
+                CardCond::ExpressionIn(expr) => {
+                    analyze_exprs.push((expr.bind(&deck.data, &deck.data), EdgeSide::Right))
+                }
                 CardCond::Predicate(expr, o) => {
-                    eval_exprs.push((expr.optimize(&deck.data, &deck.data).unwrap(), *o))
+                    let ie = expr.bind(&deck.data, &deck.data);
+                    // a predicate that doesn't depend on the Cards is only
+                    // checked once
+                    if !ie.is_const() {
+                        eval_exprs.push((ie, *o))
+                    } else if ie.get_value(0, 0) != Some(OwnedExprValue::Bool(true)) {
+                        return vec![];
+                    }
+                }
+                CardCond::PredicateOut(expr) => {
+                    let ie = expr.bind(&deck.data, &deck.data);
+                    if !ie.is_const() {
+                        out_exprs.push(ie)
+                    } else if ie.get_value(0, 0) != Some(OwnedExprValue::Bool(true)) {
+                        return vec![];
+                    }
+                }
+                CardCond::Score(expr, o) => {
+                    score_exprs.push((expr.bind(&deck.data, &deck.data), *o))
+                }
+                CardCond::Compare(expr, value, ordering) => {
+                    cmp_exprs.push((expr.bind(&deck.data, &deck.data), value, *ordering))
+                }
+                CardCond::Distance(expr, left, km, ordering) => {
+                    let ie = expr.bind(&deck.data, &deck.data);
+                    let Some(OwnedExprValue::LatLng(point)) = ie.get_value(0, *left) else {
+                        return vec![];
+                    };
+                    prohibited.insert(*left);
+                    dist_exprs.push((ie, point, *km, *ordering))
                 }
                 CardCond::NoEdge(left, which) => {
                     let indices = deck.pairings[*which]
@@ -144,77 +241,143 @@ impl Select for selectors::Card {
                         .map(|((_, i), _)| *i);
                     prohibited.extend(indices);
                 }
+                CardCond::Other(left) => {
+                    prohibited.insert(*left);
+                }
+                CardCond::Eligible(set) => eligible = Some(set),
                 _ => (),
             }
         }
-        deck.with_iter(self.difficulty, |iter| {
-            iter.filter_map(|i| {
-                if prohibited.contains(&i) {
+        let accept = |i: usize| {
+            if prohibited.contains(&i) {
+                return None;
+            }
+            for (expr, side) in analyze_exprs.iter() {
+                let check = match side {
+                    EdgeSide::Left => expr.has_vars(&PartialContext::Left(i)),
+                    EdgeSide::Right => expr.has_vars(&PartialContext::Right(i)),
+                };
+                if !check {
                     return None;
                 }
-                for (expr, side) in analyze_exprs.iter() {
-                    let check = match side {
-                        EdgeSide::Left => expr.has_vars(&PartialContext::Left(i)),
-                        EdgeSide::Right => expr.has_vars(&PartialContext::Right(i)),
-                    };
-                    if !check {
-                        return None;
-                    }
+            }
+            for expr in out_exprs.iter() {
+                match expr.get_value(i, 0) {
+                    Some(OwnedExprValue::Bool(true)) => (),
+                    _ => return None,
                 }
-                for (expr, left) in eval_exprs.iter() {
-                    let check = expr.get_value(left.unwrap_or(0), i);
-                    match check {
-                        Some(OwnedExprValue::Bool(true)) => (),
-                        _ => return None,
-                    }
+            }
+            for (expr, value, ordering) in cmp_exprs.iter() {
+                let check = expr.get_value(0, i);
+                if check.and_then(|v| compare_values(&v, value)) != Some(*ordering) {
+                    return None;
                 }
-                for cond in conds {
-                    let check = match cond {
-                        CardCond::Predicate(_, _) => true,
-                        CardCond::ExpressionOut(_) => true,
-                        CardCond::ExpressionIn(_) => true,
-                        CardCond::NoEdge(_, _) => true,
-                        CardCond::Category(instances::Category(cat)) => deck.data.cards[i]
-                            .category
-                            .as_ref()
-                            .is_some_and(|ci| ci == cat),
-                        CardCond::EdgeOut(which) => deck.pairings[*which]
-                            .edge_infos
-                            .range((i, 0)..(i + 1, 0))
-                            .next()
-                            .is_some(),
-                        CardCond::Tag(instances::Tag { which, value }) => {
-                            deck.data.tag_defs[*which].values[i].contains(value)
-                        }
-                        CardCond::NoTag(instances::Tag { which, value }) => {
-                            !deck.data.tag_defs[*which].values[i].contains(value)
-                        }
-                        CardCond::TagOut(which) => !deck.data.tag_defs[*which].values[i].is_empty(),
-                    };
-                    if !check {
-                        return None;
+            }
+            for (expr, point, km, ordering) in dist_exprs.iter() {
+                let Some(OwnedExprValue::LatLng(v)) = expr.get_value(0, i) else {
+                    return None;
+                };
+                if tinylang::distance_km(*point, v).partial_cmp(km) != Some(*ordering) {
+                    return None;
+                }
+            }
+            for (expr, left) in eval_exprs.iter() {
+                let check = expr.get_value(left.unwrap_or(0), i);
+                match check {
+                    Some(OwnedExprValue::Bool(true)) => (),
+                    _ => return None,
+                }
+            }
+            for cond in conds {
+                let check = match cond {
+                    CardCond::Predicate(_, _) => true,
+                    CardCond::PredicateOut(_) => true,
+                    CardCond::Score(_, _) => true,
+                    CardCond::Compare(_, _, _) => true,
+                    CardCond::Distance(_, _, _, _) => true,
+                    CardCond::ExpressionOut(_) => true,
+                    CardCond::ExpressionIn(_) => true,
+                    CardCond::NoEdge(_, _) => true,
+                    CardCond::Other(_) => true,
+                    CardCond::Eligible(_) => true,
+                    CardCond::Category(instances::Category(cat)) => deck.data.cards[i]
+                        .category
+                        .as_ref()
+                        .is_some_and(|ci| ci == cat),
+                    CardCond::NoCategory(instances::Category(cat)) => deck.data.cards[i]
+                        .category
+                        .as_ref()
+                        .is_some_and(|ci| ci != cat),
+                    CardCond::EdgeOut(which) => deck.pairings[*which]
+                        .edge_infos
+                        .range((i, 0)..(i + 1, 0))
+                        .next()
+                        .is_some(),
+                    CardCond::Tag(instances::Tag { which, value }) => {
+                        deck.data.tag_defs[*which].values[i].contains(value)
+                    }
+                    CardCond::NoTag(instances::Tag { which, value }) => {
+                        !deck.data.tag_defs[*which].values[i].contains(value)
                     }
+                    CardCond::TagOut(which) => !deck.data.tag_defs[*which].values[i].is_empty(),
+                };
+                if !check {
+                    return None;
+                }
+            }
+            let mut stats = vec![];
+            for (expr, value_type) in stat_exprs.iter() {
+                if let Some(value) = expr.get_value(0, i) {
+                    stats.push(instances::Stat {
+                        value,
+                        value_type: *value_type,
+                    })
+                } else {
+                    return None;
                 }
-                let mut stats = vec![];
-                for (expr, value_type) in stat_exprs.iter() {
-                    if let Some(value) = expr.get_value(0, i) {
-                        stats.push(instances::Stat {
-                            value,
-                            value_type: *value_type,
-                        })
-                    } else {
-                        return None;
+            }
+            Some(instances::Card {
+                index: i,
+                stats,
+                pairing_info: None,
+            })
+        };
+        if score_exprs.is_empty() {
+            return deck.with_iter_over(self.difficulty, eligible, |iter| {
+                iter.filter_map(accept).take(n).collect()
+            });
+        }
+        // The scores depend on the instance Card, so the deck's sample tree
+        // can't be reused, and the weights can't be precomputed per def.
+        // Visit every enabled Card instead, which costs O(n) per call.
+        deck.data
+            .cards
+            .iter()
+            .enumerate()
+            .filter(|(i, c)| !c.is_disabled && eligible.is_none_or(|e| e.contains(*i)))
+            .filter_map(|(i, c)| {
+                let mut weight = f64::exp(-self.difficulty * c.popularity);
+                for (expr, left) in score_exprs.iter() {
+                    match expr.get_value(left.unwrap_or(0), i) {
+                        Some(OwnedExprValue::Number(x)) if x > 0.0 && x.is_finite() => weight *= x,
+                        _ => return None,
                     }
                 }
-                Some(instances::Card {
-                    index: i,
-                    stats,
-                    pairing_info: None,
-                })
+                accept(i).map(|inst| (weight, inst))
             })
-            .take(n)
+            .sample_weighted(n, |(weight, _)| *weight)
+            .into_iter()
+            .map(|(_, inst)| inst)
             .collect()
-        })
+    }
+}
+
+/// Numbers and Dates compare like in tinylang, other values don't
+fn compare_values(lhs: &OwnedExprValue, rhs: &OwnedExprValue) -> Option<Ordering> {
+    match (lhs, rhs) {
+        (OwnedExprValue::Number(l), OwnedExprValue::Number(r)) => l.partial_cmp(r),
+        (OwnedExprValue::Date(l), OwnedExprValue::Date(r)) => Some(l.cmp(r)),
+        _ => None,
     }
 }
 
@@ -251,7 +414,11 @@ impl Select for selectors::Tag {
                     None => false,
                 };
                 if !intersects {
-                    for t in ti.iter().take(n - acc.len()) {
+                    // a Tag already in the set doesn't count towards `n`
+                    for t in ti.iter() {
+                        if acc.len() >= n {
+                            break;
+                        }
                         let _ = acc.insert(t);
                     }
                     if acc.len() >= n {
@@ -293,4 +460,166 @@ impl Select for selectors::Stat {
 
 pub trait TriviaGen {
     fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia>;
+
+    /// Like `get_trivia`, but about the subject carried over from an earlier
+    /// Trivia instead of a fresh sample
+    fn get_follow_up(
+        &self,
+        _deck: &ActiveDeck,
+        _common: &TriviaDefCommon,
+        _continuation: &Continuation,
+    ) -> Result<GradeableTrivia> {
+        Err(ErrorKind::NoFollowUp.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        tinylang::{expr, test_support::card},
+        trivia::tests::active_deck,
+        types::{CardTable, StatArray, StatDef, TagDef},
+    };
+
+    /// Cards scored by their "Score" stat
+    fn scored(scores: &[Option<f64>]) -> (ActiveDeck, CardCond) {
+        let data = CardTable {
+            cards: (0..scores.len())
+                .map(|i| card(format!("Card {}", i)))
+                .collect(),
+            stat_defs: vec![StatDef {
+                label: "Score".into(),
+                data: StatArray::Number {
+                    unit: None,
+                    values: scores.to_vec(),
+                },
+            }],
+            ..Default::default()
+        };
+        let score = expr("R\"Score\"").unwrap().compile(&data, &data).unwrap();
+        (active_deck(0, data), CardCond::Score(score, None))
+    }
+
+    /// Cards with the given values of a "Tag" tag
+    fn tagged(values: &[&[&str]]) -> ActiveDeck {
+        let data = CardTable {
+            cards: (0..values.len())
+                .map(|i| card(format!("Card {}", i)))
+                .collect(),
+            tag_defs: vec![TagDef {
+                label: "Tag".into(),
+                values: values
+                    .iter()
+                    .map(|v| v.iter().map(|t| t.to_string()).collect())
+                    .collect(),
+            }],
+            ..Default::default()
+        };
+        active_deck(0, data)
+    }
+
+    fn select_tags(deck: &ActiveDeck, conds: &[TagCond], n: usize) -> Vec<String> {
+        let selector = selectors::Tag {
+            difficulty: 0.0,
+            which: 0,
+        };
+        let mut values: Vec<_> = selector
+            .select_n(deck, conds, n)
+            .into_iter()
+            .map(|inst| inst.value)
+            .collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_eligible() {
+        let (deck, cond) = scored(&[Some(1.0), None]);
+        let expr_in = CardCond::ExpressionIn(match cond {
+            CardCond::Score(program, _) => program,
+            _ => unreachable!(),
+        });
+        let eligible = CardCond::eligible(&deck, &[expr_in]).unwrap();
+        assert!(eligible.contains(0) && !eligible.contains(1));
+        // conds that depend on an instance are an error, not a panic
+        assert!(CardCond::eligible(&deck, &[CardCond::Other(0)]).is_err());
+    }
+
+    #[test]
+    fn test_score_rejects() {
+        let (deck, cond) = scored(&[Some(1.0), Some(0.0), None, Some(-2.0), Some(f64::NAN)]);
+        let selector = selectors::Card::new(0.0);
+        for _ in 0..100 {
+            let selected = selector.select_n(&deck, std::slice::from_ref(&cond), 5);
+            let indices: Vec<_> = selected.iter().map(|inst| inst.index).collect();
+            assert_eq!(indices, vec![0]);
+        }
+    }
+
+    #[test]
+    fn test_score_reweights() {
+        // the ratio is large enough that the light Card is never drawn first
+        let (deck, cond) = scored(&[Some(1e-150), Some(1e150)]);
+        let selector = selectors::Card::new(0.0);
+        for _ in 0..100 {
+            let selected = selector.select(&deck, std::slice::from_ref(&cond));
+            assert_eq!(selected.map(|inst| inst.index), Some(1));
+        }
+
+        let (deck, cond) = scored(&[Some(1.0), Some(3.0)]);
+        let mut counts = HashMap::new();
+        for _ in 0..4000 {
+            let selected = selector.select(&deck, std::slice::from_ref(&cond)).unwrap();
+            *counts.entry(selected.index).or_insert(0u32) += 1;
+        }
+        let p = 0.75;
+        let variance: f64 = 4000.0 * p * (1.0 - p);
+        let actual = *counts.get(&1).unwrap_or(&0) as f64;
+        let min = 4000.0 * p - 6.0 * variance.sqrt();
+        let max = 4000.0 * p + 6.0 * variance.sqrt();
+        assert!(
+            min < actual && actual < max,
+            "{} < {} < {}",
+            min,
+            actual,
+            max
+        );
+    }
+
+    #[test]
+    fn test_tag_already_selected() {
+        // the only Card with C lists it after Tags every Card has
+        let deck = tagged(&[&["A", "B"], &["A", "B"], &["A", "B"], &["A", "B", "C"]]);
+        for _ in 0..100 {
+            assert_eq!(select_tags(&deck, &[], 3), ["A", "B", "C"]);
+        }
+        let deck = tagged(&[&["A", "B"], &["C", "D"], &["B", "E"]]);
+        for _ in 0..100 {
+            assert_eq!(select_tags(&deck, &[], 5), ["A", "B", "C", "D", "E"]);
+        }
+    }
+
+    #[test]
+    fn test_tag_selection() {
+        // one Tag per Card, like the Director of a movie
+        let deck = tagged(&[&["A"], &["B"], &["A"], &["C"], &[]]);
+        for _ in 0..100 {
+            let values = select_tags(&deck, &[], 2);
+            assert_eq!(values.len(), 2);
+            assert!(values.iter().all(|v| ["A", "B", "C"].contains(&v.as_str())));
+            assert_eq!(select_tags(&deck, &[], 5), ["A", "B", "C"]);
+            // the Tags of Cards sharing one with Card 0 are left out
+            assert_eq!(select_tags(&deck, &[TagCond::NoEdge(0)], 5), ["B", "C"]);
+            assert_eq!(select_tags(&deck, &[TagCond::Edge(3)], 5), ["C"]);
+        }
+
+        let deck = tagged(&[&["A", "B"], &["C", "D"], &["B", "E"]]);
+        for _ in 0..100 {
+            assert_eq!(select_tags(&deck, &[], 1).len(), 1);
+            assert_eq!(select_tags(&deck, &[TagCond::NoEdge(0)], 5), ["C", "D"]);
+        }
+    }
 }
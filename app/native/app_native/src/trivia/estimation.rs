@@ -0,0 +1,242 @@
+use chrono::Datelike;
+
+use crate::{
+    tinylang::{self, OwnedExprValue},
+    trivia::types::TriviaExp,
+};
+
+use super::{
+    engine::{Select, TriviaGen},
+    types::{
+        selectors, ActiveDeck, Continuation, GradeableTrivia, SanityCheck, StatAnnotation, Trivia,
+        TriviaAnswerType, TriviaDefCommon,
+    },
+    ErrorKind, Result,
+};
+
+/// Increasing distances from the stat. An answer within the first band earns
+/// full credit, and each next band a smaller share of it
+#[derive(Debug, Clone)]
+pub enum Tolerance {
+    /// In the unit of the stat, or years for Dates
+    Absolute(Vec<f64>),
+    /// As fractions of the stat
+    Relative(Vec<f64>),
+}
+
+pub struct EstimationCommon {
+    pub tolerance: Tolerance,
+    /// Bounds of the input slider. Missing bounds are filled in with the
+    /// range of the stat over the deck when the TriviaDef is created.
+    pub stat_annotation: Option<StatAnnotation>,
+}
+
+impl SanityCheck for EstimationCommon {
+    type Error = super::Error;
+
+    fn sanity_check(&self) -> std::result::Result<(), Self::Error> {
+        let (Tolerance::Absolute(bands) | Tolerance::Relative(bands)) = &self.tolerance;
+        if bands.is_empty() {
+            return Err(ErrorKind::Msg("tolerance must not be empty".into()).into());
+        }
+        if !bands.iter().all(|x| x.is_finite() && *x >= 0.0) {
+            return Err(ErrorKind::Msg("tolerance >= 0".into()).into());
+        }
+        if bands.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(ErrorKind::Msg("tolerance must be increasing".into()).into());
+        }
+        Ok(())
+    }
+}
+
+pub enum EstimationDef {
+    /// Guess a Number or Date stat of a Card from its title
+    Stat {
+        selector: selectors::Stat,
+        params: EstimationCommon,
+    },
+}
+
+impl Trivia {
+    /// Dates are answered at year precision, with the year as a Number
+    pub fn new_estimation(
+        params: &EstimationCommon,
+        question: String,
+        question_value_type: tinylang::ExprType,
+    ) -> Self {
+        Self {
+            question,
+            answer_type: TriviaAnswerType::Estimate,
+            min_answers: 1,
+            max_answers: 1,
+            question_value_type,
+            stat_annotation: params.stat_annotation,
+            map_annotation: None,
+            continuation: None,
+            options: vec![],
+            prefilled_answers: vec![],
+        }
+    }
+}
+
+/// The number a player has to guess for a stat, None if it has none
+pub fn estimated_number(value: &OwnedExprValue) -> Option<f64> {
+    match value {
+        OwnedExprValue::Number(v) if v.is_finite() => Some(*v),
+        OwnedExprValue::Date(v) => Some(v.year() as f64),
+        _ => None,
+    }
+}
+
+/// The share of credit earned by each player's answer to an estimate of
+/// `value`. When more than one player answered, the closest answers earn
+/// full credit, and the others the credit of their band.
+pub fn estimate_credits(
+    value: f64,
+    bands: &[f64],
+    is_relative: bool,
+    answers: &[Option<f64>],
+) -> Vec<f64> {
+    let scale = if is_relative { value.abs() } else { 1.0 };
+    let errors: Vec<Option<f64>> = answers
+        .iter()
+        .map(|answer| answer.filter(|x| x.is_finite()).map(|x| (x - value).abs()))
+        .collect();
+    let closest = errors.iter().flatten().copied().reduce(f64::min);
+    let answered = errors.iter().flatten().count();
+    errors
+        .iter()
+        .map(|error| match *error {
+            None => 0.0,
+            Some(e) if answered > 1 && Some(e) == closest => 1.0,
+            Some(e) => match bands.iter().position(|band| e <= band * scale) {
+                Some(k) => 1.0 - k as f64 / bands.len() as f64,
+                None => 0.0,
+            },
+        })
+        .collect()
+}
+
+impl TriviaGen for EstimationDef {
+    fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia> {
+        match self {
+            EstimationDef::Stat { selector, params } => {
+                let (card_index, stat) = selector
+                    .select(deck, &[])
+                    .ok_or(ErrorKind::NotEnoughData(1))?;
+                let value = estimated_number(&stat.value).ok_or_else(|| {
+                    ErrorKind::Msg(
+                        "EstimationDef::Stat: selector must have return type Number or Date".into(),
+                    )
+                })?;
+                let (bands, is_relative) = match &params.tolerance {
+                    Tolerance::Absolute(bands) => (bands.clone(), false),
+                    Tolerance::Relative(bands) => (bands.clone(), true),
+                };
+                let expectations = vec![TriviaExp::Near {
+                    value,
+                    bands,
+                    is_relative,
+                }];
+                let card_title = &deck.data.cards[card_index].title;
+                let question = common.question_format.replace("{}", card_title);
+                let mut trivia = Trivia::new_estimation(params, question, selector.return_type);
+                trivia.continuation = Some(Continuation::card(deck, card_index));
+                Ok((trivia, expectations))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::types::NaiveDateTimeExt;
+
+    #[test]
+    fn test_estimated_number() {
+        assert_eq!(estimated_number(&OwnedExprValue::Number(2.5)), Some(2.5));
+        assert_eq!(estimated_number(&OwnedExprValue::Number(f64::NAN)), None);
+        let date = NaiveDate::from_ymd_opt(1969, 12, 31)
+            .and_then(|d| d.and_hms_opt(23, 0, 0))
+            .unwrap();
+        assert_eq!(
+            estimated_number(&OwnedExprValue::Date(NaiveDateTimeExt::from(date))),
+            Some(1969.0)
+        );
+        assert_eq!(
+            estimated_number(&OwnedExprValue::String("1969".into())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sanity_check() {
+        let params = |tolerance| EstimationCommon {
+            tolerance,
+            stat_annotation: None,
+        };
+        assert!(params(Tolerance::Relative(vec![0.1, 0.5]))
+            .sanity_check()
+            .is_ok());
+        assert!(params(Tolerance::Absolute(vec![0.0]))
+            .sanity_check()
+            .is_ok());
+        assert!(params(Tolerance::Absolute(vec![])).sanity_check().is_err());
+        assert!(params(Tolerance::Absolute(vec![-1.0]))
+            .sanity_check()
+            .is_err());
+        assert!(params(Tolerance::Relative(vec![f64::NAN]))
+            .sanity_check()
+            .is_err());
+        assert!(params(Tolerance::Absolute(vec![5.0, 1.0]))
+            .sanity_check()
+            .is_err());
+    }
+
+    #[test]
+    fn test_estimate_credits() {
+        let bands = [1.0, 5.0, 10.0, 20.0];
+        // a lone answer earns the credit of its band
+        assert_eq!(
+            estimate_credits(1969.0, &bands, false, &[Some(1975.0)]),
+            [0.5]
+        );
+        assert_eq!(
+            estimate_credits(1969.0, &bands, false, &[Some(1990.0)]),
+            [0.0]
+        );
+        // the closest answers win
+        assert_eq!(
+            estimate_credits(
+                1969.0,
+                &bands,
+                false,
+                &[
+                    Some(1990.0),
+                    Some(1972.0),
+                    None,
+                    Some(1960.0),
+                    Some(f64::NAN)
+                ]
+            ),
+            [0.0, 1.0, 0.0, 0.5, 0.0]
+        );
+        // even outside the bands
+        assert_eq!(
+            estimate_credits(1969.0, &bands, false, &[Some(1999.0), Some(1939.0)]),
+            [1.0, 1.0]
+        );
+        // relative bands scale with the value
+        assert_eq!(
+            estimate_credits(-2000.0, &[0.1, 0.25], true, &[Some(-2300.0)]),
+            [0.5]
+        );
+        assert_eq!(
+            estimate_credits(-2000.0, &[0.1, 0.25], true, &[Some(-2300.0), Some(-2600.0)]),
+            [1.0, 0.0]
+        );
+    }
+}
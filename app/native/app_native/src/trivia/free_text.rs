@@ -0,0 +1,217 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::{
+    tinylang::{self, OwnedExprValue},
+    trivia::types::TriviaExp,
+};
+
+use super::{
+    engine::{Select, TriviaGen},
+    types::{
+        selectors, ActiveDeck, Continuation, GradeableTrivia, SanityCheck, Trivia,
+        TriviaAnswerType, TriviaDefCommon,
+    },
+    ErrorKind, Result,
+};
+
+/// Articles that a typed answer may leave out, or add
+const LEADING_ARTICLES: [&str; 3] = ["the", "a", "an"];
+
+/// An expected answer of this many normalized characters tolerates one typo
+const CHARS_PER_TYPO: usize = 5;
+
+pub struct FreeTextCommon {
+    pub max_typos: u8,
+}
+
+impl SanityCheck for FreeTextCommon {
+    type Error = super::Error;
+
+    fn sanity_check(&self) -> std::result::Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+pub enum FreeTextDef {
+    /// Guess the title of a Card from a stat, or one of its values for the
+    /// `aliases` Tag
+    Card {
+        selector: selectors::Stat,
+        aliases: Option<usize>,
+        params: FreeTextCommon,
+    },
+    /// Guess a String stat of a Card from its title
+    Stat {
+        selector: selectors::Stat,
+        params: FreeTextCommon,
+    },
+}
+
+impl Trivia {
+    pub fn new_free_text(question: String) -> Self {
+        Self {
+            question,
+            answer_type: TriviaAnswerType::FreeText,
+            min_answers: 1,
+            max_answers: 1,
+            question_value_type: tinylang::ExprType::String,
+            stat_annotation: None,
+            map_annotation: None,
+            continuation: None,
+            options: vec![],
+            prefilled_answers: vec![],
+        }
+    }
+}
+
+/// Lowercase, without diacritics, punctuation or a leading article, and with
+/// single spaces between words
+pub fn normalize(text: &str) -> String {
+    let folded: String = text
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    let words: Vec<_> = folded.split_whitespace().collect();
+    let words = match &words[..] {
+        [article, rest @ ..] if !rest.is_empty() && LEADING_ARTICLES.contains(article) => rest,
+        all => all,
+    };
+    words.join(" ")
+}
+
+/// The Levenshtein distance between two strings, counted in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(diagonal + 1).min(row[j] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether the `typed` answer matches one of the `answers`, once both are
+/// normalized. Short answers tolerate fewer typos than `max_typos`.
+pub fn grade_text(answers: &[String], max_typos: u8, typed: &str) -> bool {
+    let typed = normalize(typed);
+    answers.iter().any(|answer| {
+        let answer = normalize(answer);
+        let tolerance = (max_typos as usize).min(answer.chars().count() / CHARS_PER_TYPO);
+        edit_distance(&answer, &typed) <= tolerance
+    })
+}
+
+impl TriviaGen for FreeTextDef {
+    fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia> {
+        match self {
+            FreeTextDef::Card {
+                selector,
+                aliases,
+                params,
+            } => {
+                let (card_index, stat) = selector
+                    .select(deck, &[])
+                    .ok_or(ErrorKind::NotEnoughData(1))?;
+                let hint = match stat.value {
+                    OwnedExprValue::StringArray(v) => v.join(", "),
+                    OwnedExprValue::String(v) => v,
+                    _ => return Err(ErrorKind::Msg(
+                        "FreeTextDef::Card: selector must have return type String or StringArray"
+                            .into(),
+                    )
+                    .into()),
+                };
+                let mut answers = vec![deck.data.cards[card_index].title.clone()];
+                if let Some(which) = aliases {
+                    answers.extend(
+                        deck.data.tag_defs[*which].values[card_index]
+                            .iter()
+                            .cloned(),
+                    );
+                }
+                let question = common.question_format.replace("{}", &hint);
+                let expectations = vec![TriviaExp::Text {
+                    answers,
+                    max_typos: params.max_typos,
+                }];
+                let mut trivia = Trivia::new_free_text(question);
+                trivia.continuation = Some(Continuation::card(deck, card_index));
+                Ok((trivia, expectations))
+            }
+            FreeTextDef::Stat { selector, params } => {
+                let (card_index, stat) = selector
+                    .select(deck, &[])
+                    .ok_or(ErrorKind::NotEnoughData(1))?;
+                let answer = match stat.value {
+                    OwnedExprValue::String(v) => v,
+                    _ => {
+                        return Err(ErrorKind::Msg(
+                            "FreeTextDef::Stat: selector must have return type String".into(),
+                        )
+                        .into())
+                    }
+                };
+                let card_title = &deck.data.cards[card_index].title;
+                let question = common.question_format.replace("{}", card_title);
+                let expectations = vec![TriviaExp::Text {
+                    answers: vec![answer],
+                    max_typos: params.max_typos,
+                }];
+                let mut trivia = Trivia::new_free_text(question);
+                trivia.continuation = Some(Continuation::card(deck, card_index));
+                Ok((trivia, expectations))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  The Beatles "), "beatles");
+        assert_eq!(normalize("Zoë Saldaña"), "zoe saldana");
+        assert_eq!(normalize("São Paulo"), "sao paulo");
+        assert_eq!(normalize("Washington, D.C."), "washington d c");
+        assert_eq!(normalize("A"), "a");
+        assert_eq!(normalize("The The"), "the");
+        assert_eq!(normalize("Anne"), "anne");
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("bogota", "bogotá"), 1);
+    }
+
+    #[test]
+    fn test_grade_text() {
+        let answers = vec!["Canberra".to_string()];
+        assert!(grade_text(&answers, 1, "canberra"));
+        assert!(grade_text(&answers, 1, "Canbera"));
+        assert!(!grade_text(&answers, 1, "Cambera"));
+        assert!(!grade_text(&answers, 0, "Canbera"));
+
+        // short answers must be exact
+        let answers = vec!["Rome".to_string()];
+        assert!(grade_text(&answers, 2, "rome"));
+        assert!(!grade_text(&answers, 2, "roma"));
+
+        let answers = vec!["The Godfather".to_string(), "Il padrino".to_string()];
+        assert!(grade_text(&answers, 2, "godfather"));
+        assert!(grade_text(&answers, 2, "a godfather"));
+        assert!(grade_text(&answers, 2, "il padrino"));
+        assert!(!grade_text(&answers, 2, ""));
+    }
+}
@@ -0,0 +1,290 @@
+use std::cmp::Ordering;
+
+use rand::Rng;
+
+use crate::{
+    tinylang::{self, OwnedExprValue},
+    trivia::types::TriviaExp,
+};
+
+use super::{
+    engine::{CardCond, Select, TriviaGen},
+    multiple_choice::{transform_multiple_choice, MultipleChoiceCommon},
+    types::{
+        selectors, ActiveDeck, Continuation, GradeableTrivia, MapAnnotation, SanityCheck, Trivia,
+        TriviaAnswer, TriviaAnswerType, TriviaDefCommon,
+    },
+    ErrorKind, Result,
+};
+
+/// How many subjects or draws to try before giving up on a geography
+/// question
+const GEO_TRIES: usize = 4;
+
+pub struct LocateCommon {
+    /// Increasing distances in km. An answer within the first band earns
+    /// full credit, and each next band a smaller share of it
+    pub bands_km: Vec<f64>,
+}
+
+impl SanityCheck for LocateCommon {
+    type Error = super::Error;
+
+    fn sanity_check(&self) -> std::result::Result<(), Self::Error> {
+        if self.bands_km.is_empty() {
+            return Err(ErrorKind::Msg("bands_km must not be empty".into()).into());
+        }
+        if !self.bands_km.iter().all(|km| km.is_finite() && *km > 0.0) {
+            return Err(ErrorKind::Msg("bands_km > 0".into()).into());
+        }
+        if self.bands_km.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(ErrorKind::Msg("bands_km must be increasing".into()).into());
+        }
+        Ok(())
+    }
+}
+
+pub enum GeoDef {
+    /// Pick the Card furthest north
+    Extreme {
+        selector: selectors::Stat,
+        /// The locations of the deck
+        map: MapAnnotation,
+        params: MultipleChoiceCommon,
+    },
+    /// Pick the right Cards within `radius_km` of the left Card
+    Within {
+        left: selectors::Stat,
+        right: selectors::Stat,
+        radius_km: f64,
+        map: MapAnnotation,
+        params: MultipleChoiceCommon,
+    },
+    /// Click the location of a Card on the map
+    Locate {
+        selector: selectors::Stat,
+        map: MapAnnotation,
+        params: LocateCommon,
+    },
+}
+
+impl Trivia {
+    /// The answer is a single point, as a latitude followed by a longitude
+    pub fn new_location(question: String, map_annotation: MapAnnotation) -> Self {
+        Self {
+            question,
+            answer_type: TriviaAnswerType::Location,
+            min_answers: 1,
+            max_answers: 1,
+            question_value_type: tinylang::ExprType::LatLng,
+            stat_annotation: None,
+            map_annotation: Some(map_annotation),
+            continuation: None,
+            options: vec![],
+            prefilled_answers: vec![],
+        }
+    }
+}
+
+/// The corners of the area spanned by the LatLng expression over the enabled
+/// Cards, None if no Card has a location
+pub fn map_bounds(deck: &ActiveDeck, expression: &tinylang::Program) -> Option<MapAnnotation> {
+    let bound = expression.bind(&deck.data, &deck.data);
+    let mut acc: Option<((f64, f64), (f64, f64))> = None;
+    for (i, card) in deck.data.cards.iter().enumerate() {
+        if card.is_disabled {
+            continue;
+        }
+        if let Some(OwnedExprValue::LatLng((lat, lng))) = bound.get_value(0, i) {
+            acc = Some(match acc {
+                Some((min, max)) => (
+                    (min.0.min(lat), min.1.min(lng)),
+                    (max.0.max(lat), max.1.max(lng)),
+                ),
+                None => ((lat, lng), (lat, lng)),
+            });
+        }
+    }
+    acc.map(|(min, max)| MapAnnotation {
+        min,
+        max,
+        marker: None,
+        radius_km: None,
+    })
+}
+
+/// The share of credit earned by answering `answer` for the location
+/// `lat_lng`
+pub fn location_credit(lat_lng: (f64, f64), bands_km: &[f64], answer: (f64, f64)) -> f64 {
+    // Lambert's formula is undefined between a point and itself
+    let distance = if answer == lat_lng {
+        0.0
+    } else {
+        tinylang::distance_km(lat_lng, answer)
+    };
+    match bands_km.iter().position(|km| distance <= *km) {
+        Some(k) => 1.0 - k as f64 / bands_km.len() as f64,
+        None => 0.0,
+    }
+}
+
+impl TriviaGen for GeoDef {
+    fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia> {
+        match self {
+            GeoDef::Extreme {
+                selector,
+                map,
+                params,
+            } => {
+                let total: usize = params.total.into();
+                for _ in 0..GEO_TRIES {
+                    let mut answers: Vec<_> = selector
+                        .select_n(deck, &[], total)
+                        .into_iter()
+                        .filter_map(|(idx, stat)| match stat.value {
+                            OwnedExprValue::LatLng(v) => Some((idx, v)),
+                            _ => None,
+                        })
+                        .collect();
+                    if answers.len() < total {
+                        return Err(ErrorKind::NotEnoughData(params.total).into());
+                    }
+                    // by latitude, northernmost first
+                    answers.sort_by(|(_, (a, _)), (_, (b, _))| b.total_cmp(a));
+                    // a tie for the furthest would have two correct answers
+                    if answers[0].1 .0 <= answers[1].1 .0 {
+                        continue;
+                    }
+                    let answers_f = answers.split_off(1);
+                    let (answers, expectations) =
+                        transform_multiple_choice(answers, answers_f, params, |id, (idx, v)| {
+                            TriviaAnswer {
+                                id,
+                                answer: deck.data.cards[idx].title.clone(),
+                                question_value: v.into(),
+                            }
+                        });
+                    let question = common.question_format.clone();
+                    let mut trivia = Trivia::new_selection(
+                        params,
+                        question,
+                        tinylang::ExprType::LatLng,
+                        answers,
+                    );
+                    trivia.map_annotation = Some(*map);
+                    return Ok((trivia, expectations));
+                }
+                Err(ErrorKind::NotEnoughData(params.total).into())
+            }
+            GeoDef::Within {
+                left,
+                right,
+                radius_km,
+                map,
+                params,
+            } => {
+                for (card_index, stat) in left.select_n(deck, &[], GEO_TRIES) {
+                    let OwnedExprValue::LatLng(center) = stat.value else {
+                        continue;
+                    };
+                    let cond = |ordering| {
+                        CardCond::Distance(
+                            right.expression.clone(),
+                            card_index,
+                            *radius_km,
+                            ordering,
+                        )
+                    };
+                    let count_t = rand::thread_rng().gen_range(params.min_true..=params.max_true);
+                    let answers_t = right.select_n(deck, &[cond(Ordering::Less)], count_t.into());
+                    if answers_t.len() < params.min_true.into() {
+                        continue;
+                    }
+                    let count_f = params.total as usize - answers_t.len();
+                    let answers_f = right.select_n(deck, &[cond(Ordering::Greater)], count_f);
+                    if answers_f.len() < count_f {
+                        continue;
+                    }
+                    let (answers, expectations) = transform_multiple_choice(
+                        answers_t,
+                        answers_f,
+                        params,
+                        |id, (idx, inst)| TriviaAnswer {
+                            id,
+                            answer: deck.data.cards[idx].title.clone(),
+                            question_value: inst.value.into(),
+                        },
+                    );
+                    let card_title = &deck.data.cards[card_index].title;
+                    let question = common.question_format.replace("{}", card_title);
+                    let mut trivia = Trivia::new_selection(
+                        params,
+                        question,
+                        tinylang::ExprType::LatLng,
+                        answers,
+                    );
+                    trivia.map_annotation = Some(MapAnnotation {
+                        marker: Some(center),
+                        radius_km: Some(*radius_km),
+                        ..*map
+                    });
+                    return Ok((trivia, expectations));
+                }
+                Err(ErrorKind::NotEnoughData(params.total).into())
+            }
+            GeoDef::Locate {
+                selector,
+                map,
+                params,
+            } => {
+                let (card_index, stat) = selector
+                    .select(deck, &[])
+                    .ok_or(ErrorKind::NotEnoughData(1))?;
+                let OwnedExprValue::LatLng(lat_lng) = stat.value else {
+                    return Err(ErrorKind::Msg(
+                        "GeoDef::Locate: selector must have return type LatLng".into(),
+                    )
+                    .into());
+                };
+                let expectations = vec![TriviaExp::Location {
+                    lat_lng,
+                    bands_km: params.bands_km.clone(),
+                }];
+                let card_title = &deck.data.cards[card_index].title;
+                let question = common.question_format.replace("{}", card_title);
+                let mut trivia = Trivia::new_location(question, *map);
+                trivia.continuation = Some(Continuation::card(deck, card_index));
+                Ok((trivia, expectations))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location_credit() {
+        let paris = (48.8566, 2.3522);
+        let bands = [100.0, 500.0, 1000.0, 2000.0];
+        assert_eq!(location_credit(paris, &bands, paris), 1.0);
+        // Brussels, about 260 km away
+        assert_eq!(location_credit(paris, &bands, (50.8503, 4.3517)), 0.75);
+        // Madrid, about 1050 km away
+        assert_eq!(location_credit(paris, &bands, (40.4168, -3.7038)), 0.25);
+        // New York
+        assert_eq!(location_credit(paris, &bands, (40.7128, -74.006)), 0.0);
+    }
+
+    #[test]
+    fn test_sanity_check() {
+        let params = |bands_km: &[f64]| LocateCommon {
+            bands_km: bands_km.to_vec(),
+        };
+        assert!(params(&[50.0, 200.0]).sanity_check().is_ok());
+        assert!(params(&[]).sanity_check().is_err());
+        assert!(params(&[0.0]).sanity_check().is_err());
+        assert!(params(&[200.0, 50.0]).sanity_check().is_err());
+    }
+}
@@ -1,6 +1,16 @@
-use std::{collections::HashMap, num::TryFromIntError};
+use std::{
+    collections::{HashMap, HashSet},
+    num::TryFromIntError,
+    ops::RangeInclusive,
+};
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 
-use crate::{tinylang::{OwnedExprValue, self}, trivia::types::TriviaExp};
+use crate::{
+    probability::ReservoirSample,
+    tinylang::{self, OwnedExprValue},
+    trivia::types::TriviaExp,
+};
 
 use super::{
     engine::{Select, TriviaGen},
@@ -11,8 +21,48 @@ use super::{
     ErrorKind, Result,
 };
 
+/// Letters offered in full when an answer uses any of them, so that the ones
+/// missing from the answer count as misses
+const ALPHABETS: [RangeInclusive<char>; 4] = ['A'..='Z', 'Α'..='Ω', 'А'..='Я', 'א'..='ת'];
+
+/// How many decoys are offered for each letter of a script missing from
+/// `ALPHABETS`, and how many code points away from it they may be
+const DECOYS_PER_LETTER: usize = 2;
+const DECOY_SPREAD: u32 = 0x40;
+
 pub struct HangmanCommon {
     pub lives: u8,
+    /// Digits are shown from the start instead of guessed
+    pub prefill_digits: bool,
+    /// Punctuation and symbols are shown from the start instead of guessed.
+    /// Whitespace is always shown
+    pub prefill_punctuation: bool,
+    /// The first letter of each word is given from the start, at all of its
+    /// positions
+    pub reveal_per_word: bool,
+}
+
+impl HangmanCommon {
+    pub fn typical(lives: u8) -> Self {
+        Self {
+            lives,
+            prefill_digits: true,
+            prefill_punctuation: true,
+            reveal_per_word: false,
+        }
+    }
+
+    fn is_guessable(&self, c: char) -> bool {
+        if c.is_whitespace() {
+            false
+        } else if c.is_alphabetic() {
+            true
+        } else if c.is_numeric() {
+            !self.prefill_digits
+        } else {
+            !self.prefill_punctuation
+        }
+    }
 }
 
 impl SanityCheck for HangmanCommon {
@@ -47,6 +97,8 @@ impl Trivia {
             max_answers: 1,
             question_value_type: tinylang::ExprType::IntArray,
             stat_annotation: None,
+            map_annotation: None,
+            continuation: None,
             options,
             prefilled_answers,
         }
@@ -63,46 +115,114 @@ fn single_uppercase(c: char) -> char {
     }
 }
 
+/// The symbol a char is guessed as: uppercase, and without accents unless
+/// the accented letter is part of an alphabet, like "Й"
+fn fold(c: char) -> char {
+    let upper = single_uppercase(c);
+    if ALPHABETS.iter().any(|alphabet| alphabet.contains(&upper)) {
+        return upper;
+    }
+    let mut base = std::iter::once(upper)
+        .nfd()
+        .filter(|c| !is_combining_mark(*c));
+    match (base.next(), base.next()) {
+        (Some(b), None) => single_uppercase(b),
+        _ => upper,
+    }
+}
+
+/// Letters near `letter` in its block of code points, to offer along with it
+/// when its script is missing from `ALPHABETS`
+fn decoys(letter: char, taken: &[char]) -> Vec<char> {
+    let code = letter as u32;
+    (code.saturating_sub(DECOY_SPREAD)..=code.saturating_add(DECOY_SPREAD))
+        .filter_map(char::from_u32)
+        .filter(|&d| d.is_alphabetic() && fold(d) == d && !taken.contains(&d))
+        .sample(DECOYS_PER_LETTER)
+}
+
 fn transform_hangman(
     answer_str: &str,
     params: &HangmanCommon,
 ) -> std::result::Result<HangmanTriple, TryFromIntError> {
-    let mut answer_map: HashMap<_, _> = ('A'..='Z')
-        .enumerate()
-        .map(|(id, c)| {
-            let positions: Vec<i64> = vec![];
-            (c, (id, positions))
-        })
+    let chars: Vec<_> = answer_str.chars().map(fold).collect();
+    let mut offered = vec![];
+    for alphabet in ALPHABETS.iter() {
+        if chars.iter().any(|c| alphabet.contains(c)) {
+            offered.extend(alphabet.clone().filter(|c| c.is_alphabetic()));
+        }
+    }
+    if !params.prefill_digits && chars.iter().any(char::is_ascii_digit) {
+        offered.extend('0'..='9');
+    }
+    // the other symbols, with decoys for their letters, in code point order
+    // so that the decoys don't stand out
+    let mut others = vec![];
+    for &c in chars.iter() {
+        if params.is_guessable(c) && !offered.contains(&c) && !others.contains(&c) {
+            others.push(c);
+        }
+    }
+    let letters: Vec<_> = others
+        .iter()
+        .copied()
+        .filter(|c| c.is_alphabetic())
         .collect();
-    for (pos, c) in answer_str.chars().enumerate() {
-        let l = answer_map.len();
-        answer_map
-            .entry(single_uppercase(c))
-            .and_modify(|(_, positions)| positions.push(pos as i64))
-            .or_insert((l, vec![pos as i64]));
+    for c in letters {
+        let taken: Vec<_> = offered.iter().chain(others.iter()).copied().collect();
+        others.extend(decoys(c, &taken));
+    }
+    others.sort_unstable();
+    offered.extend(others);
+    // the first letter of each word is given, as if it had been guessed
+    let mut revealed = HashSet::new();
+    if params.reveal_per_word {
+        let mut needs_hint = true;
+        for &c in chars.iter() {
+            if c.is_whitespace() {
+                needs_hint = true;
+            } else if needs_hint && c.is_alphabetic() {
+                revealed.insert(c);
+                needs_hint = false;
+            }
+        }
+    }
+    let mut positions: HashMap<char, Vec<i64>> = HashMap::new();
+    for (pos, &c) in chars.iter().enumerate() {
+        positions.entry(c).or_default().push(pos as i64);
     }
     let mut answers = vec![];
-    let mut prefilled_answers = vec![];
     let mut ids_f = vec![];
     let mut ids_t = vec![];
-    for (c, (id, positions)) in answer_map {
-        let id: u8 = id.try_into()?;
-        let is_empty = positions.is_empty();
-        let ans = TriviaAnswer {
+    let mut prefilled_answers = vec![];
+    for c in offered {
+        let id: u8 = answers.len().try_into()?;
+        let found = positions.remove(&c).unwrap_or_default();
+        let answer = TriviaAnswer {
             id,
             answer: c.to_string(),
-            question_value: positions.into(),
+            question_value: found.clone().into(),
         };
-        if c.is_ascii_uppercase() {
-            answers.push(ans);
-        } else {
-            prefilled_answers.push(ans);
-        }
-        if is_empty {
+        // a revealed letter is prefilled under its own id, so it is neither
+        // required nor a miss
+        if revealed.contains(&c) {
+            prefilled_answers.push(answer.clone());
+        } else if found.is_empty() {
             ids_f.push(id);
         } else {
             ids_t.push(id);
         }
+        answers.push(answer);
+    }
+    // the symbols that aren't guessed are shown from the start
+    let mut shown: Vec<_> = positions.into_iter().collect();
+    shown.sort_by_key(|(_, found)| found[0]);
+    for (k, (c, found)) in shown.into_iter().enumerate() {
+        prefilled_answers.push(TriviaAnswer {
+            id: (answers.len() + k).try_into()?,
+            answer: c.to_string(),
+            question_value: found.into(),
+        });
     }
     let expectations = vec![
         TriviaExp::All { ids: ids_t },
@@ -165,7 +285,7 @@ mod tests {
     use super::*;
     use crate::{
         tinylang::{expr, ExprType},
-        trivia::tests::decks,
+        trivia::{tests::decks, Availability},
         types::Deck,
     };
     use rstest::rstest;
@@ -176,14 +296,18 @@ mod tests {
         let definition = HangmanDef::Card {
             selector: selectors::Stat {
                 difficulty: -0.5,
-                expression: expr("R\"Description\"").unwrap(),
+                expression: expr("R\"Description\"")
+                    .unwrap()
+                    .compile(&decks[3].data, &decks[3].data)
+                    .unwrap(),
                 return_type: ExprType::String,
             },
-            params: HangmanCommon { lives: 1 },
+            params: HangmanCommon::typical(1),
         };
         let common = TriviaDefCommon {
             deck_id: 3,
             question_format: "Who is this: {}".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[3], &common)?;
         writeln!(
@@ -204,14 +328,18 @@ mod tests {
         let definition = HangmanDef::Stat {
             selector: selectors::Stat {
                 difficulty: -0.5,
-                expression: expr("R\"Capital\"").unwrap(),
+                expression: expr("R\"Capital\"")
+                    .unwrap()
+                    .compile(&decks[4].data, &decks[4].data)
+                    .unwrap(),
                 return_type: ExprType::String,
             },
-            params: HangmanCommon { lives: 1 },
+            params: HangmanCommon::typical(1),
         };
         let common = TriviaDefCommon {
             deck_id: 4,
             question_format: "What is the capital of {}?".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[4], &common)?;
         writeln!(
@@ -225,4 +353,103 @@ mod tests {
         }
         Ok(())
     }
+
+    fn positions<'a>(answers: &'a [TriviaAnswer], c: &str) -> Vec<&'a TriviaAnswer> {
+        answers.iter().filter(|a| a.answer == c).collect()
+    }
+
+    #[test]
+    fn test_transform_accents() -> std::result::Result<(), TryFromIntError> {
+        let (answers, prefilled, exps) =
+            transform_hangman("Émile Zola", &HangmanCommon::typical(1))?;
+        assert_eq!(answers.len(), 26);
+        let e = positions(&answers, "E");
+        assert_eq!(e[0].question_value, vec![0i64, 4].into());
+        assert_eq!(prefilled.len(), 1);
+        assert_eq!(prefilled[0].answer, " ");
+        let TriviaExp::All { ids } = &exps[0] else {
+            panic!("expected All");
+        };
+        assert_eq!(ids.len(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_scripts() -> std::result::Result<(), TryFromIntError> {
+        let (answers, prefilled, _) = transform_hangman("Йошкар-Ола", &HangmanCommon::typical(1))?;
+        // Й is a letter of its own, not an accented И
+        assert_eq!(answers.len(), 32);
+        assert_eq!(
+            positions(&answers, "Й")[0].question_value,
+            vec![0i64].into()
+        );
+        assert!(positions(&answers, "И")[0].question_value == Vec::<i64>::new().into());
+        assert_eq!(prefilled[0].answer, "-");
+        // a script with no alphabet gets decoys, sorted in with the letters
+        let (answers, _, exps) = transform_hangman("東京", &HangmanCommon::typical(1))?;
+        assert_eq!(answers.len(), 6);
+        let offered: Vec<_> = answers.iter().map(|a| a.answer.as_str()).collect();
+        assert!(offered.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            positions(&answers, "東")[0].question_value,
+            vec![0i64].into()
+        );
+        assert_eq!(
+            positions(&answers, "京")[0].question_value,
+            vec![1i64].into()
+        );
+        let TriviaExp::NoneLenient { ids, .. } = &exps[1] else {
+            panic!("expected NoneLenient");
+        };
+        assert_eq!(ids.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_digits() -> std::result::Result<(), TryFromIntError> {
+        let params = HangmanCommon {
+            prefill_digits: false,
+            ..HangmanCommon::typical(1)
+        };
+        let (answers, prefilled, _) = transform_hangman("Route 66", &params)?;
+        assert_eq!(answers.len(), 36);
+        assert_eq!(
+            positions(&answers, "6")[0].question_value,
+            vec![6i64, 7].into()
+        );
+        assert_eq!(prefilled.len(), 1);
+        let (answers, prefilled, _) = transform_hangman("Route 66", &HangmanCommon::typical(1))?;
+        assert_eq!(answers.len(), 26);
+        assert_eq!(prefilled.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transform_reveal_per_word() -> std::result::Result<(), TryFromIntError> {
+        let params = HangmanCommon {
+            reveal_per_word: true,
+            ..HangmanCommon::typical(1)
+        };
+        let (answers, prefilled, exps) = transform_hangman("Nanny York", &params)?;
+        // the revealed letters are prefilled under their own ids, at every
+        // position, so the client doesn't count them as misses
+        let n = positions(&answers, "N")[0];
+        assert_eq!(n.question_value, vec![0i64, 2, 3].into());
+        let shown: Vec<_> = prefilled
+            .iter()
+            .map(|a| (a.id, a.answer.as_str()))
+            .collect();
+        let y = positions(&answers, "Y")[0];
+        assert_eq!(shown, [(n.id, "N"), (y.id, "Y"), (26, " ")]);
+        assert_eq!(prefilled[0].question_value, n.question_value);
+        let (TriviaExp::All { ids: ids_t }, TriviaExp::NoneLenient { ids: ids_f, .. }) =
+            (&exps[0], &exps[1])
+        else {
+            panic!("expected All and NoneLenient");
+        };
+        assert!(!ids_t.contains(&n.id) && !ids_f.contains(&n.id));
+        assert!(!ids_t.contains(&y.id) && !ids_f.contains(&y.id));
+        assert_eq!(ids_t.len(), 4);
+        Ok(())
+    }
 }
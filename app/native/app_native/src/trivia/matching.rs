@@ -0,0 +1,249 @@
+use rand::seq::SliceRandom;
+
+use crate::{bitset::Bitset, tinylang, trivia::types::TriviaExp};
+
+use super::{
+    engine::{CardCond, Select, TagCond, TriviaGen},
+    types::{
+        instances, selectors, ActiveDeck, GradeableTrivia, SanityCheck, Trivia, TriviaAnswer,
+        TriviaAnswerType, TriviaDefCommon,
+    },
+    ErrorKind, Result,
+};
+
+pub struct MatchingCommon {
+    pub total: u8,
+}
+
+impl SanityCheck for MatchingCommon {
+    type Error = super::Error;
+
+    fn sanity_check(&self) -> std::result::Result<(), Self::Error> {
+        if self.total <= 1 {
+            return Err(ErrorKind::Msg("total > 1".into()).into());
+        }
+        Ok(())
+    }
+}
+
+pub enum MatchingDef {
+    /// Match left Cards to the Cards they are paired with
+    Pairing {
+        left: selectors::Card,
+        right: selectors::Card,
+        pairing_id: usize,
+        /// The left Cards in the pairing
+        eligible: Bitset,
+        params: MatchingCommon,
+    },
+    /// Match Cards to one of their Tags
+    CardTag {
+        left: selectors::Card,
+        right: selectors::Tag,
+        /// The Cards with a Tag for the right tag definition
+        eligible: Bitset,
+        params: MatchingCommon,
+    },
+}
+
+impl Trivia {
+    /// The left items are the prefilled answers, in order, and the right items
+    /// are the options, shuffled. The selection lists the right items in the
+    /// order of the left items they match.
+    pub fn new_matching(
+        params: &MatchingCommon,
+        question: String,
+        left: Vec<TriviaAnswer>,
+        right: Vec<TriviaAnswer>,
+    ) -> Self {
+        Self {
+            question,
+            answer_type: TriviaAnswerType::Matching,
+            min_answers: params.total,
+            max_answers: params.total,
+            question_value_type: tinylang::ExprType::String,
+            stat_annotation: None,
+            map_annotation: None,
+            continuation: None,
+            options: right,
+            prefilled_answers: left,
+        }
+    }
+}
+
+/// The first `total` candidate pairs such that no left item of a pair
+/// `matches` the right item of another, so that there is a single solution
+fn unambiguous<L, R, F>(
+    candidates: impl Iterator<Item = (L, R)>,
+    total: usize,
+    matches: F,
+) -> Vec<(L, R)>
+where
+    F: Fn(&L, &R) -> bool,
+{
+    let mut acc: Vec<(L, R)> = vec![];
+    for (l, r) in candidates {
+        if acc
+            .iter()
+            .all(|(l2, r2)| !matches(l2, &r) && !matches(&l, r2))
+        {
+            acc.push((l, r));
+            if acc.len() >= total {
+                break;
+            }
+        }
+    }
+    acc
+}
+
+fn transform_matching<L, R, FL, FR>(
+    pairs: Vec<(L, R)>,
+    fun_left: FL,
+    fun_right: FR,
+) -> (Vec<TriviaAnswer>, Vec<TriviaAnswer>, Vec<TriviaExp>)
+where
+    FL: Fn(u8, L) -> TriviaAnswer,
+    FR: Fn(u8, R) -> TriviaAnswer,
+{
+    let mut order: Vec<u8> = (0..pairs.len() as u8).collect();
+    order.shuffle(&mut rand::thread_rng());
+    let mut left = vec![];
+    let mut right = vec![];
+    for (id, (l, r)) in pairs.into_iter().enumerate() {
+        left.push(fun_left(id as u8, l));
+        right.push(fun_right(order[id], r));
+    }
+    right.sort_by_key(|ans| ans.id);
+    let expectations = vec![TriviaExp::Permutation { ids: order }];
+    (left, right, expectations)
+}
+
+impl TriviaGen for MatchingDef {
+    fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia> {
+        match self {
+            MatchingDef::Pairing {
+                left,
+                right,
+                pairing_id,
+                eligible,
+                params,
+            } => {
+                let total: usize = params.total.into();
+                // extra subjects, in case some of them make the matching
+                // ambiguous
+                let subjects =
+                    left.select_n(deck, &[CardCond::Eligible(eligible.clone())], 2 * total);
+                let candidates = subjects.into_iter().filter_map(|inst| {
+                    let right = selectors::Card {
+                        difficulty: right.difficulty,
+                        stats: vec![],
+                        pairing: Some(selectors::PairingNested {
+                            left: inst.index,
+                            which: *pairing_id,
+                        }),
+                    };
+                    right.select(deck, &[]).map(|inst2| (inst, inst2))
+                });
+                let edge_infos = &deck.pairings[*pairing_id].edge_infos;
+                // a Card on both sides would match itself in a symmetric
+                // pairing
+                let pairs = unambiguous(candidates, total, |l, r| {
+                    l.index == r.index || edge_infos.contains_key(&(l.index, r.index))
+                });
+                if pairs.len() < total {
+                    return Err(ErrorKind::NotEnoughData(params.total).into());
+                }
+                let (left, right, expectations) = transform_matching(
+                    pairs,
+                    |id, inst: instances::Card| TriviaAnswer {
+                        id,
+                        answer: deck.data.cards[inst.index].title.clone(),
+                        question_value: String::new().into(),
+                    },
+                    |id, inst: instances::Card| TriviaAnswer {
+                        id,
+                        answer: deck.data.cards[inst.index].title.clone(),
+                        question_value: inst.pairing_info.unwrap_or_default().into(),
+                    },
+                );
+                let question = common.question_format.clone();
+                let trivia = Trivia::new_matching(params, question, left, right);
+                Ok((trivia, expectations))
+            }
+            MatchingDef::CardTag {
+                left,
+                right,
+                eligible,
+                params,
+            } => {
+                let total: usize = params.total.into();
+                let subjects =
+                    left.select_n(deck, &[CardCond::Eligible(eligible.clone())], 2 * total);
+                let candidates = subjects.into_iter().filter_map(|inst| {
+                    right
+                        .select(deck, &[TagCond::Edge(inst.index)])
+                        .map(|tag| (inst, tag))
+                });
+                let pairs = unambiguous(candidates, total, |l, r| {
+                    deck.data.tag_defs[r.which].values[l.index].contains(&r.value)
+                });
+                if pairs.len() < total {
+                    return Err(ErrorKind::NotEnoughData(params.total).into());
+                }
+                let (left, right, expectations) = transform_matching(
+                    pairs,
+                    |id, inst: instances::Card| TriviaAnswer {
+                        id,
+                        answer: deck.data.cards[inst.index].title.clone(),
+                        question_value: String::new().into(),
+                    },
+                    |id, inst: instances::Tag| TriviaAnswer {
+                        id,
+                        answer: inst.value,
+                        question_value: String::new().into(),
+                    },
+                );
+                let question = common.question_format.clone();
+                let trivia = Trivia::new_matching(params, question, left, right);
+                Ok((trivia, expectations))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unambiguous() {
+        // left i matches right j when j is a multiple of i
+        let candidates = [(2, 4), (3, 6), (2, 8), (5, 5), (7, 7)].into_iter();
+        let pairs = unambiguous(candidates, 3, |l, r| r % l == 0);
+        assert_eq!(pairs, vec![(2, 4), (5, 5), (7, 7)]);
+
+        let pairs = unambiguous([(1, 1), (2, 2)].into_iter(), 2, |l, r| r % l == 0);
+        assert_eq!(pairs, vec![(1, 1)]);
+    }
+
+    #[test]
+    fn test_transform_matching() {
+        let pairs = vec![("a", "A"), ("b", "B"), ("c", "C"), ("d", "D")];
+        let answer = |id, s: &str| TriviaAnswer {
+            id,
+            answer: s.into(),
+            question_value: String::new().into(),
+        };
+        let (left, right, exps) = transform_matching(pairs, answer, answer);
+        let [TriviaExp::Permutation { ids }] = &exps[..] else {
+            panic!("expected a single Permutation, got {:?}", exps);
+        };
+        let left_ids: Vec<_> = left.iter().map(|ans| ans.id).collect();
+        let right_ids: Vec<_> = right.iter().map(|ans| ans.id).collect();
+        assert_eq!(left_ids, vec![0, 1, 2, 3]);
+        assert_eq!(right_ids, vec![0, 1, 2, 3]);
+        for (l, id) in left.iter().zip(ids) {
+            assert_eq!(right[*id as usize].answer, l.answer.to_uppercase());
+        }
+    }
+}
@@ -6,26 +6,39 @@ use crate::{
     probability::ReservoirSample,
     tinylang::{self, expr},
     trivia::types::SanityCheck,
-    types::{Card, Deck},
+    types::{Card, CardTable, Deck},
 };
 
 mod engine;
+mod estimation;
+mod free_text;
+mod geo;
 mod hangman;
+mod matching;
 mod multiple_choice;
 mod ranking;
 mod types;
 
+pub use estimation::estimate_credits;
+pub use free_text::grade_text;
+pub use geo::location_credit;
+pub use ranking::order_credit;
 pub use types::{
-    ActiveDeck, ActivePairing, DeckFeatureSet, GradeableTrivia, QValue, Trivia, TriviaAnswer,
-    TriviaAnswerType, TriviaDefCommon, TriviaExp,
+    ActiveDeck, ActivePairing, Availability, Continuation, DeckFeatureSet, Evaluation,
+    EvaluationRow, GradeableTrivia, QValue, Trivia, TriviaAnswer, TriviaAnswerType,
+    TriviaDefCommon, TriviaExp,
 };
 
 use self::{
-    engine::TriviaGen,
+    engine::{CardCond, TriviaGen},
+    estimation::{EstimationCommon, EstimationDef, Tolerance},
+    free_text::{FreeTextCommon, FreeTextDef},
+    geo::{GeoDef, LocateCommon},
     hangman::{HangmanCommon, HangmanDef},
-    multiple_choice::{MultipleChoiceCommon, MultipleChoiceDef},
+    matching::{MatchingCommon, MatchingDef},
+    multiple_choice::{MultipleChoiceCommon, MultipleChoiceDef, Pivot},
     ranking::{RankingCommon, RankingDef},
-    types::{selectors, RankingType, StatAxisMod},
+    types::{selectors, MapAnnotation, RankingType, StatAnnotation, StatAxisMod},
 };
 
 pub fn scale_popularity(deck: &mut Deck) {
@@ -80,13 +93,13 @@ error_chain! {
             description("invalid Pairing name")
             display("invalid Pairing name: {}", nm)
         }
-        TinylangSyntaxError(src: String, msg: String) {
+        TinylangSyntaxError(src: String, errors: Vec<tinylang::Diagnostic>) {
             description("syntax error")
-            display("syntax error in {:?}: {}", src, msg)
+            display("syntax error in {:?}:\n{}", src, tinylang::render_all(src, errors))
         }
-        TinylangTypeError(src: String, msg: String) {
+        TinylangTypeError(src: String, error: tinylang::Diagnostic) {
             description("type error")
-            display("type error in {:?}: {}", src, msg)
+            display("type error in {:?}:\n{}", src, error.render(src))
         }
         // generation-time
         InvalidTriviaDefId(id: usize) {
@@ -100,6 +113,12 @@ error_chain! {
         NotPlural {
             description("a card can only have 1 stat in a particular column")
         }
+        NoFollowUp {
+            description("the TriviaDef can't follow up on the continuation")
+        }
+        StaleContinuation {
+            description("the deck changed since the continuation was made")
+        }
     }
 }
 
@@ -108,6 +127,89 @@ pub struct KnowledgeBase {
     pub trivia_defs: Vec<TriviaDef>,
 }
 
+/// How many rows or pairs `evaluate_expression` may visit in one call.
+const MAX_EVALUATED: usize = 250_000;
+
+/// Evaluate an expression for every enabled Card of the deck data, or
+/// every pair of distinct enabled Cards if the expression or the filter reads
+/// both sides. Rows where the filter isn't true, or the value is nil,
+/// are skipped. At most `MAX_EVALUATED` rows or pairs are visited, so a
+/// filter that rarely holds can return fewer than `limit` rows. Takes the
+/// data rather than a deck id, so the caller can copy it out of the
+/// KnowledgeBase and release it before a long evaluation.
+pub fn evaluate_expression(
+    data: &CardTable,
+    src: &str,
+    filter_src: Option<&str>,
+    limit: usize,
+) -> Result<Evaluation> {
+    let expression = TriviaDef::_parse_expression(src)?;
+    let program = TriviaDef::_compile_expression(data, src, &expression)?;
+    let mut sides = expression.sides();
+    let filter = match filter_src {
+        Some(filter_src) => {
+            let filter = TriviaDef::_parse_expression(filter_src)?;
+            let filter_program = TriviaDef::_compile_expression(data, filter_src, &filter)?;
+            let return_type = filter_program.get_type();
+            if !matches!(return_type, tinylang::ExprType::Bool) {
+                return Err(ErrorKind::Msg(format!(
+                    "expected Bool expression for filter, got {:?}",
+                    return_type
+                ))
+                .into());
+            }
+            let filter_sides = filter.sides();
+            sides = (sides.0 || filter_sides.0, sides.1 || filter_sides.1);
+            Some(filter_program)
+        }
+        None => None,
+    };
+
+    let bound = program.bind(data, data);
+    let bound_filter = filter.as_ref().map(|f| f.bind(data, data));
+    let cards = &data.cards;
+    let enabled: Vec<usize> = (0..cards.len())
+        .filter(|&i| !cards[i].is_disabled)
+        .collect();
+    // with one side read, the same index stands in for the other
+    let pairs = sides == (true, true);
+    let mut rows = vec![];
+    let mut visited = 0;
+    'outer: for &i in enabled.iter() {
+        let others = if pairs {
+            &enabled[..]
+        } else {
+            std::slice::from_ref(&i)
+        };
+        for &j in others {
+            if rows.len() >= limit || visited >= MAX_EVALUATED {
+                break 'outer;
+            }
+            if pairs && i == j {
+                continue;
+            }
+            visited += 1;
+            if let Some(f) = &bound_filter {
+                if f.get_value(i, j) != Some(tinylang::OwnedExprValue::Bool(true)) {
+                    continue;
+                }
+            }
+            if let Some(value) = bound.get_value(i, j) {
+                rows.push(EvaluationRow {
+                    left_title: cards[i].title.clone(),
+                    right_title: pairs.then(|| cards[j].title.clone()),
+                    value: value.into(),
+                });
+            }
+        }
+    }
+    Ok(Evaluation {
+        source: tinylang::print(&expression),
+        return_type: program.get_type(),
+        rows,
+    })
+}
+
 impl KnowledgeBase {
     pub fn get_deck(&self, deck_id: u64) -> Option<&ActiveDeck> {
         self.decks.iter().find(|d| d.id == deck_id)
@@ -141,6 +243,12 @@ impl KnowledgeBase {
         Ok(cards.into_iter().cloned().collect())
     }
 
+    /// The columns an expression reads from the deck, and its type
+    pub fn analyze_expression(&self, deck_id: u64, src: &str) -> Result<tinylang::Analysis> {
+        let deck = self.require_deck(deck_id)?;
+        Ok(tinylang::analyze(src, &deck.data, &deck.data))
+    }
+
     pub fn get_trivia(&self, trivia_def_id: usize) -> Result<GradeableTrivia> {
         let trivia_def = self
             .trivia_defs
@@ -151,6 +259,41 @@ impl KnowledgeBase {
             TriviaDef::MultipleChoice(body, common) => body.get_trivia(deck, common),
             TriviaDef::Ranking(body, common) => body.get_trivia(deck, common),
             TriviaDef::Hangman(body, common) => body.get_trivia(deck, common),
+            TriviaDef::FreeText(body, common) => body.get_trivia(deck, common),
+            TriviaDef::Estimation(body, common) => body.get_trivia(deck, common),
+            TriviaDef::Matching(body, common) => body.get_trivia(deck, common),
+            TriviaDef::Geo(body, common) => body.get_trivia(deck, common),
+        }
+    }
+
+    /// A Trivia about the subject of an earlier one, from a TriviaDef of the
+    /// same deck
+    pub fn get_follow_up(
+        &self,
+        trivia_def_id: usize,
+        continuation: &Continuation,
+    ) -> Result<GradeableTrivia> {
+        let trivia_def = self
+            .trivia_defs
+            .get(trivia_def_id)
+            .ok_or(ErrorKind::InvalidTriviaDefId(trivia_def_id))?;
+        if trivia_def.common().deck_id != continuation.deck_id {
+            return Err(ErrorKind::NoFollowUp.into());
+        }
+        let deck = self.require_deck(continuation.deck_id)?;
+        if deck.revision != continuation.revision {
+            return Err(ErrorKind::StaleContinuation.into());
+        }
+        match trivia_def {
+            TriviaDef::MultipleChoice(body, common) => {
+                body.get_follow_up(deck, common, continuation)
+            }
+            TriviaDef::Ranking(body, common) => body.get_follow_up(deck, common, continuation),
+            TriviaDef::Hangman(body, common) => body.get_follow_up(deck, common, continuation),
+            TriviaDef::FreeText(body, common) => body.get_follow_up(deck, common, continuation),
+            TriviaDef::Estimation(body, common) => body.get_follow_up(deck, common, continuation),
+            TriviaDef::Matching(body, common) => body.get_follow_up(deck, common, continuation),
+            TriviaDef::Geo(body, common) => body.get_follow_up(deck, common, continuation),
         }
     }
 }
@@ -159,6 +302,10 @@ pub enum TriviaDef {
     MultipleChoice(MultipleChoiceDef, TriviaDefCommon),
     Ranking(RankingDef, TriviaDefCommon),
     Hangman(HangmanDef, TriviaDefCommon),
+    FreeText(FreeTextDef, TriviaDefCommon),
+    Estimation(EstimationDef, TriviaDefCommon),
+    Matching(MatchingDef, TriviaDefCommon),
+    Geo(GeoDef, TriviaDefCommon),
 }
 
 impl TriviaDef {
@@ -167,20 +314,50 @@ impl TriviaDef {
             TriviaDef::MultipleChoice(_, v) => v,
             TriviaDef::Ranking(_, v) => v,
             TriviaDef::Hangman(_, v) => v,
+            TriviaDef::FreeText(_, v) => v,
+            TriviaDef::Estimation(_, v) => v,
+            TriviaDef::Matching(_, v) => v,
+            TriviaDef::Geo(_, v) => v,
         }
     }
 
+    fn _parse_expression(expr_src: &str) -> Result<tinylang::Expression> {
+        expr(expr_src)
+            .map_err(|errors| ErrorKind::TinylangSyntaxError(expr_src.into(), errors).into())
+    }
+
+    fn _compile_expression(
+        data: &CardTable,
+        expr_src: &str,
+        expression: &tinylang::Expression,
+    ) -> Result<tinylang::Program> {
+        expression
+            .compile(data, data)
+            .map_err(|error| ErrorKind::TinylangTypeError(expr_src.into(), error).into())
+    }
+
+    /// Parse and compile the expression once, so that every `get_trivia`
+    /// call reuses the program
     fn _expression_exprtype(
         deck: &ActiveDeck,
         expr_src: &str,
-    ) -> Result<(tinylang::Expression, tinylang::ExprType)> {
-        let expression =
-            expr(expr_src).map_err(|msg| ErrorKind::TinylangSyntaxError(expr_src.into(), msg))?;
-        let return_type = expression
-            .optimize(&deck.data, &deck.data)
-            .map_err(|msg| ErrorKind::TinylangTypeError(expr_src.into(), msg))?
-            .get_type();
-        Ok((expression, return_type))
+    ) -> Result<(tinylang::Program, tinylang::ExprType)> {
+        let expression = Self::_parse_expression(expr_src)?;
+        let program = Self::_compile_expression(&deck.data, expr_src, &expression)?;
+        let return_type = program.get_type();
+        Ok((program, return_type))
+    }
+
+    fn _score_expression(deck: &ActiveDeck, score_src: &str) -> Result<tinylang::Program> {
+        let (expression, return_type) = Self::_expression_exprtype(deck, score_src)?;
+        if !matches!(return_type, tinylang::ExprType::Number) {
+            return Err(ErrorKind::Msg(format!(
+                "expected Number expression for score, got {:?}",
+                return_type
+            ))
+            .into());
+        }
+        Ok(expression)
     }
 
     pub fn create_multiple_choice_card_stat(
@@ -236,9 +413,11 @@ impl TriviaDef {
             difficulty: difficulties.1,
             which: tag_id,
         };
+        let eligible = CardCond::eligible(deck, &[CardCond::TagOut(tag_id)])?;
         let body = MultipleChoiceDef::CardTag {
             left,
             right,
+            eligible,
             params,
         };
         Ok(TriviaDef::MultipleChoice(body, common))
@@ -269,6 +448,7 @@ impl TriviaDef {
         Ok(TriviaDef::MultipleChoice(body, common))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_multiple_choice_pairing(
         base: &KnowledgeBase,
         common: TriviaDefCommon,
@@ -276,12 +456,15 @@ impl TriviaDef {
         difficulties: (f64, f64),
         pairing_name: &str,
         maybe_predicate_src: Option<&str>,
+        maybe_score_src: Option<&str>,
         separator: char,
     ) -> Result<Self> {
         params.sanity_check()?;
         let deck = base.require_deck(common.deck_id)?;
-        let predicate = if let Some(predicate_src) = maybe_predicate_src {
-            let (expression, return_type) = Self::_expression_exprtype(deck, predicate_src)?;
+        let (predicate, predicate_out) = if let Some(predicate_src) = maybe_predicate_src {
+            let expression = Self::_parse_expression(predicate_src)?;
+            let program = Self::_compile_expression(&deck.data, predicate_src, &expression)?;
+            let return_type = program.get_type();
             if !matches!(return_type, tinylang::ExprType::Bool) {
                 return Err(ErrorKind::Msg(format!(
                     "expected Bool expression, got {:?}",
@@ -289,13 +472,31 @@ impl TriviaDef {
                 ))
                 .into());
             }
-            Some(expression)
+            let predicate_out = expression
+                .split_conjunction()
+                .left
+                .map(|left| Self::_compile_expression(&deck.data, predicate_src, &left))
+                .transpose()?;
+            (Some(program), predicate_out)
         } else {
-            None
+            (None, None)
         };
+        let score = maybe_score_src
+            .map(|score_src| Self::_score_expression(deck, score_src))
+            .transpose()?;
         let pairing_id = deck
             .get_pairing_index(pairing_name)
             .ok_or_else(|| ErrorKind::InvalidPairingName(pairing_name.into()))?;
+        let eligible_t = CardCond::eligible(deck, &[CardCond::EdgeOut(pairing_id)])?;
+        let mut lconds: Vec<_> = predicate
+            .iter()
+            .chain(score.iter())
+            .map(|e| CardCond::ExpressionOut(e.clone()))
+            .collect();
+        // Left Cards that fail the left-only part of the predicate can't be
+        // paired with any right Card
+        lconds.extend(predicate_out.map(CardCond::PredicateOut));
+        let eligible_f = CardCond::eligible(deck, &lconds)?;
         let left = selectors::Card::new(difficulties.0);
         let right = selectors::Card::new(difficulties.1);
         let body = MultipleChoiceDef::Pairing {
@@ -304,11 +505,216 @@ impl TriviaDef {
             separator,
             pairing_id,
             predicate,
+            eligible_t,
+            eligible_f,
+            score,
+            params,
+        };
+        Ok(TriviaDef::MultipleChoice(body, common))
+    }
+
+    /// Which of `params.total` Cards lacks the Tag the others share. The
+    /// question format gets the label of the tag definition.
+    pub fn create_multiple_choice_odd_one_out(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MultipleChoiceCommon,
+        difficulties: (f64, f64),
+        tag_name: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        if params.min_true != 1 || params.max_true != 1 {
+            return Err(ErrorKind::Msg("min_true = max_true = 1".into()).into());
+        }
+        let deck = base.require_deck(common.deck_id)?;
+        let tag_id = deck
+            .get_tag_index(tag_name)
+            .ok_or_else(|| ErrorKind::InvalidTagName(tag_name.into()))?;
+        let left = selectors::Tag {
+            difficulty: difficulties.0,
+            which: tag_id,
+        };
+        let right = selectors::Card::new(difficulties.1);
+        let body = MultipleChoiceDef::OddOneOut {
+            left,
+            right,
+            params,
+        };
+        Ok(TriviaDef::MultipleChoice(body, common))
+    }
+
+    /// Like `create_multiple_choice_odd_one_out`, with Categories instead of
+    /// Tags
+    pub fn create_multiple_choice_odd_one_out_category(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MultipleChoiceCommon,
+        difficulties: (f64, f64),
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        if params.min_true != 1 || params.max_true != 1 {
+            return Err(ErrorKind::Msg("min_true = max_true = 1".into()).into());
+        }
+        base.require_deck(common.deck_id)?;
+        let left = selectors::Category {
+            difficulty: difficulties.0,
+        };
+        let right = selectors::Card::new(difficulties.1);
+        let body = MultipleChoiceDef::OddOneOutCategory {
+            left,
+            right,
+            params,
+        };
+        Ok(TriviaDef::MultipleChoice(body, common))
+    }
+
+    pub fn create_multiple_choice_threshold(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MultipleChoiceCommon,
+        difficulties: (f64, f64),
+        stat_expr_src: &str,
+        pivot: Pivot,
+        is_below: bool,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        if let Pivot::Rounded { step } = pivot {
+            if !(step.is_finite() && step > 0.0) {
+                return Err(ErrorKind::Msg("step > 0".into()).into());
+            }
+        }
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, return_type) = Self::_expression_exprtype(deck, stat_expr_src)?;
+        if !matches!(
+            return_type,
+            tinylang::ExprType::Number | tinylang::ExprType::Date
+        ) {
+            return Err(ErrorKind::Msg(format!(
+                "expected Number or Date expression, got {:?}",
+                return_type
+            ))
+            .into());
+        }
+        let left = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr.clone(),
+            return_type,
+        };
+        let right = selectors::Stat {
+            difficulty: difficulties.1,
+            expression: stat_expr,
+            return_type,
+        };
+        let body = MultipleChoiceDef::Threshold {
+            left,
+            right,
+            pivot,
+            is_below,
             params,
         };
         Ok(TriviaDef::MultipleChoice(body, common))
     }
 
+    /// A LatLng expression, and the area of the map its values span
+    fn _location_expression(
+        deck: &ActiveDeck,
+        stat_expr_src: &str,
+    ) -> Result<(tinylang::Program, MapAnnotation)> {
+        let (stat_expr, return_type) = Self::_expression_exprtype(deck, stat_expr_src)?;
+        if !matches!(return_type, tinylang::ExprType::LatLng) {
+            return Err(ErrorKind::Msg(format!(
+                "expected LatLng expression, got {:?}",
+                return_type
+            ))
+            .into());
+        }
+        let map = geo::map_bounds(deck, &stat_expr).ok_or(ErrorKind::NotEnoughData(1))?;
+        Ok((stat_expr, map))
+    }
+
+    pub fn create_geo_extreme(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MultipleChoiceCommon,
+        difficulties: (f64,),
+        stat_expr_src: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        if params.min_true != 1 || params.max_true != 1 || params.total < 2 {
+            return Err(ErrorKind::Msg("min_true = max_true = 1 < total".into()).into());
+        }
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, map) = Self::_location_expression(deck, stat_expr_src)?;
+        let selector = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr,
+            return_type: tinylang::ExprType::LatLng,
+        };
+        let body = GeoDef::Extreme {
+            selector,
+            map,
+            params,
+        };
+        Ok(TriviaDef::Geo(body, common))
+    }
+
+    pub fn create_geo_within(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MultipleChoiceCommon,
+        difficulties: (f64, f64),
+        stat_expr_src: &str,
+        radius_km: f64,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        if !(radius_km.is_finite() && radius_km > 0.0) {
+            return Err(ErrorKind::Msg("radius_km > 0".into()).into());
+        }
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, map) = Self::_location_expression(deck, stat_expr_src)?;
+        let left = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr.clone(),
+            return_type: tinylang::ExprType::LatLng,
+        };
+        let right = selectors::Stat {
+            difficulty: difficulties.1,
+            expression: stat_expr,
+            return_type: tinylang::ExprType::LatLng,
+        };
+        let body = GeoDef::Within {
+            left,
+            right,
+            radius_km,
+            map,
+            params,
+        };
+        Ok(TriviaDef::Geo(body, common))
+    }
+
+    pub fn create_geo_locate(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: LocateCommon,
+        difficulties: (f64,),
+        stat_expr_src: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, map) = Self::_location_expression(deck, stat_expr_src)?;
+        let selector = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr,
+            return_type: tinylang::ExprType::LatLng,
+        };
+        let body = GeoDef::Locate {
+            selector,
+            map,
+            params,
+        };
+        Ok(TriviaDef::Geo(body, common))
+    }
+
     pub fn create_ranking_card(
         base: &KnowledgeBase,
         common: TriviaDefCommon,
@@ -354,6 +760,7 @@ impl TriviaDef {
         params: RankingCommon,
         difficulties: (f64, f64),
         stat_expr_src: &str,
+        maybe_score_src: Option<&str>,
         separator: char,
     ) -> Result<Self> {
         params.sanity_check()?;
@@ -369,6 +776,16 @@ impl TriviaDef {
             ))
             .into());
         }
+        let score = maybe_score_src
+            .map(|score_src| Self::_score_expression(deck, score_src))
+            .transpose()?;
+        let lconds: Vec<_> = std::iter::once(&stat_expr)
+            .chain(score.iter())
+            .map(|e| CardCond::ExpressionOut(e.clone()))
+            .collect();
+        let eligible_left = CardCond::eligible(deck, &lconds)?;
+        let eligible_right =
+            CardCond::eligible(deck, &[CardCond::ExpressionIn(stat_expr.clone())])?;
         let left = selectors::Card::new(difficulties.0);
         let right = selectors::Card::new(difficulties.1);
         let stat = selectors::StatNested {
@@ -379,12 +796,61 @@ impl TriviaDef {
             left,
             right,
             stat,
+            score,
+            eligible_left,
+            eligible_right,
             separator,
             params,
         };
         Ok(TriviaDef::Ranking(body, common))
     }
 
+    /// Rank values of a Tag by how many Cards have them, like films by the
+    /// number of Cards for the actors in them
+    pub fn create_ranking_tag_count(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: RankingCommon,
+        difficulties: (f64,),
+        tag_name: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let tag_id = deck
+            .get_tag_index(tag_name)
+            .ok_or_else(|| ErrorKind::InvalidTagName(tag_name.into()))?;
+        let left = selectors::Tag {
+            difficulty: difficulties.0,
+            which: tag_id,
+        };
+        let body = RankingDef::TagCount { left, params };
+        Ok(TriviaDef::Ranking(body, common))
+    }
+
+    /// Rank Cards by how many Cards they are paired with
+    pub fn create_ranking_pairing_degree(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: RankingCommon,
+        difficulties: (f64,),
+        pairing_name: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let pairing_id = deck
+            .get_pairing_index(pairing_name)
+            .ok_or_else(|| ErrorKind::InvalidPairingName(pairing_name.into()))?;
+        let eligible = CardCond::eligible(deck, &[CardCond::EdgeOut(pairing_id)])?;
+        let left = selectors::Card::new(difficulties.0);
+        let body = RankingDef::PairingDegree {
+            left,
+            pairing_id,
+            eligible,
+            params,
+        };
+        Ok(TriviaDef::Ranking(body, common))
+    }
+
     pub fn create_hangman_card(
         base: &KnowledgeBase,
         common: TriviaDefCommon,
@@ -439,6 +905,176 @@ impl TriviaDef {
         let body = HangmanDef::Stat { selector, params };
         Ok(TriviaDef::Hangman(body, common))
     }
+
+    /// Like `create_hangman_card`, but the title is typed. The values of the
+    /// Card for the `alias_tag_name` Tag are accepted too.
+    pub fn create_free_text_card(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: FreeTextCommon,
+        difficulties: (f64,),
+        stat_expr_src: &str,
+        alias_tag_name: Option<&str>,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, return_type) = Self::_expression_exprtype(deck, stat_expr_src)?;
+        if !matches!(
+            return_type,
+            tinylang::ExprType::String | tinylang::ExprType::StringArray
+        ) {
+            return Err(ErrorKind::Msg(format!(
+                "expected String or StringArray expression, got {:?}",
+                return_type
+            ))
+            .into());
+        }
+        let aliases = alias_tag_name
+            .map(|tag_name| {
+                deck.get_tag_index(tag_name)
+                    .ok_or_else(|| ErrorKind::InvalidTagName(tag_name.into()))
+            })
+            .transpose()?;
+        let selector = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr,
+            return_type,
+        };
+        let body = FreeTextDef::Card {
+            selector,
+            aliases,
+            params,
+        };
+        Ok(TriviaDef::FreeText(body, common))
+    }
+
+    pub fn create_free_text_stat(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: FreeTextCommon,
+        difficulties: (f64,),
+        stat_expr_src: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, return_type) = Self::_expression_exprtype(deck, stat_expr_src)?;
+        if !matches!(return_type, tinylang::ExprType::String) {
+            return Err(ErrorKind::Msg(format!(
+                "expected String expression, got {:?}",
+                return_type
+            ))
+            .into());
+        }
+        let selector = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr,
+            return_type,
+        };
+        let body = FreeTextDef::Stat { selector, params };
+        Ok(TriviaDef::FreeText(body, common))
+    }
+
+    pub fn create_estimation(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        mut params: EstimationCommon,
+        difficulties: (f64,),
+        stat_expr_src: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let (stat_expr, return_type) = Self::_expression_exprtype(deck, stat_expr_src)?;
+        if !matches!(
+            return_type,
+            tinylang::ExprType::Number | tinylang::ExprType::Date
+        ) {
+            return Err(ErrorKind::Msg(format!(
+                "expected Number or Date expression, got {:?}",
+                return_type
+            ))
+            .into());
+        }
+        let mut annotation = params.stat_annotation.unwrap_or(StatAnnotation {
+            axis_mod: None,
+            axis_min: None,
+            axis_max: None,
+        });
+        if annotation.axis_min.is_none() || annotation.axis_max.is_none() {
+            let bound = stat_expr.bind(&deck.data, &deck.data);
+            let numbers: Vec<f64> = (0..deck.data.cards.len())
+                .filter(|&i| !deck.data.cards[i].is_disabled)
+                .filter_map(|i| bound.get_value(i, i))
+                .filter_map(|value| estimation::estimated_number(&value))
+                .collect();
+            if numbers.is_empty() {
+                return Err(ErrorKind::NotEnoughData(1).into());
+            }
+            let min = numbers.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = numbers.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            annotation.axis_min = annotation.axis_min.or(Some(min));
+            annotation.axis_max = annotation.axis_max.or(Some(max));
+        }
+        params.stat_annotation = Some(annotation);
+        let selector = selectors::Stat {
+            difficulty: difficulties.0,
+            expression: stat_expr,
+            return_type,
+        };
+        let body = EstimationDef::Stat { selector, params };
+        Ok(TriviaDef::Estimation(body, common))
+    }
+
+    pub fn create_matching_pairing(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MatchingCommon,
+        difficulties: (f64, f64),
+        pairing_name: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let pairing_id = deck
+            .get_pairing_index(pairing_name)
+            .ok_or_else(|| ErrorKind::InvalidPairingName(pairing_name.into()))?;
+        let eligible = CardCond::eligible(deck, &[CardCond::EdgeOut(pairing_id)])?;
+        let left = selectors::Card::new(difficulties.0);
+        let right = selectors::Card::new(difficulties.1);
+        let body = MatchingDef::Pairing {
+            left,
+            right,
+            pairing_id,
+            eligible,
+            params,
+        };
+        Ok(TriviaDef::Matching(body, common))
+    }
+
+    pub fn create_matching_card_tag(
+        base: &KnowledgeBase,
+        common: TriviaDefCommon,
+        params: MatchingCommon,
+        difficulties: (f64, f64),
+        tag_name: &str,
+    ) -> Result<Self> {
+        params.sanity_check()?;
+        let deck = base.require_deck(common.deck_id)?;
+        let tag_id = deck
+            .get_tag_index(tag_name)
+            .ok_or_else(|| ErrorKind::InvalidTagName(tag_name.into()))?;
+        let left = selectors::Card::new(difficulties.0);
+        let right = selectors::Tag {
+            difficulty: difficulties.1,
+            which: tag_id,
+        };
+        let eligible = CardCond::eligible(deck, &[CardCond::TagOut(tag_id)])?;
+        let body = MatchingDef::CardTag {
+            left,
+            right,
+            eligible,
+            params,
+        };
+        Ok(TriviaDef::Matching(body, common))
+    }
 }
 
 pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
@@ -447,6 +1083,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 6,
             question_format: "Which movie was directed by {}?".into(),
+            availability: Availability::Rotation,
         },
         MultipleChoiceCommon::typical(4),
         (0.0, -1.5),
@@ -458,6 +1095,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 6,
             question_format: "Who directed {}?".into(),
+            availability: Availability::Rotation,
         },
         MultipleChoiceCommon::typical(4),
         (0.0, -1.0),
@@ -470,6 +1108,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
             deck_id: 6,
             question_format: "Rank these {} movies from highest to lowest Letterboxd rating."
                 .into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::new(RankingType::Desc, 3, Some((0.0, 5.0).into())),
         (-1.5,),
@@ -482,6 +1121,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 4,
             question_format: "Rank these songs from most to least Spotify plays.".into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::typical(RankingType::Desc, 3),
         (-0.75,),
@@ -494,6 +1134,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 3,
             question_format: "Rank these people from most to least popular on Wikipedia.".into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::typical(RankingType::Desc, 3),
         (-1.625,),
@@ -506,6 +1147,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 3,
             question_format: "Rank these people from oldest to youngest.".into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::new(RankingType::Desc, 3, Some(StatAxisMod::Age.into())),
         (-1.625,),
@@ -518,6 +1160,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 3,
             question_format: "Pick the fake couple.".into(),
+            availability: Availability::Rotation,
         },
         MultipleChoiceCommon {
             min_true: 3,
@@ -528,6 +1171,13 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         (0.0, -1.0),
         "Couple",
         Some("L\"Card\" != R\"Card\" and L\"Pronoun\" == R\"Partner pronoun\" and R\"Pronoun\" == L\"Partner pronoun\""),
+        Some(
+            r#"{
+              let b1: Date = L"Birth date"
+              let b2: Date = R"Birth date"
+              return 1 / (1 + ((b2 - b1) / 3652.5) ** 2)
+            }"#,
+        ),
         '+',
     )?;
     base.trivia_defs.push(trivia_def);
@@ -536,8 +1186,9 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 3,
             question_format: "Who is this:\n{}".into(),
+            availability: Availability::Rotation,
         },
-        HangmanCommon { lives: 1 },
+        HangmanCommon::typical(1),
         (-1.0,),
         "R\"Description\"",
     )?;
@@ -547,6 +1198,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 2,
             question_format: "Rank these places from most to least popular on Wikipedia.".into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::typical(RankingType::Desc, 3),
         (-2.25,),
@@ -559,6 +1211,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 2,
             question_format: "Rank these places by population (highest first).".into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::typical(RankingType::Desc, 3),
         (-1.625,),
@@ -571,10 +1224,12 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 2,
             question_format: "Pick the closest pair of cities geographically.".into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::new(RankingType::Min, 3, Some(StatAxisMod::Distance.into())),
         (-1.25, -1.25),
         "L\"Coordinates\" <-> R\"Coordinates\"",
+        None,
         '↔',
     )?;
     base.trivia_defs.push(trivia_def);
@@ -583,8 +1238,9 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         TriviaDefCommon {
             deck_id: 2,
             question_format: "What is the capital of {}?".into(),
+            availability: Availability::Rotation,
         },
-        HangmanCommon { lives: 1 },
+        HangmanCommon::typical(1),
         (-1.0,),
         "R\"Capital\"",
     )?;
@@ -595,6 +1251,7 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
             deck_id: 1,
             question_format: "Rank these characters from most to fewest fanfiction works on AO3."
                 .into(),
+            availability: Availability::Rotation,
         },
         RankingCommon::typical(RankingType::Desc, 3),
         (-1.75,),
@@ -602,14 +1259,254 @@ pub fn seed(base: &mut KnowledgeBase) -> Result<()> {
         "R\"AO3 fanfics\"",
     )?;
     base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_free_text_stat(
+        base,
+        TriviaDefCommon {
+            deck_id: 2,
+            question_format: "Type the capital of {}.".into(),
+            availability: Availability::Preview,
+        },
+        FreeTextCommon { max_typos: 2 },
+        (-1.5,),
+        "R\"Capital\"",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_free_text_card(
+        base,
+        TriviaDefCommon {
+            deck_id: 3,
+            question_format: "Type the name of this person:\n{}".into(),
+            availability: Availability::Preview,
+        },
+        FreeTextCommon { max_typos: 2 },
+        (-1.0,),
+        "R\"Description\"",
+        None,
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_estimation(
+        base,
+        TriviaDefCommon {
+            deck_id: 2,
+            question_format: "What is the population of {}?".into(),
+            availability: Availability::Preview,
+        },
+        EstimationCommon {
+            tolerance: Tolerance::Relative(vec![0.1, 0.25, 0.5]),
+            stat_annotation: None,
+        },
+        (-1.625,),
+        "R\"Population\"",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_estimation(
+        base,
+        TriviaDefCommon {
+            deck_id: 3,
+            question_format: "In what year was {} born?".into(),
+            availability: Availability::Preview,
+        },
+        EstimationCommon {
+            tolerance: Tolerance::Absolute(vec![1.0, 5.0, 10.0]),
+            stat_annotation: None,
+        },
+        (-1.625,),
+        "R\"Birth date\"",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_matching_card_tag(
+        base,
+        TriviaDefCommon {
+            deck_id: 6,
+            question_format: "Match each movie to its director.".into(),
+            availability: Availability::Preview,
+        },
+        MatchingCommon { total: 4 },
+        (-1.0, 0.0),
+        "Director",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_matching_pairing(
+        base,
+        TriviaDefCommon {
+            deck_id: 3,
+            question_format: "Match each person to their partner.".into(),
+            availability: Availability::Preview,
+        },
+        MatchingCommon { total: 4 },
+        (-1.0, 0.0),
+        "Couple",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_multiple_choice_odd_one_out(
+        base,
+        TriviaDefCommon {
+            deck_id: 6,
+            question_format: "Which movie doesn't share a {} with the others?".into(),
+            availability: Availability::Rotation,
+        },
+        MultipleChoiceCommon::typical(3),
+        (-1.0, -1.0),
+        "Director",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_multiple_choice_odd_one_out_category(
+        base,
+        TriviaDefCommon {
+            deck_id: 1,
+            question_format: "Which character doesn't belong with the others?".into(),
+            availability: Availability::Rotation,
+        },
+        MultipleChoiceCommon::typical(4),
+        (0.0, -1.0),
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_ranking_tag_count(
+        base,
+        TriviaDefCommon {
+            deck_id: 6,
+            question_format: "Rank these directors from most to fewest movies in the deck.".into(),
+            availability: Availability::Rotation,
+        },
+        RankingCommon::typical(RankingType::Desc, 3),
+        (-1.0,),
+        "Director",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_ranking_pairing_degree(
+        base,
+        TriviaDefCommon {
+            deck_id: 3,
+            question_format: "Rank these people from most to fewest partners.".into(),
+            availability: Availability::Rotation,
+        },
+        RankingCommon::typical(RankingType::Desc, 3),
+        (-1.0,),
+        "Couple",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_ranking_card(
+        base,
+        TriviaDefCommon {
+            deck_id: 3,
+            question_format: "Put these people in order of birth, oldest first.".into(),
+            availability: Availability::Preview,
+        },
+        RankingCommon::timeline(5),
+        (-1.625,),
+        false,
+        "R\"Birth date\"",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_multiple_choice_threshold(
+        base,
+        TriviaDefCommon {
+            deck_id: 3,
+            question_format: "Choose everyone born before {}.".into(),
+            availability: Availability::Rotation,
+        },
+        MultipleChoiceCommon {
+            min_true: 1,
+            max_true: 3,
+            total: 4,
+            is_inverted: false,
+        },
+        (0.0, -1.0),
+        "R\"Birth date\"",
+        Pivot::Rounded { step: 10.0 },
+        true,
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_multiple_choice_threshold(
+        base,
+        TriviaDefCommon {
+            deck_id: 2,
+            question_format: "Choose the places more populous than {}.".into(),
+            availability: Availability::Rotation,
+        },
+        MultipleChoiceCommon {
+            min_true: 1,
+            max_true: 3,
+            total: 4,
+            is_inverted: false,
+        },
+        (-1.0, -1.0),
+        "R\"Population\"",
+        Pivot::Card,
+        false,
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_geo_extreme(
+        base,
+        TriviaDefCommon {
+            deck_id: 2,
+            question_format: "Which of these places is furthest north?".into(),
+            availability: Availability::Preview,
+        },
+        MultipleChoiceCommon::typical(4),
+        (-1.0,),
+        "R\"Coordinates\"",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_geo_locate(
+        base,
+        TriviaDefCommon {
+            deck_id: 2,
+            question_format: "Click the location of {}.".into(),
+            availability: Availability::Preview,
+        },
+        LocateCommon {
+            bands_km: vec![100.0, 300.0, 1000.0],
+        },
+        (-1.625,),
+        "R\"Coordinates\"",
+    )?;
+    base.trivia_defs.push(trivia_def);
+    let trivia_def = TriviaDef::create_geo_within(
+        base,
+        TriviaDefCommon {
+            deck_id: 2,
+            question_format: "Which of these places are within 500 km of {}?".into(),
+            availability: Availability::Preview,
+        },
+        MultipleChoiceCommon {
+            min_true: 1,
+            max_true: 3,
+            total: 4,
+            is_inverted: false,
+        },
+        (-1.0, -1.0),
+        "R\"Coordinates\"",
+        500.0,
+    )?;
+    base.trivia_defs.push(trivia_def);
+    // asked after "Who directed {}?", about the director it answered
+    let trivia_def = TriviaDef::create_multiple_choice_tag_card(
+        base,
+        TriviaDefCommon {
+            deck_id: 6,
+            question_format: "Which of these other movies did {} direct?".into(),
+            availability: Availability::FollowUp,
+        },
+        MultipleChoiceCommon::typical(4),
+        (0.0, -1.5),
+        "Director",
+    )?;
+    base.trivia_defs.push(trivia_def);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use rstest::fixture;
+    use rstest::{fixture, rstest};
+
+    use crate::{
+        importer,
+        tinylang::ExprType,
+        types::{CardTable, Deck},
+    };
 
-    use crate::{importer, types::Deck};
+    use super::{evaluate_expression, ActiveDeck, Error, ErrorKind, QValue};
 
     use super::scale_popularity;
 
@@ -638,4 +1535,55 @@ mod tests {
             })
             .collect()
     }
+
+    /// A deck of hand-written data, for tests that need particular Cards
+    pub fn active_deck(id: u64, data: CardTable) -> ActiveDeck {
+        ActiveDeck::new(Deck {
+            id,
+            revision: 0,
+            title: format!("Deck {}", id),
+            spreadsheet_id: String::new(),
+            image_url: None,
+            data,
+        })
+    }
+
+    #[rstest]
+    fn test_evaluate_expression(decks: &[Deck]) {
+        let data = &decks[4].data;
+        let cards = &data.cards;
+        let enabled = cards.iter().filter(|c| !c.is_disabled).count();
+
+        // an expression reading neither side gives one row per enabled Card
+        let evaluation = evaluate_expression(data, "1 + 1", None, 5).unwrap();
+        assert_eq!(evaluation.source, "1 + 1");
+        assert_eq!(evaluation.return_type, ExprType::Number);
+        assert_eq!(evaluation.rows.len(), enabled.min(5));
+        for row in evaluation.rows.iter() {
+            assert_eq!(row.right_title, None);
+            assert_eq!(row.value, QValue::from(2.0));
+        }
+
+        // the filter reading both sides is enough to evaluate pairs
+        let filter = r#"L"Capital" != R"Capital""#;
+        let evaluation = evaluate_expression(data, "1", Some(filter), 10).unwrap();
+        assert!(!evaluation.rows.is_empty());
+        for row in evaluation.rows.iter() {
+            assert_ne!(Some(&row.left_title), row.right_title.as_ref());
+        }
+
+        // a filter that never holds stops once the pairs visited are capped
+        let filter = r#"L"Capital" == R"Capital" and 1 > 2"#;
+        let evaluation = evaluate_expression(data, "1", Some(filter), 10).unwrap();
+        assert_eq!(evaluation.rows.len(), 0);
+
+        assert!(matches!(
+            evaluate_expression(data, r#"R"Capital" == "#, None, 10),
+            Err(Error(ErrorKind::TinylangSyntaxError(..), _))
+        ));
+        assert!(matches!(
+            evaluate_expression(data, "1", Some(r#"R"Capital""#), 10),
+            Err(Error(ErrorKind::Msg(..), _))
+        ));
+    }
 }
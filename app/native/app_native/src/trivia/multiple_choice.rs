@@ -1,16 +1,22 @@
+use std::cmp::Ordering;
+
+use chrono::{Datelike, NaiveDate};
+use rand::Rng;
 use smallvec::SmallVec;
 
 use crate::{
+    bitset::Bitset,
     probability::{Blend, ReservoirSample},
     tinylang::{self, OwnedExprValue},
     trivia::types::TriviaExp,
+    types::{CardTable, NaiveDateTimeExt},
 };
 
 use super::{
     engine::{CardCond, Select, TagCond, TriviaGen},
     types::{
-        selectors, ActiveDeck, GradeableTrivia, SanityCheck, Trivia, TriviaAnswer,
-        TriviaAnswerType, TriviaDefCommon,
+        instances, selectors, ActiveDeck, Continuation, GradeableTrivia, SanityCheck, Trivia,
+        TriviaAnswer, TriviaAnswerType, TriviaDefCommon,
     },
     ErrorKind, Result,
 };
@@ -80,6 +86,8 @@ pub enum MultipleChoiceDef {
     CardTag {
         left: selectors::Card,
         right: selectors::Tag,
+        /// The Cards with a Tag for the right tag definition
+        eligible: Bitset,
         params: MultipleChoiceCommon,
     },
     TagCard {
@@ -94,12 +102,114 @@ pub enum MultipleChoiceDef {
         pairing_id: usize,
         /// Will be satisfied by incorrect answers, which also must not be in
         /// the pairing
-        predicate: Option<tinylang::Expression>,
-        // TODO boost
+        predicate: Option<tinylang::Program>,
+        /// The left Cards in the pairing
+        eligible_t: Bitset,
+        /// The left Cards that have the variables of the predicate and score,
+        /// and satisfy the part of the predicate that only reads them
+        eligible_f: Bitset,
+        /// Number expression which multiplies the weight of each incorrect
+        /// answer. None rejects the answer
+        score: Option<tinylang::Program>,
+        params: MultipleChoiceCommon,
+    },
+    /// Pick the Card that doesn't have the Tag the others share
+    OddOneOut {
+        left: selectors::Tag,
+        right: selectors::Card,
+        params: MultipleChoiceCommon,
+    },
+    /// Pick the Card that isn't in the Category the others share
+    OddOneOutCategory {
+        left: selectors::Category,
+        right: selectors::Card,
+        params: MultipleChoiceCommon,
+    },
+    /// Pick the Cards whose stat is below the pivot, or above it if not
+    /// `is_below`
+    Threshold {
+        left: selectors::Stat,
+        right: selectors::Stat,
+        pivot: Pivot,
+        is_below: bool,
         params: MultipleChoiceCommon,
     },
 }
 
+/// Where the threshold of a MultipleChoiceDef::Threshold comes from
+#[derive(Debug, Clone, Copy)]
+pub enum Pivot {
+    /// The stat of a Card, named in the question
+    Card,
+    /// The stat of a Card rounded to a multiple of `step`, in years for Dates
+    Rounded { step: f64 },
+}
+
+/// How many pivots to try before giving up on a threshold
+const THRESHOLD_PIVOTS: usize = 4;
+
+/// The value rounded to a multiple of `step`, Dates to January 1st of a year
+/// that is a multiple of `step`
+fn round_pivot(value: &OwnedExprValue, step: f64) -> Option<OwnedExprValue> {
+    match value {
+        OwnedExprValue::Number(v) => Some(OwnedExprValue::Number((v / step).round() * step)),
+        OwnedExprValue::Date(v) => {
+            let year = ((v.year() as f64 / step).round() * step) as i32;
+            let date = NaiveDate::from_ymd_opt(year, 1, 1)?.and_hms_opt(0, 0, 0)?;
+            Some(OwnedExprValue::Date(NaiveDateTimeExt::from(date)))
+        }
+        _ => None,
+    }
+}
+
+/// How a rounded pivot reads in the question
+fn format_pivot(value: &OwnedExprValue) -> String {
+    match value {
+        OwnedExprValue::Number(v) => v.to_string(),
+        OwnedExprValue::Date(v) => v.year().to_string(),
+        _ => String::new(),
+    }
+}
+
+/// How many Tags or Categories to try before giving up on an odd one out
+const ODD_ONE_OUT_SUBJECTS: usize = 4;
+
+/// How many Cards to draw for the odd one out, of which the one most alike
+/// the others is kept
+const ODD_ONE_OUT_CANDIDATES: usize = 8;
+
+/// How many Tags the Card has in common with the Cards of the group, plus one
+/// if it shares a Category with them
+fn shared_traits(table: &CardTable, group: &[usize], i: usize) -> usize {
+    let tags: usize = table
+        .tag_defs
+        .iter()
+        .map(|td| {
+            td.values[i]
+                .iter()
+                .filter(|t| group.iter().any(|j| td.values[*j].contains(t)))
+                .count()
+        })
+        .sum();
+    let category = &table.cards[i].category;
+    let same_category =
+        category.is_some() && group.iter().any(|j| &table.cards[*j].category == category);
+    tags + usize::from(same_category)
+}
+
+/// The candidate most alike the group, the first one drawn on ties
+fn most_plausible(
+    table: &CardTable,
+    group: &[instances::Card],
+    candidates: Vec<instances::Card>,
+) -> Option<instances::Card> {
+    let group: Vec<_> = group.iter().map(|inst| inst.index).collect();
+    candidates
+        .into_iter()
+        .rev()
+        .max_by_key(|inst| shared_traits(table, &group, inst.index))
+}
+
 impl Trivia {
     pub fn new_selection(
         params: &MultipleChoiceCommon,
@@ -114,13 +224,15 @@ impl Trivia {
             max_answers: params.max_answers(),
             question_value_type,
             stat_annotation: None,
+            map_annotation: None,
+            continuation: None,
             options,
             prefilled_answers: vec![],
         }
     }
 }
 
-fn transform_multiple_choice<E, F>(
+pub(super) fn transform_multiple_choice<E, F>(
     answers_t: Vec<E>,
     answers_f: Vec<E>,
     params: &MultipleChoiceCommon,
@@ -151,6 +263,99 @@ where
     (answers, expectations)
 }
 
+/// Which of the right Tags the subject Card has
+fn card_tag_trivia(
+    deck: &ActiveDeck,
+    common: &TriviaDefCommon,
+    right: &selectors::Tag,
+    params: &MultipleChoiceCommon,
+    subj: instances::Card,
+) -> Result<GradeableTrivia> {
+    let answers_t = right.select_n(deck, &[TagCond::Edge(subj.index)], params.max_true.into());
+    if answers_t.len() < params.min_true.into() {
+        return Err(ErrorKind::NotEnoughData(params.min_true).into());
+    }
+    let answers_f = right.select_n(
+        deck,
+        &[TagCond::NoEdge(subj.index)],
+        params.max_false().into(),
+    );
+    if answers_f.len() < params.min_false().into() {
+        return Err(ErrorKind::NotEnoughData(params.min_false()).into());
+    }
+    let continuation = Continuation {
+        card: Some(subj.index),
+        tag: answers_t.first().cloned(),
+        ..Continuation::new(deck)
+    };
+    let (answers, expectations) =
+        transform_multiple_choice(answers_t, answers_f, params, |id, inst| {
+            let mut question_value = vec![];
+            if let Some(card_indices) = deck.tag_defs[inst.which].edge_sources.get(&inst.value) {
+                question_value = card_indices
+                    .iter()
+                    .map(|i| deck.data.cards[*i].title.clone())
+                    .sample(2);
+            }
+            TriviaAnswer {
+                id,
+                answer: inst.value,
+                question_value: SmallVec::from(question_value).into(),
+            }
+        });
+    let card_title = &deck.data.cards[subj.index].title;
+    let question = common.question_format.as_str().replace("{}", card_title);
+    let mut trivia =
+        Trivia::new_selection(params, question, tinylang::ExprType::StringArray, answers);
+    trivia.continuation = Some(continuation);
+    Ok((trivia, expectations))
+}
+
+/// Which of the right Cards have the subject Tag, leaving out the `other`
+/// Card
+fn tag_card_trivia(
+    deck: &ActiveDeck,
+    common: &TriviaDefCommon,
+    right: &selectors::Card,
+    params: &MultipleChoiceCommon,
+    subj: instances::Tag,
+    other: Option<usize>,
+) -> Result<GradeableTrivia> {
+    let conds: Vec<_> = std::iter::once(CardCond::Tag(subj.clone()))
+        .chain(other.map(CardCond::Other))
+        .collect();
+    let answers_t = right.select_n(deck, &conds, params.max_true.into());
+    if answers_t.len() < params.min_true.into() {
+        return Err(ErrorKind::NotEnoughData(params.min_true).into());
+    }
+    let answers_f = right.select_n(
+        deck,
+        &[CardCond::NoTag(subj.clone())],
+        params.max_false().into(),
+    );
+    if answers_f.len() < params.min_false().into() {
+        return Err(ErrorKind::NotEnoughData(params.min_false()).into());
+    }
+    let continuation = Continuation {
+        card: answers_t.first().map(|inst| inst.index),
+        tag: Some(subj.clone()),
+        ..Continuation::new(deck)
+    };
+    let (answers, expectations) =
+        transform_multiple_choice(answers_t, answers_f, params, |id, inst| TriviaAnswer {
+            id,
+            answer: deck.data.cards[inst.index].title.clone(),
+            question_value: deck.data.tag_defs[subj.which].values[inst.index]
+                .clone()
+                .into(),
+        });
+    let question = common.question_format.replace("{}", &subj.value);
+    let mut trivia =
+        Trivia::new_selection(params, question, tinylang::ExprType::StringArray, answers);
+    trivia.continuation = Some(continuation);
+    Ok((trivia, expectations))
+}
+
 impl TriviaGen for MultipleChoiceDef {
     fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia> {
         match self {
@@ -199,51 +404,21 @@ impl TriviaGen for MultipleChoiceDef {
                 );
                 let card_title = deck.data.cards[subj.0].title.as_str();
                 let question = common.question_format.replace("{}", card_title);
-                let trivia = Trivia::new_selection(params, question, tinylang::ExprType::String, answers);
+                let mut trivia =
+                    Trivia::new_selection(params, question, tinylang::ExprType::String, answers);
+                trivia.continuation = Some(Continuation::card(deck, subj.0));
                 Ok((trivia, expectations))
             }
             MultipleChoiceDef::CardTag {
                 left,
                 right,
+                eligible,
                 params,
             } => {
                 let subj = left
-                    .select(deck, &[CardCond::TagOut(right.which)])
+                    .select(deck, &[CardCond::Eligible(eligible.clone())])
                     .ok_or_else(|| ErrorKind::NotEnoughData(1))?;
-                let answers_t =
-                    right.select_n(deck, &[TagCond::Edge(subj.index)], params.max_true.into());
-                if answers_t.len() < params.min_true.into() {
-                    return Err(ErrorKind::NotEnoughData(params.min_true).into());
-                }
-                let answers_f = right.select_n(
-                    deck,
-                    &[TagCond::NoEdge(subj.index)],
-                    params.max_false().into(),
-                );
-                if answers_f.len() < params.min_false().into() {
-                    return Err(ErrorKind::NotEnoughData(params.min_false()).into());
-                }
-                let (answers, expectations) =
-                    transform_multiple_choice(answers_t, answers_f, params, |id, inst| {
-                        let mut question_value = vec![];
-                        if let Some(card_indices) =
-                            deck.tag_defs[inst.which].edge_sources.get(&inst.value)
-                        {
-                            question_value = card_indices
-                                .iter()
-                                .map(|i| deck.data.cards[*i].title.clone())
-                                .sample(2);
-                        }
-                        TriviaAnswer {
-                            id,
-                            answer: inst.value,
-                            question_value: SmallVec::from(question_value).into(),
-                        }
-                    });
-                let card_title = &deck.data.cards[subj.index].title;
-                let question = common.question_format.as_str().replace("{}", card_title);
-                let trivia = Trivia::new_selection(params, question, tinylang::ExprType::StringArray, answers);
-                Ok((trivia, expectations))
+                card_tag_trivia(deck, common, right, params, subj)
             }
             MultipleChoiceDef::TagCard {
                 left,
@@ -253,32 +428,7 @@ impl TriviaGen for MultipleChoiceDef {
                 let subj = left
                     .select(deck, &[])
                     .ok_or_else(|| ErrorKind::NotEnoughData(1))?;
-                let answers_t =
-                    right.select_n(deck, &[CardCond::Tag(subj.clone())], params.max_true.into());
-                if answers_t.len() < params.min_true.into() {
-                    return Err(ErrorKind::NotEnoughData(params.min_true).into());
-                }
-                let answers_f = right.select_n(
-                    deck,
-                    &[CardCond::NoTag(subj.clone())],
-                    params.max_false().into(),
-                );
-                if answers_f.len() < params.min_false().into() {
-                    return Err(ErrorKind::NotEnoughData(params.min_false()).into());
-                }
-                let (answers, expectations) =
-                    transform_multiple_choice(answers_t, answers_f, params, |id, inst| {
-                        TriviaAnswer {
-                            id,
-                            answer: deck.data.cards[inst.index].title.clone(),
-                            question_value: deck.data.tag_defs[left.which].values[inst.index]
-                                .clone()
-                                .into(),
-                        }
-                    });
-                let question = common.question_format.replace("{}", &subj.value);
-                let trivia = Trivia::new_selection(params, question, tinylang::ExprType::StringArray, answers);
-                Ok((trivia, expectations))
+                tag_card_trivia(deck, common, right, params, subj, None)
             }
             MultipleChoiceDef::Pairing {
                 left,
@@ -286,11 +436,14 @@ impl TriviaGen for MultipleChoiceDef {
                 separator,
                 pairing_id,
                 predicate,
+                eligible_t,
+                eligible_f,
+                score,
                 params,
             } => {
                 let subjects_t = left.select_n(
                     deck,
-                    &[CardCond::EdgeOut(*pairing_id)],
+                    &[CardCond::Eligible(eligible_t.clone())],
                     params.max_true.into(),
                 );
                 if subjects_t.len() < params.min_true.into() {
@@ -311,10 +464,7 @@ impl TriviaGen for MultipleChoiceDef {
                         .ok_or_else(|| ErrorKind::NotEnoughData(1))?;
                     answers_t.push((inst, inst2));
                 }
-                let lconds: Vec<_> = predicate
-                    .iter()
-                    .map(|e| CardCond::ExpressionOut(e.clone()))
-                    .collect();
+                let lconds = [CardCond::Eligible(eligible_f.clone())];
                 let mut answers_f = vec![];
                 for _ in 0..2 {
                     let subjects_f = left.select_n(deck, &lconds, params.max_false().into());
@@ -323,6 +473,9 @@ impl TriviaGen for MultipleChoiceDef {
                         predicate.iter().for_each(|e| {
                             rconds.push(CardCond::Predicate(e.clone(), Some(inst.index)))
                         });
+                        score.iter().for_each(|e| {
+                            rconds.push(CardCond::Score(e.clone(), Some(inst.index)))
+                        });
                         if let Some(inst2) = right.select(deck, &rconds) {
                             answers_f.push((inst, inst2));
                             if answers_f.len() >= params.max_false().into() {
@@ -354,6 +507,207 @@ impl TriviaGen for MultipleChoiceDef {
                 let trivia = Trivia::new_selection(params, question, tinylang::ExprType::String, answers);
                 Ok((trivia, expectations))
             }
+            MultipleChoiceDef::OddOneOut {
+                left,
+                right,
+                params,
+            } => {
+                for subj in left.select_n(deck, &[], ODD_ONE_OUT_SUBJECTS) {
+                    let group = right.select_n(
+                        deck,
+                        &[CardCond::Tag(subj.clone())],
+                        params.max_false().into(),
+                    );
+                    if group.len() < params.min_false().into() {
+                        continue;
+                    }
+                    // a Card without any value for the tag definition is
+                    // more likely missing data than odd
+                    let conds = [CardCond::TagOut(subj.which), CardCond::NoTag(subj.clone())];
+                    let candidates = right.select_n(deck, &conds, ODD_ONE_OUT_CANDIDATES);
+                    let Some(odd) = most_plausible(&deck.data, &group, candidates) else {
+                        continue;
+                    };
+                    // after answering, the group shows the Tag they share and
+                    // the odd one shows its own
+                    let odd_index = odd.index;
+                    let (answers, expectations) =
+                        transform_multiple_choice(vec![odd], group, params, |id, inst| {
+                            let question_value = if inst.index == odd_index {
+                                deck.data.tag_defs[subj.which].values[inst.index].clone()
+                            } else {
+                                SmallVec::from_elem(subj.value.clone(), 1)
+                            };
+                            TriviaAnswer {
+                                id,
+                                answer: deck.data.cards[inst.index].title.clone(),
+                                question_value: question_value.into(),
+                            }
+                        });
+                    let tag_label = &deck.data.tag_defs[subj.which].label;
+                    let question = common.question_format.replace("{}", tag_label);
+                    let trivia = Trivia::new_selection(
+                        params,
+                        question,
+                        tinylang::ExprType::StringArray,
+                        answers,
+                    );
+                    return Ok((trivia, expectations));
+                }
+                Err(ErrorKind::NotEnoughData(params.total).into())
+            }
+            MultipleChoiceDef::OddOneOutCategory {
+                left,
+                right,
+                params,
+            } => {
+                for subj in left.select_n(deck, &[], ODD_ONE_OUT_SUBJECTS) {
+                    let group = right.select_n(
+                        deck,
+                        &[CardCond::Category(subj.clone())],
+                        params.max_false().into(),
+                    );
+                    if group.len() < params.min_false().into() {
+                        continue;
+                    }
+                    let conds = [CardCond::NoCategory(subj.clone())];
+                    let candidates = right.select_n(deck, &conds, ODD_ONE_OUT_CANDIDATES);
+                    let Some(odd) = most_plausible(&deck.data, &group, candidates) else {
+                        continue;
+                    };
+                    let (answers, expectations) =
+                        transform_multiple_choice(vec![odd], group, params, |id, inst| {
+                            let category = deck.data.cards[inst.index].category.clone();
+                            let question_value: SmallVec<[String; 2]> =
+                                category.into_iter().collect();
+                            TriviaAnswer {
+                                id,
+                                answer: deck.data.cards[inst.index].title.clone(),
+                                question_value: question_value.into(),
+                            }
+                        });
+                    let question = common.question_format.clone();
+                    let trivia = Trivia::new_selection(
+                        params,
+                        question,
+                        tinylang::ExprType::StringArray,
+                        answers,
+                    );
+                    return Ok((trivia, expectations));
+                }
+                Err(ErrorKind::NotEnoughData(params.total).into())
+            }
+            MultipleChoiceDef::Threshold {
+                left,
+                right,
+                pivot,
+                is_below,
+                params,
+            } => {
+                let ordering = if *is_below {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+                for (card_index, stat) in left.select_n(deck, &[], THRESHOLD_PIVOTS) {
+                    let (value, label) = match pivot {
+                        Pivot::Card => (stat.value, deck.data.cards[card_index].title.clone()),
+                        Pivot::Rounded { step } => {
+                            let Some(value) = round_pivot(&stat.value, *step) else {
+                                continue;
+                            };
+                            let label = format_pivot(&value);
+                            (value, label)
+                        }
+                    };
+                    let count_t = rand::thread_rng().gen_range(params.min_true..=params.max_true);
+                    let conds = [CardCond::Compare(
+                        right.expression.clone(),
+                        value.clone(),
+                        ordering,
+                    )];
+                    let answers_t = right.select_n(deck, &conds, count_t.into());
+                    if answers_t.len() < params.min_true.into() {
+                        continue;
+                    }
+                    // fewer correct answers than drawn leave room for more
+                    // incorrect ones, within max_false
+                    let count_f = params.total as usize - answers_t.len();
+                    let conds = [CardCond::Compare(
+                        right.expression.clone(),
+                        value,
+                        ordering.reverse(),
+                    )];
+                    let answers_f = right.select_n(deck, &conds, count_f);
+                    if answers_f.len() < count_f {
+                        continue;
+                    }
+                    let (answers, expectations) = transform_multiple_choice(
+                        answers_t,
+                        answers_f,
+                        params,
+                        |id, (idx, inst)| {
+                            let question_value = match inst.value {
+                                OwnedExprValue::Number(v) => v.into(),
+                                OwnedExprValue::Date(v) => v.into(),
+                                _ => panic!(
+                                    "MultipleChoiceDef::Threshold: right must have return type Number or Date"
+                                ),
+                            };
+                            TriviaAnswer {
+                                id,
+                                answer: deck.data.cards[idx].title.clone(),
+                                question_value,
+                            }
+                        },
+                    );
+                    let question = common.question_format.replace("{}", &label);
+                    let trivia =
+                        Trivia::new_selection(params, question, right.return_type, answers);
+                    return Ok((trivia, expectations));
+                }
+                Err(ErrorKind::NotEnoughData(params.total).into())
+            }
+        }
+    }
+
+    fn get_follow_up(
+        &self,
+        deck: &ActiveDeck,
+        common: &TriviaDefCommon,
+        continuation: &Continuation,
+    ) -> Result<GradeableTrivia> {
+        match self {
+            MultipleChoiceDef::CardTag {
+                right,
+                eligible,
+                params,
+                ..
+            } => {
+                let index = continuation
+                    .card
+                    .filter(|i| eligible.contains(*i))
+                    .ok_or(ErrorKind::NoFollowUp)?;
+                let subj = instances::Card {
+                    index,
+                    stats: vec![],
+                    pairing_info: None,
+                };
+                card_tag_trivia(deck, common, right, params, subj)
+            }
+            MultipleChoiceDef::TagCard {
+                left,
+                right,
+                params,
+            } => {
+                let subj = continuation
+                    .tag
+                    .clone()
+                    .filter(|tag| tag.which == left.which)
+                    .ok_or(ErrorKind::NoFollowUp)?;
+                tag_card_trivia(deck, common, right, params, subj, continuation.card)
+            }
+            _ => Err(ErrorKind::NoFollowUp.into()),
         }
     }
 }
@@ -364,8 +718,11 @@ mod tests {
 
     use super::*;
     use crate::{
-        tinylang::{expr, ExprType},
-        trivia::tests::decks,
+        tinylang::{expr, test_support::card, ExprType},
+        trivia::{
+            tests::{active_deck, decks},
+            Availability, Error, KnowledgeBase, TriviaDef,
+        },
         types::Deck,
     };
     use rstest::rstest;
@@ -377,7 +734,10 @@ mod tests {
             left: None,
             right: selectors::Stat {
                 difficulty: -0.5,
-                expression: expr("R\"Capital\"").unwrap(),
+                expression: expr("R\"Capital\"")
+                    .unwrap()
+                    .compile(&decks[4].data, &decks[4].data)
+                    .unwrap(),
                 return_type: ExprType::String,
             },
             params: MultipleChoiceCommon {
@@ -390,6 +750,7 @@ mod tests {
         let common = TriviaDefCommon {
             deck_id: 4,
             question_format: "What is the capital of {}?".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[4], &common)?;
         writeln!(
@@ -407,19 +768,21 @@ mod tests {
     #[rstest]
     fn test_card_tag(decks: &[Deck]) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let decks: Vec<_> = decks.iter().cloned().map(ActiveDeck::new).collect();
+        let which = decks[0]
+            .data
+            .tag_defs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, td)| (td.label == "Director").then_some(i))
+            .next()
+            .unwrap();
         let definition = MultipleChoiceDef::CardTag {
             left: selectors::Card::new(-0.5),
             right: selectors::Tag {
                 difficulty: -0.5,
-                which: decks[0]
-                    .data
-                    .tag_defs
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, td)| (td.label == "Director").then_some(i))
-                    .next()
-                    .unwrap(),
+                which,
             },
+            eligible: CardCond::eligible(&decks[0], &[CardCond::TagOut(which)])?,
             params: MultipleChoiceCommon {
                 min_true: 1,
                 max_true: 1,
@@ -430,6 +793,7 @@ mod tests {
         let common = TriviaDefCommon {
             deck_id: 0,
             question_format: "Who directed {}?".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[0], &common)?;
         writeln!(
@@ -470,6 +834,7 @@ mod tests {
         let common = TriviaDefCommon {
             deck_id: 0,
             question_format: "Which movie was directed by {}?".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[0], &common)?;
         writeln!(
@@ -487,17 +852,37 @@ mod tests {
     #[rstest]
     fn test_pairing(decks: &[Deck]) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let decks: Vec<_> = decks.iter().cloned().map(ActiveDeck::new).collect();
+        let predicate = expr(
+            "L\"Card\" != R\"Card\" and L\"Pronoun\" == R\"Partner pronoun\" and R\"Pronoun\" == L\"Partner pronoun\"",
+        )
+        .unwrap()
+        .compile(&decks[3].data, &decks[3].data)
+        .unwrap();
+        let score = expr(
+            r#"{
+              let b1: Date = L"Birth date"
+              let b2: Date = R"Birth date"
+              return 1 / (1 + ((b2 - b1) / 3652.5) ** 2)
+            }"#,
+        )
+        .unwrap()
+        .compile(&decks[3].data, &decks[3].data)
+        .unwrap();
         let definition = MultipleChoiceDef::Pairing {
             left: selectors::Card::new(-0.5),
             right: selectors::Card::new(-0.5),
             separator: '+',
             pairing_id: 0,
-            predicate: Some(
-                expr(
-                    "L\"Card\" != R\"Card\" and L\"Pronoun\" == R\"Partner pronoun\" and R\"Pronoun\" == L\"Partner pronoun\"",
-                )
-                .unwrap(),
-            ),
+            eligible_t: CardCond::eligible(&decks[3], &[CardCond::EdgeOut(0)])?,
+            eligible_f: CardCond::eligible(
+                &decks[3],
+                &[
+                    CardCond::ExpressionOut(predicate.clone()),
+                    CardCond::ExpressionOut(score.clone()),
+                ],
+            )?,
+            predicate: Some(predicate),
+            score: Some(score),
             params: MultipleChoiceCommon {
                 min_true: 3,
                 max_true: 3,
@@ -510,6 +895,7 @@ mod tests {
             question_format: "Pick the fr[]
             r[]
             r[]ake couple.".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[3], &common)?;
         writeln!(
@@ -523,4 +909,161 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_round_pivot() {
+        let pivot = round_pivot(&OwnedExprValue::Number(1234.0), 100.0).unwrap();
+        assert_eq!(pivot, OwnedExprValue::Number(1200.0));
+        assert_eq!(format_pivot(&pivot), "1200");
+
+        let date = NaiveDate::from_ymd_opt(1987, 6, 5)
+            .and_then(|d| d.and_hms_opt(12, 0, 0))
+            .unwrap();
+        let pivot = round_pivot(&OwnedExprValue::Date(date.into()), 10.0).unwrap();
+        let OwnedExprValue::Date(v) = &pivot else {
+            panic!("expected a Date, got {:?}", pivot);
+        };
+        assert_eq!((v.year(), v.month(), v.day()), (1990, 1, 1));
+        assert_eq!(format_pivot(&pivot), "1990");
+
+        assert_eq!(
+            round_pivot(&OwnedExprValue::String("1987".into()), 10.0),
+            None
+        );
+    }
+
+    #[test]
+    fn test_most_plausible() {
+        let cards = ["Alien", "Heat", "Up", "Cars", "Jaws"]
+            .into_iter()
+            .map(|title| crate::types::Card {
+                category: (title != "Jaws").then(|| "Film".into()),
+                ..card(title)
+            })
+            .collect();
+        let tag_values = |values: &[&[&str]]| {
+            values
+                .iter()
+                .map(|cell| cell.iter().map(|t| t.to_string()).collect())
+                .collect()
+        };
+        let table = CardTable {
+            cards,
+            tag_defs: vec![
+                crate::types::TagDef {
+                    label: "Genre".into(),
+                    values: tag_values(&[
+                        &["Horror", "Sci-fi"],
+                        &["Crime"],
+                        &["Animation"],
+                        &["Animation", "Sci-fi"],
+                        &["Horror"],
+                    ]),
+                },
+                crate::types::TagDef {
+                    label: "Studio".into(),
+                    values: tag_values(&[&["Fox"], &["Warner"], &["Pixar"], &["Pixar"], &[]]),
+                },
+            ],
+            ..Default::default()
+        };
+        // Alien and Up share a Category, Cars also shares Sci-fi with Alien,
+        // and Jaws only shares Horror
+        assert_eq!(shared_traits(&table, &[0, 2], 1), 1);
+        assert_eq!(shared_traits(&table, &[0, 2], 3), 4);
+        assert_eq!(shared_traits(&table, &[0, 2], 4), 1);
+        let card = |index| instances::Card {
+            index,
+            stats: vec![],
+            pairing_info: None,
+        };
+        let group = [card(0), card(2)];
+        let odd = most_plausible(&table, &group, vec![card(1), card(3), card(4)]);
+        assert_eq!(odd.map(|inst| inst.index), Some(3));
+        // ties go to the first Card drawn
+        let odd = most_plausible(&table, &group, vec![card(4), card(1)]);
+        assert_eq!(odd.map(|inst| inst.index), Some(4));
+        assert!(most_plausible(&table, &group, vec![]).is_none());
+    }
+
+    #[test]
+    fn test_follow_up() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let directors = [
+            ("Jaws", "Spielberg"),
+            ("E.T.", "Spielberg"),
+            ("Alien", "Scott"),
+            ("Heat", "Mann"),
+            ("Up", "Docter"),
+            ("Cars", "Lasseter"),
+        ];
+        let cards = directors.iter().map(|(title, _)| card(*title)).collect();
+        let data = CardTable {
+            cards,
+            tag_defs: vec![crate::types::TagDef {
+                label: "Director".into(),
+                values: directors
+                    .iter()
+                    .map(|(_, director)| std::iter::once(director.to_string()).collect())
+                    .collect(),
+            }],
+            ..Default::default()
+        };
+        let deck = active_deck(6, data);
+        let who_directed = MultipleChoiceDef::CardTag {
+            left: selectors::Card::new(0.0),
+            right: selectors::Tag {
+                difficulty: 0.0,
+                which: 0,
+            },
+            eligible: Bitset::full(directors.len()),
+            params: MultipleChoiceCommon::typical(4),
+        };
+        let other_films = MultipleChoiceDef::TagCard {
+            left: selectors::Tag {
+                difficulty: 0.0,
+                which: 0,
+            },
+            right: selectors::Card::new(0.0),
+            params: MultipleChoiceCommon::typical(4),
+        };
+        let common = TriviaDefCommon {
+            deck_id: 6,
+            question_format: "{}".into(),
+            availability: Availability::Rotation,
+        };
+        let jaws = Continuation::card(&deck, 0);
+        for _ in 0..8 {
+            let (trivia, _) = who_directed.get_follow_up(&deck, &common, &jaws)?;
+            assert_eq!(trivia.question, "Jaws");
+            let continuation = trivia.continuation.expect("expected a continuation");
+            let (trivia, exps) = other_films.get_follow_up(&deck, &common, &continuation)?;
+            assert_eq!(trivia.question, "Spielberg");
+            let TriviaExp::All { ids } = &exps[0] else {
+                panic!("expected All, got {:?}", exps);
+            };
+            let correct: Vec<_> = ids
+                .iter()
+                .map(|id| trivia.options[*id as usize].answer.as_str())
+                .collect();
+            assert_eq!(correct, vec!["E.T."]);
+        }
+        // a Card alone doesn't say which Tag to ask about
+        assert!(other_films.get_follow_up(&deck, &common, &jaws).is_err());
+
+        // the Card index of a continuation from another revision may be stale
+        let kb = KnowledgeBase {
+            decks: vec![deck],
+            trivia_defs: vec![TriviaDef::MultipleChoice(who_directed, common)],
+        };
+        assert!(kb.get_follow_up(0, &jaws).is_ok());
+        let stale = Continuation {
+            revision: 1,
+            ..jaws
+        };
+        assert!(matches!(
+            kb.get_follow_up(0, &stale),
+            Err(Error(ErrorKind::StaleContinuation, _))
+        ));
+        Ok(())
+    }
 }
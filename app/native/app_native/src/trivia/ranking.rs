@@ -1,6 +1,7 @@
 use std::cmp::Ordering;
 
 use crate::{
+    bitset::Bitset,
     tinylang::{self, OwnedExprValue},
     trivia::types::{StatAxisMod, TriviaExp},
     types::NaiveDateTimeExt,
@@ -19,6 +20,9 @@ pub struct RankingCommon {
     pub ranking_type: RankingType,
     pub total: u8,
     pub stat_annotation: Option<StatAnnotation>,
+    /// An order that is only partly right earns partial credit, instead of
+    /// nothing
+    pub is_scored: bool,
 }
 
 impl SanityCheck for RankingCommon {
@@ -42,6 +46,7 @@ impl RankingCommon {
             ranking_type,
             total,
             stat_annotation,
+            is_scored: false,
         }
     }
 
@@ -50,6 +55,17 @@ impl RankingCommon {
             ranking_type,
             total,
             stat_annotation: None,
+            is_scored: false,
+        }
+    }
+
+    /// Put `total` Cards in ascending order, with partial credit
+    pub fn timeline(total: u8) -> Self {
+        Self {
+            ranking_type: RankingType::Asc,
+            total,
+            stat_annotation: None,
+            is_scored: true,
         }
     }
 
@@ -87,9 +103,29 @@ pub enum RankingDef {
         left: selectors::Card,
         right: selectors::Card,
         stat: selectors::StatNested,
+        /// Number expression which multiplies the weight of each right Card.
+        /// None rejects the pair
+        score: Option<tinylang::Program>,
+        /// The left Cards that have the left variables of the stat and score
+        eligible_left: Bitset,
+        /// The right Cards that have the right variables of the stat
+        eligible_right: Bitset,
         separator: char,
         params: RankingCommon,
     },
+    /// Rank Tags by how many enabled Cards have them
+    TagCount {
+        left: selectors::Tag,
+        params: RankingCommon,
+    },
+    /// Rank Cards by how many Cards they are paired with
+    PairingDegree {
+        left: selectors::Card,
+        pairing_id: usize,
+        /// The Cards in the pairing
+        eligible: Bitset,
+        params: RankingCommon,
+    },
 }
 
 impl Trivia {
@@ -106,6 +142,8 @@ impl Trivia {
             max_answers: params.num_answers(),
             question_value_type,
             stat_annotation: params.stat_annotation,
+            map_annotation: None,
+            continuation: None,
             options,
             prefilled_answers: vec![],
         }
@@ -151,6 +189,8 @@ where
     let expectations = if params.is_single() {
         let ids = groups.drain(..).next().unwrap();
         vec![TriviaExp::Any { ids }]
+    } else if params.is_scored {
+        vec![TriviaExp::Order { groups }]
     } else {
         let mut exps = vec![];
         let mut min_pos = 0u8;
@@ -164,6 +204,39 @@ where
     (answers, expectations)
 }
 
+/// The share of the pairs of IDs from different groups that the selection
+/// puts in the order of their groups, which is 1 minus the normalized
+/// Kendall tau distance. IDs of the same group may come in any order, and an
+/// ID missing from the selection is out of order with every other.
+pub fn order_credit(groups: &[Vec<u8>], selection: &[i64]) -> f64 {
+    let position = |id: u8| selection.iter().position(|x| *x == id as i64);
+    let ranked: Vec<(usize, Option<usize>)> = groups
+        .iter()
+        .enumerate()
+        .flat_map(|(rank, ids)| ids.iter().map(move |id| (rank, *id)))
+        .map(|(rank, id)| (rank, position(id)))
+        .collect();
+    let mut pairs = 0;
+    let mut concordant = 0;
+    for (k, (rank_a, pos_a)) in ranked.iter().enumerate() {
+        for (rank_b, pos_b) in ranked[k + 1..].iter() {
+            if rank_a == rank_b {
+                continue;
+            }
+            pairs += 1;
+            if let (Some(pa), Some(pb)) = (pos_a, pos_b) {
+                if (pa < pb) == (rank_a < rank_b) {
+                    concordant += 1;
+                }
+            }
+        }
+    }
+    if pairs == 0 {
+        return 1.0;
+    }
+    concordant as f64 / pairs as f64
+}
+
 impl TriviaGen for RankingDef {
     fn get_trivia(&self, deck: &ActiveDeck, common: &TriviaDefCommon) -> Result<GradeableTrivia> {
         match self {
@@ -216,18 +289,28 @@ impl TriviaGen for RankingDef {
                 left,
                 right,
                 stat,
+                score,
+                eligible_left,
+                eligible_right,
                 separator,
                 params,
             } => {
-                let lconds = vec![CardCond::ExpressionOut(stat.expression.clone())];
-                let rconds = vec![CardCond::ExpressionIn(stat.expression.clone())];
-                let expr = stat.expression.optimize(&deck.data, &deck.data).unwrap();
+                let lconds = [CardCond::Eligible(eligible_left.clone())];
+                let expr = stat.expression.bind(&deck.data, &deck.data);
                 let mut answers = vec![];
                 for _ in 0..2 {
                     let subjects = left.select_n(deck, &lconds, params.total.into());
                     for inst in subjects {
-                        if let Some(inst2) = right.select(deck, &rconds) {
-                            answers.push((inst, inst2));
+                        let mut rconds = vec![CardCond::Eligible(eligible_right.clone())];
+                        score.iter().for_each(|e| {
+                            rconds.push(CardCond::Score(e.clone(), Some(inst.index)))
+                        });
+                        let Some(inst2) = right.select(deck, &rconds) else {
+                            continue;
+                        };
+                        // has_vars can't see failed coercions or guards in a block
+                        if let Some(value) = expr.get_value(inst.index, inst2.index) {
+                            answers.push((inst, inst2, value));
                             if answers.len() >= params.total.into() {
                                 break;
                             }
@@ -243,10 +326,7 @@ impl TriviaGen for RankingDef {
                 let (answers, expectations) = transform_ranking(
                     answers,
                     params,
-                    |id, (inst, inst2)| {
-                        let value = expr
-                            .get_value(inst.index, inst2.index)
-                            .expect("null-checker lied");
+                    |id, (inst, inst2, value)| {
                         let (num, question_value) = match value {
                             OwnedExprValue::Number(v) => (v, v.into()),
                             OwnedExprValue::Date(v) => (
@@ -274,6 +354,61 @@ impl TriviaGen for RankingDef {
                 let trivia = Trivia::new_ranking(params, question, stat.return_type, answers);
                 Ok((trivia, expectations))
             }
+            RankingDef::TagCount { left, params } => {
+                let answers = left.select_n(deck, &[], params.total.into());
+                if answers.len() < params.total.into() {
+                    return Err(ErrorKind::NotEnoughData(params.total).into());
+                }
+                let (answers, expectations) = transform_ranking(answers, params, |id, inst| {
+                    let count = deck.tag_defs[inst.which]
+                        .edge_sources
+                        .get(&inst.value)
+                        .map_or(0, |cards| {
+                            cards
+                                .iter()
+                                .filter(|i| !deck.data.cards[**i].is_disabled)
+                                .count()
+                        }) as f64;
+                    let ans = TriviaAnswer {
+                        id,
+                        answer: inst.value,
+                        question_value: count.into(),
+                    };
+                    (count, ans)
+                });
+                let question = common.question_format.clone();
+                let trivia =
+                    Trivia::new_ranking(params, question, tinylang::ExprType::Number, answers);
+                Ok((trivia, expectations))
+            }
+            RankingDef::PairingDegree {
+                left,
+                pairing_id,
+                eligible,
+                params,
+            } => {
+                let conds = [CardCond::Eligible(eligible.clone())];
+                let answers = left.select_n(deck, &conds, params.total.into());
+                if answers.len() < params.total.into() {
+                    return Err(ErrorKind::NotEnoughData(params.total).into());
+                }
+                let edge_infos = &deck.pairings[*pairing_id].edge_infos;
+                let (answers, expectations) = transform_ranking(answers, params, |id, inst| {
+                    let degree = edge_infos
+                        .range((inst.index, 0)..(inst.index + 1, 0))
+                        .count() as f64;
+                    let ans = TriviaAnswer {
+                        id,
+                        answer: deck.data.cards[inst.index].title.clone(),
+                        question_value: degree.into(),
+                    };
+                    (degree, ans)
+                });
+                let question = common.question_format.clone();
+                let trivia =
+                    Trivia::new_ranking(params, question, tinylang::ExprType::Number, answers);
+                Ok((trivia, expectations))
+            }
         }
     }
 }
@@ -284,9 +419,12 @@ mod tests {
 
     use super::*;
     use crate::{
-        tinylang::{expr, ExprType},
-        trivia::tests::decks,
-        types::Deck,
+        tinylang::{expr, test_support::card, ExprType},
+        trivia::{
+            tests::{active_deck, decks},
+            Availability, QValue,
+        },
+        types::{Card, CardTable, Deck, Edge, Pairing, TagDef},
     };
     use rstest::rstest;
 
@@ -306,7 +444,10 @@ mod tests {
             left: Some(selectors::Category { difficulty: 0.0 }),
             right: selectors::Stat {
                 difficulty: -0.5,
-                expression: expr("R\"Spotify plays\"").unwrap(),
+                expression: expr("R\"Spotify plays\"")
+                    .unwrap()
+                    .compile(&decks[2].data, &decks[2].data)
+                    .unwrap(),
                 return_type: ExprType::Number,
             },
             params: RankingCommon::typical(typ, 3),
@@ -314,6 +455,7 @@ mod tests {
         let common = TriviaDefCommon {
             deck_id: 2,
             question_format: "Rank these songs from most to least Spotify plays".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[2], &common)?;
         writeln!(
@@ -346,7 +488,10 @@ mod tests {
             left: Some(selectors::Category { difficulty: 0.0 }),
             right: selectors::Stat {
                 difficulty: -0.5,
-                expression: expr("R\"Birth date\"").unwrap(),
+                expression: expr("R\"Birth date\"")
+                    .unwrap()
+                    .compile(&decks[3].data, &decks[3].data)
+                    .unwrap(),
                 return_type: ExprType::Date,
             },
             params: RankingCommon::typical(typ, 3),
@@ -354,6 +499,7 @@ mod tests {
         let common = TriviaDefCommon {
             deck_id: 3,
             question_format: "Rank these people from earliest to latest birth dates".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[3], &common)?;
         writeln!(
@@ -373,19 +519,33 @@ mod tests {
     #[rstest]
     fn test_card_squared(decks: &[Deck]) -> std::result::Result<(), Box<dyn std::error::Error>> {
         let decks: Vec<_> = decks.iter().cloned().map(ActiveDeck::new).collect();
+        let expression = expr("L\"Coordinates\" <-> R\"Coordinates\"")
+            .unwrap()
+            .compile(&decks[4].data, &decks[4].data)
+            .unwrap();
         let definition = RankingDef::CardCard {
             left: selectors::Card::new(-0.5),
             right: selectors::Card::new(-0.5),
+            eligible_left: CardCond::eligible(
+                &decks[4],
+                &[CardCond::ExpressionOut(expression.clone())],
+            )?,
+            eligible_right: CardCond::eligible(
+                &decks[4],
+                &[CardCond::ExpressionIn(expression.clone())],
+            )?,
             stat: selectors::StatNested {
-                expression: expr("L\"Coordinates\" <-> R\"Coordinates\"").unwrap(),
+                expression,
                 return_type: ExprType::Number,
             },
+            score: None,
             separator: '↔',
             params: RankingCommon::typical(RankingType::Min, 3),
         };
         let common = TriviaDefCommon {
             deck_id: 4,
             question_format: "Pick the closest pair of cities geographically.".into(),
+            availability: Availability::Rotation,
         };
         let (trivia, exps) = definition.get_trivia(&decks[4], &common)?;
         writeln!(
@@ -399,4 +559,163 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_order_credit() {
+        let groups = vec![vec![2], vec![0, 3], vec![1]];
+        assert_eq!(order_credit(&groups, &[2, 0, 3, 1]), 1.0);
+        assert_eq!(order_credit(&groups, &[2, 3, 0, 1]), 1.0);
+        // 5 pairs across groups, 3 of them involve 1
+        assert_eq!(order_credit(&groups, &[1, 2, 0, 3]), 0.4);
+        assert_eq!(order_credit(&groups, &[1, 3, 0, 2]), 0.0);
+        assert_eq!(order_credit(&groups, &[2, 0, 3]), 0.4);
+        assert_eq!(order_credit(&groups, &[]), 0.0);
+        assert_eq!(order_credit(&[vec![0, 1]], &[1, 0]), 1.0);
+    }
+
+    #[test]
+    fn test_timeline() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let deck = cast();
+        let definition = RankingDef::TagCount {
+            left: selectors::Tag {
+                difficulty: 0.0,
+                which: 0,
+            },
+            params: RankingCommon::timeline(3),
+        };
+        let common = TriviaDefCommon {
+            deck_id: 0,
+            question_format: "Order these films by cast size.".into(),
+            availability: Availability::Rotation,
+        };
+        let (trivia, exps) = definition.get_trivia(&deck, &common)?;
+        let [TriviaExp::Order { groups }] = &exps[..] else {
+            panic!("expected a single Order, got {:?}", exps);
+        };
+        let ordered: Vec<_> = groups
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|id| trivia.options[*id as usize].answer.as_str())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(ordered, vec![vec!["Gamma"], vec!["Beta"], vec!["Alpha"]]);
+        Ok(())
+    }
+
+    /// Five people, the last one disabled, who played in films and are
+    /// friends with each other
+    fn cast() -> ActiveDeck {
+        let cards = ["Ann", "Bob", "Cid", "Dee", "Eve"]
+            .into_iter()
+            .map(|title| Card {
+                is_disabled: title == "Eve",
+                ..card(title)
+            })
+            .collect();
+        let films: [&[&str]; 5] = [
+            &["Alpha", "Beta", "Gamma"],
+            &["Alpha", "Beta"],
+            &["Alpha"],
+            &[],
+            &["Beta", "Gamma"],
+        ];
+        let edges = [(0, 1), (0, 2), (0, 3), (1, 2), (3, 4)];
+        let data = CardTable {
+            cards,
+            tag_defs: vec![TagDef {
+                label: "Film".into(),
+                values: films
+                    .iter()
+                    .map(|cell| cell.iter().map(|t| t.to_string()).collect())
+                    .collect(),
+            }],
+            pairings: vec![Pairing {
+                label: "Friends".into(),
+                is_symmetric: true,
+                data: edges.iter().map(|(l, r)| Edge::new(*l, *r, None)).collect(),
+            }],
+            ..Default::default()
+        };
+        active_deck(0, data)
+    }
+
+    /// The value of every answer, by position in the expected ranking
+    fn ranked_values(trivia: &Trivia, exps: &[TriviaExp]) -> Vec<(String, QValue)> {
+        let mut ranked = vec![];
+        for exp in exps {
+            let TriviaExp::AllPos { ids, min_pos } = exp else {
+                panic!("expected AllPos, got {:?}", exp);
+            };
+            assert_eq!(*min_pos as usize, ranked.len());
+            let mut group: Vec<_> = ids
+                .iter()
+                .map(|id| {
+                    let ans = &trivia.options[*id as usize];
+                    (ans.answer.clone(), ans.question_value.clone())
+                })
+                .collect();
+            group.sort_by(|a, b| a.0.cmp(&b.0));
+            ranked.extend(group);
+        }
+        ranked
+    }
+
+    #[test]
+    fn test_tag_count() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let deck = cast();
+        let definition = RankingDef::TagCount {
+            left: selectors::Tag {
+                difficulty: 0.0,
+                which: 0,
+            },
+            params: RankingCommon::typical(RankingType::Desc, 3),
+        };
+        let common = TriviaDefCommon {
+            deck_id: 0,
+            question_format: "Rank these films by cast size.".into(),
+            availability: Availability::Rotation,
+        };
+        let (trivia, exps) = definition.get_trivia(&deck, &common)?;
+        let ranked = ranked_values(&trivia, &exps);
+        // Eve is disabled, so Beta and Gamma lose a Card
+        assert_eq!(
+            ranked,
+            vec![
+                ("Alpha".into(), 3.0.into()),
+                ("Beta".into(), 2.0.into()),
+                ("Gamma".into(), 1.0.into()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pairing_degree() -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let deck = cast();
+        let definition = RankingDef::PairingDegree {
+            left: selectors::Card::new(0.0),
+            pairing_id: 0,
+            eligible: CardCond::eligible(&deck, &[CardCond::EdgeOut(0)])?,
+            params: RankingCommon::typical(RankingType::Asc, 4),
+        };
+        let common = TriviaDefCommon {
+            deck_id: 0,
+            question_format: "Rank these people by number of friends.".into(),
+            availability: Availability::Rotation,
+        };
+        let (trivia, exps) = definition.get_trivia(&deck, &common)?;
+        let ranked = ranked_values(&trivia, &exps);
+        assert_eq!(
+            ranked,
+            vec![
+                ("Dee".into(), 1.0.into()),
+                ("Bob".into(), 2.0.into()),
+                ("Cid".into(), 2.0.into()),
+                ("Ann".into(), 3.0.into()),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -8,6 +8,7 @@ use rustler::{Decoder, Encoder, NifMap, NifTaggedEnum, NifUnitEnum};
 use smallvec::SmallVec;
 
 use crate::{
+    bitset::Bitset,
     probability::SampleTree,
     tinylang::{self, OwnedExprValue},
     types::{CardTable, Deck},
@@ -23,15 +24,18 @@ pub struct ActiveTagDef {
 
 pub struct ActiveDeck {
     pub id: u64,
+    pub revision: u64,
     pub data: CardTable,
     pub pairings: Vec<ActivePairing>,
     pub tag_defs: Vec<ActiveTagDef>,
-    views: RefCell<HashMap<u64, DeckView>>,
+    /// Sample trees by difficulty, over the enabled Cards or a subset of them
+    views: RefCell<HashMap<(u64, Option<Bitset>), DeckView>>,
 }
 
 impl ActiveDeck {
     pub fn new(deck: Deck) -> Self {
         let id = deck.id;
+        let revision = deck.revision;
         let data = deck.data;
         let pairings: Vec<ActivePairing> = data
             .pairings
@@ -69,6 +73,7 @@ impl ActiveDeck {
             .collect();
         Self {
             id,
+            revision,
             data,
             pairings,
             tag_defs,
@@ -98,11 +103,20 @@ impl ActiveDeck {
     where
         F: FnOnce(DeckViewIter<'_>) -> R,
     {
-        let key = difficulty.to_bits();
+        self.with_iter_over(difficulty, None, f)
+    }
+
+    /// Like `with_iter`, but only samples the enabled Cards in `eligible`.
+    /// The sample tree is kept for the next call with the same set.
+    pub fn with_iter_over<F, R>(&self, difficulty: f64, eligible: Option<&Bitset>, f: F) -> R
+    where
+        F: FnOnce(DeckViewIter<'_>) -> R,
+    {
+        let key = (difficulty.to_bits(), eligible.cloned());
         let mut map = self.views.borrow_mut();
         let view = map
             .entry(key)
-            .or_insert_with(|| DeckView::new(&self.data, difficulty));
+            .or_insert_with(|| DeckView::new(&self.data, difficulty, eligible));
         f(view.iter())
     }
 }
@@ -130,13 +144,13 @@ impl<'a> Drop for DeckViewIter<'a> {
 }
 
 impl DeckView {
-    pub fn new(card_table: &CardTable, difficulty: f64) -> Self {
+    pub fn new(card_table: &CardTable, difficulty: f64, eligible: Option<&Bitset>) -> Self {
         let sample_tree = SampleTree::new(
             card_table
                 .cards
                 .iter()
                 .enumerate()
-                .filter(|(_, c)| !c.is_disabled)
+                .filter(|(i, c)| !c.is_disabled && eligible.is_none_or(|e| e.contains(*i)))
                 .map(|(i, c)| (f64::exp(-difficulty * c.popularity), i)),
         );
         DeckView { tree: sample_tree }
@@ -147,10 +161,22 @@ impl DeckView {
     }
 }
 
+/// Where a TriviaDef may be used. Only the Rotation ones are picked for a
+/// room, the others can still be tried from the explorer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, NifUnitEnum)]
+pub enum Availability {
+    Rotation,
+    /// Only asked after a Trivia of the same deck, about its subject
+    FollowUp,
+    /// The client can't answer it yet
+    Preview,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, NifMap)]
 pub struct TriviaDefCommon {
     pub deck_id: u64,
     pub question_format: String,
+    pub availability: Availability,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, NifMap)]
@@ -198,11 +224,11 @@ pub mod selectors {
     }
     pub struct Stat {
         pub difficulty: f64,
-        pub expression: tinylang::Expression,
+        pub expression: tinylang::Program,
         pub return_type: tinylang::ExprType,
     }
     pub struct StatNested {
-        pub expression: tinylang::Expression,
+        pub expression: tinylang::Program,
         pub return_type: tinylang::ExprType,
     }
     pub struct PairingNested {
@@ -230,6 +256,8 @@ pub mod selectors {
 }
 
 pub mod instances {
+    use rustler::NifMap;
+
     use crate::tinylang;
 
     #[derive(Debug, Clone, Copy)]
@@ -251,14 +279,14 @@ pub mod instances {
         pub pairing_info: Option<String>,
     }
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, NifMap)]
     pub struct Tag {
         pub which: usize,
         pub value: String,
     }
 }
 
-#[derive(Debug, PartialEq, Eq, NifTaggedEnum)]
+#[derive(Debug, PartialEq, NifTaggedEnum)]
 pub enum TriviaExp {
     /// The selection must contain every ID in the list
     All { ids: Vec<u8> },
@@ -271,6 +299,31 @@ pub enum TriviaExp {
     /// The slice of the selection `min_pos..min_pos + ids.len()` must contain
     /// every ID in the list
     AllPos { ids: Vec<u8>, min_pos: u8 },
+    /// The typed answer must match one of the answers once normalized, with
+    /// at most `max_typos` typos
+    Text { answers: Vec<String>, max_typos: u8 },
+    /// The number answered earns the credit of the first band, away from
+    /// `value`, that it is within. The bands share the credit equally, and
+    /// are fractions of `value` if `is_relative`. The closest of several
+    /// players' answers earns full credit
+    Near {
+        value: f64,
+        bands: Vec<f64>,
+        is_relative: bool,
+    },
+    /// The selection should be the list of IDs. Each ID in its position
+    /// earns an equal share of the credit
+    Permutation { ids: Vec<u8> },
+    /// The selection should list the groups in order, and the IDs of a group
+    /// in any order. The credit is the share of pairs of IDs from different
+    /// groups in the right order
+    Order { groups: Vec<Vec<u8>> },
+    /// The point answered earns the credit of the first band, in km from
+    /// `lat_lng`, that it is within. The bands share the credit equally
+    Location {
+        lat_lng: (f64, f64),
+        bands_km: Vec<f64>,
+    },
 }
 
 #[derive(Debug, Clone, Copy, NifUnitEnum)]
@@ -281,12 +334,58 @@ pub enum RankingType {
     Max,
 }
 
+/// The area of the map to show with a geography Trivia
+#[derive(Debug, Clone, Copy, NifMap)]
+pub struct MapAnnotation {
+    /// South-west corner
+    pub min: (f64, f64),
+    /// North-east corner
+    pub max: (f64, f64),
+    /// The point the question is about, shown before answering
+    pub marker: Option<(f64, f64)>,
+    /// The radius of the circle around the marker
+    pub radius_km: Option<f64>,
+}
+
+/// The subject of a Trivia, which a follow-up question can be about. The
+/// Card index is only valid for the revision of the deck it was taken from
+#[derive(Debug, Clone, NifMap)]
+pub struct Continuation {
+    pub deck_id: u64,
+    pub revision: u64,
+    pub card: Option<usize>,
+    pub tag: Option<instances::Tag>,
+}
+
+impl Continuation {
+    pub fn new(deck: &ActiveDeck) -> Self {
+        Self {
+            deck_id: deck.id,
+            revision: deck.revision,
+            card: None,
+            tag: None,
+        }
+    }
+
+    /// About the Card at the index
+    pub fn card(deck: &ActiveDeck, index: usize) -> Self {
+        Self {
+            card: Some(index),
+            ..Self::new(deck)
+        }
+    }
+}
+
 /// Compat
 #[derive(Debug, NifTaggedEnum)]
 pub enum TriviaAnswerType {
     Selection,
     Hangman,
     Ranking(RankingType),
+    FreeText,
+    Estimate,
+    Matching,
+    Location,
 }
 
 impl Display for TriviaAnswerType {
@@ -329,7 +428,7 @@ impl From<(f64, f64)> for StatAnnotation {
 }
 
 /// Compat
-#[derive(Debug, NifMap)]
+#[derive(Debug, Clone, NifMap)]
 pub struct TriviaAnswer {
     pub id: u8,
     pub answer: String,
@@ -355,6 +454,9 @@ pub struct Trivia {
     pub max_answers: u8,
     pub question_value_type: tinylang::ExprType,
     pub stat_annotation: Option<StatAnnotation>,
+    pub map_annotation: Option<MapAnnotation>,
+    /// What a follow-up question can be generated from
+    pub continuation: Option<Continuation>,
     pub options: Vec<TriviaAnswer>,
     pub prefilled_answers: Vec<TriviaAnswer>,
 }
@@ -389,6 +491,24 @@ impl Display for Trivia {
     }
 }
 
+/// An expression evaluated for a Card, or a pair of Cards when it reads both
+/// sides
+#[derive(Debug, NifMap)]
+pub struct EvaluationRow {
+    pub left_title: String,
+    pub right_title: Option<String>,
+    pub value: QValue,
+}
+
+/// The result of evaluating an expression over a deck
+#[derive(Debug, NifMap)]
+pub struct Evaluation {
+    /// The expression as it was read, with only the parentheses it needs
+    pub source: String,
+    pub return_type: tinylang::ExprType,
+    pub rows: Vec<EvaluationRow>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct QValue(OwnedExprValue);
 
@@ -408,6 +528,7 @@ impl Encoder for QValue {
             OwnedExprValue::Number(v) => v.encode(env),
             OwnedExprValue::LatLng(v) => v.encode(env),
             OwnedExprValue::Date(v) => v.encode(env),
+            OwnedExprValue::Duration(v) => v.encode(env),
             OwnedExprValue::String(v) => v.encode(env),
             OwnedExprValue::IntArray(v) => v.encode(env),
             OwnedExprValue::StringArray(v) => v.as_slice().encode(env),
@@ -452,6 +573,7 @@ impl Display for QValue {
             OwnedExprValue::Number(v) => write!(f, "{:?}", v),
             OwnedExprValue::LatLng(v) => write!(f, "{:?}", v),
             OwnedExprValue::Date(v) => write!(f, "{:?}", v),
+            OwnedExprValue::Duration(v) => write!(f, "{:?} days", v),
             OwnedExprValue::String(v) => write!(f, "{:?}", v),
             OwnedExprValue::IntArray(v) => write!(f, "{:?}", v),
             OwnedExprValue::StringArray(v) => write!(f, "{:?}", v),
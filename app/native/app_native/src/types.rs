@@ -134,6 +134,9 @@ pub enum StatArray {
     LatLng {
         values: Vec<Option<(f64, f64)>>,
     },
+    Bool {
+        values: Vec<Option<bool>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize, NifMap)]
@@ -94,6 +94,8 @@ export type RoomScoreEntry = {
   userId: number;
   score: number;
   turnGrade: boolean | null;
+  // The share of the credit earned, between 0 and 1
+  turnCredit: number | null;
 };
 
 export type RoomAnswersEntry = {